@@ -0,0 +1,30 @@
+use miniscript::{eval, Value};
+use std::io::{self, BufRead, Write};
+
+fn main() {
+    let mut env = eval::Environment::new();
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("> ");
+        io::stdout().flush().expect("Failed to flush stdout");
+        let Some(Ok(line)) = lines.next() else {
+            break;
+        };
+        match line.trim() {
+            ".quit" => break,
+            "" => continue,
+            // Errors are reported without ending the session, and leave `_`
+            // holding the last successful result.
+            line => match eval::run_program(line, &mut env) {
+                Ok((value, env)) => {
+                    if value != Value::Null {
+                        println!("{}", value.repr());
+                    }
+                    env.set("_".to_string(), value);
+                }
+                Err(e) => eprintln!("Failed: {}", e),
+            },
+        }
+    }
+}
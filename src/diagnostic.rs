@@ -0,0 +1,61 @@
+//! Error reports that quote the offending source line and point at the
+//! reported column with a caret, in the style of rustc:
+//!
+//! ```text
+//! error: Invalid character '$'
+//!  --> 1:5
+//!   |
+//! 1 | x = $
+//!   |     ^
+//! ```
+
+use crate::{lexer::LexerError, parser::ParseError};
+
+/// Renders a lexer error with the line of `source` it occurred on.
+pub fn render_error(source: &str, err: &LexerError) -> String {
+    render_at(source, err.state.line, err.state.column, &err.kind.to_string())
+}
+
+/// Renders a parse error with the line of `source` it occurred on.
+pub fn render_parse_error(source: &str, err: &ParseError) -> String {
+    render_at(source, err.line, err.column, &err.message)
+}
+
+/// Columns count characters from 1, with a tab as one column. The caret
+/// line repeats the source line's tabs, so the caret lines up however wide
+/// the terminal shows tabs.
+fn render_at(source: &str, line: usize, column: usize, message: &str) -> String {
+    let text = source.lines().nth(line.saturating_sub(1)).unwrap_or("");
+    let padding: String = text
+        .chars()
+        .chain(std::iter::repeat(' '))
+        .take(column.saturating_sub(1))
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let gutter = " ".repeat(line.to_string().len());
+    format!(
+        "error: {}\n{}--> {}:{}\n{} |\n{} | {}\n{} | {}^",
+        message, gutter, line, column, gutter, line, text, gutter, padding
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::lex;
+
+    #[test]
+    fn caret_lines_up_after_multibyte_characters() {
+        let source = "x = \"\u{e9}\u{e9}\" + $";
+        let err = lex(source).unwrap_err();
+        assert_eq!(err.state.column, 12);
+        assert_eq!(render_error(source, &err), "error: Invalid character '$'\n --> 1:12\n  |\n1 | x = \"\u{e9}\u{e9}\" + $\n  |            ^");
+    }
+
+    #[test]
+    fn caret_repeats_tabs_in_the_source_line() {
+        let source = "\tx = (1";
+        let err = crate::parser::parse_str(source).unwrap_err();
+        assert!(render_parse_error(source, &err).ends_with("1 | \tx = (1\n  | \t      ^"));
+    }
+}
@@ -0,0 +1,1964 @@
+use crate::{
+    intrinsics::Intrinsic,
+    lexer::{self, LexerError},
+    parser::{self, Expression, ParseError, Statement},
+    shared::SharedGlobals,
+};
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap},
+    fmt::{Debug, Display, Formatter, Result as FormatResult},
+    hash::{Hash, Hasher},
+    rc::Rc,
+};
+use Expression::*;
+
+/// A script value. Lists and maps are shared by reference, as in MiniScript:
+/// after `b = a`, both names hold the same list, so `b[0] = 9` changes `a`.
+/// Copying a `Value` copies the reference.
+#[derive(Clone)]
+pub enum Value {
+    Number(f64),
+    Str(String),
+    Bool(bool),
+    Null,
+    List(Rc<RefCell<Vec<Value>>>),
+    Map(Rc<RefCell<BTreeMap<MiniKey, Value>>>),
+    Function(Rc<FunctionValue>),
+}
+
+/// A map key. Numbers, strings and null can be keys. Numbers compare by
+/// value, so `m[1]` and `m[1.0]` are the same entry, as are `0` and `-0`.
+/// Keys sort null first, then numbers, then strings.
+#[derive(Debug, Clone)]
+pub enum MiniKey {
+    Null,
+    Number(f64),
+    Str(String),
+}
+
+impl MiniKey {
+    /// The key for `value`. Lists, maps, booleans, functions and NaN cannot
+    /// be keys.
+    pub fn from_value(value: Value) -> Result<MiniKey, RuntimeError> {
+        match value {
+            Value::Null => Ok(MiniKey::Null),
+            Value::Number(n) if !n.is_nan() => Ok(MiniKey::Number(if n == 0. { 0. } else { n })),
+            Value::Str(s) => Ok(MiniKey::Str(s)),
+            other => Err(RuntimeError::unary("map key", &other)),
+        }
+    }
+
+    pub fn into_value(self) -> Value {
+        match self {
+            MiniKey::Null => Value::Null,
+            MiniKey::Number(n) => Value::Number(n),
+            MiniKey::Str(s) => Value::Str(s),
+        }
+    }
+
+    fn rank(&self) -> u8 {
+        match self {
+            MiniKey::Null => 0,
+            MiniKey::Number(_) => 1,
+            MiniKey::Str(_) => 2,
+        }
+    }
+}
+
+impl From<&str> for MiniKey {
+    fn from(s: &str) -> Self {
+        MiniKey::Str(s.to_string())
+    }
+}
+
+impl From<String> for MiniKey {
+    fn from(s: String) -> Self {
+        MiniKey::Str(s)
+    }
+}
+
+/// `from_value` never makes a NaN key, so numbers are totally ordered.
+impl Ord for MiniKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (MiniKey::Number(left), MiniKey::Number(right)) => left.total_cmp(right),
+            (MiniKey::Str(left), MiniKey::Str(right)) => left.cmp(right),
+            (left, right) => left.rank().cmp(&right.rank()),
+        }
+    }
+}
+
+impl PartialOrd for MiniKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for MiniKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for MiniKey {}
+
+impl Hash for MiniKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.rank().hash(state);
+        match self {
+            MiniKey::Null => (),
+            MiniKey::Number(n) => n.to_bits().hash(state),
+            MiniKey::Str(s) => s.hash(state),
+        }
+    }
+}
+
+/// Writes the key as its value displays, so string keys come out unquoted.
+impl Display for MiniKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FormatResult {
+        write!(f, "{}", self.clone().into_value())
+    }
+}
+
+/// A function: either created by evaluating a `function ... end function`
+/// literal, or one of the built-in intrinsics, possibly bound as a method.
+pub struct FunctionValue {
+    params: Vec<(String, Option<Expression>)>,
+    body: FunctionBody,
+}
+
+enum FunctionBody {
+    /// The literal's statements, with the scopes visible where it was
+    /// defined, so the body can read (and through `outer`, update) variables
+    /// of the function that made it.
+    Script { statements: Vec<Statement>, scopes: Vec<Scope> },
+    Intrinsic(Intrinsic),
+    /// An intrinsic looked up as a member, as in `s.len`, with the value it
+    /// was looked up on to pass as `self`.
+    Method(Intrinsic, Value),
+}
+
+impl FunctionValue {
+    pub(crate) fn intrinsic(intrinsic: Intrinsic) -> Self {
+        Self { params: intrinsic.params(), body: FunctionBody::Intrinsic(intrinsic) }
+    }
+
+    /// `intrinsic` bound to `receiver`; its parameters leave out `self`.
+    fn method(intrinsic: Intrinsic, receiver: Value) -> Self {
+        Self { params: intrinsic.params().split_off(1), body: FunctionBody::Method(intrinsic, receiver) }
+    }
+}
+
+/// Script functions are equal only to themselves, intrinsics to the same
+/// intrinsic, and methods to the same intrinsic bound to an equal value.
+impl PartialEq for FunctionValue {
+    fn eq(&self, other: &Self) -> bool {
+        match (&self.body, &other.body) {
+            (FunctionBody::Intrinsic(left), FunctionBody::Intrinsic(right)) => left == right,
+            (FunctionBody::Method(left, left_self), FunctionBody::Method(right, right_self)) => {
+                left == right && left_self == right_self
+            }
+            _ => std::ptr::eq(self, other),
+        }
+    }
+}
+
+/// Leaves out the captured scopes, which may contain the function itself.
+impl Debug for FunctionValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FormatResult {
+        write!(f, "{}", self)
+    }
+}
+
+/// Renders the signature the way MiniScript prints a function value.
+impl Display for FunctionValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FormatResult {
+        write!(f, "FUNCTION(")?;
+        for (i, (name, default)) in self.params.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            match default {
+                Some(default) => write!(f, "{}={}", name, default)?,
+                None => write!(f, "{}", name)?,
+            }
+        }
+        write!(f, ")")
+    }
+}
+
+/// The address of a list or map, which identifies it while it is shared.
+pub(crate) fn container_id(value: &Value) -> Option<usize> {
+    match value {
+        Value::List(items) => Some(Rc::as_ptr(items) as *const () as usize),
+        Value::Map(entries) => Some(Rc::as_ptr(entries) as *const () as usize),
+        _ => None,
+    }
+}
+
+impl Value {
+    /// A new list holding `items`.
+    pub fn list(items: Vec<Value>) -> Value {
+        Value::List(Rc::new(RefCell::new(items)))
+    }
+    /// A new map holding `entries`.
+    pub fn map(entries: BTreeMap<MiniKey, Value>) -> Value {
+        Value::Map(Rc::new(RefCell::new(entries)))
+    }
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Number(_) => "number",
+            Value::Str(_) => "string",
+            Value::Bool(_) => "boolean",
+            Value::Null => "null",
+            Value::List(_) => "list",
+            Value::Map(_) => "map",
+            Value::Function(_) => "function",
+        }
+    }
+    /// Renders the value as MiniScript source would spell it, quoting strings.
+    /// Map entries come out in sorted key order, so output is deterministic.
+    /// A list or map inside itself comes out as `[...]` or `{...}`.
+    pub fn repr(&self) -> String {
+        self.repr_pretty_at(None, 0, &mut Vec::new())
+    }
+    /// Converts the value to a string the way MiniScript's `str` and string
+    /// concatenation do: numbers via `format_number`, strings unquoted, and
+    /// `null` as the empty string.
+    pub fn to_mini_string(&self) -> String {
+        match self {
+            Value::Null => String::new(),
+            other => other.to_string(),
+        }
+    }
+    /// Like `repr`, but puts each element of a non-empty list or map on its
+    /// own line, indented by `indent` spaces per nesting level.
+    pub fn repr_pretty(&self, indent: usize) -> String {
+        self.repr_pretty_at(Some(indent), 0, &mut Vec::new())
+    }
+    /// Renders on one line without an `indent`. `enclosing` holds the lists
+    /// and maps being rendered around this value, to cut off cycles.
+    fn repr_pretty_at(&self, indent: Option<usize>, depth: usize, enclosing: &mut Vec<usize>) -> String {
+        let id = container_id(self);
+        if id.is_some_and(|id| enclosing.contains(&id)) {
+            return if let Value::List(_) = self { "[...]" } else { "{...}" }.to_string();
+        }
+        let (open, close, items) = match self {
+            Value::Str(s) => return format!("\"{}\"", s.replace('"', "\"\"")),
+            Value::List(items) => {
+                enclosing.extend(id);
+                let items: Vec<_> = items.borrow().iter().map(|item| item.repr_pretty_at(indent, depth + 1, enclosing)).collect();
+                ('[', ']', items)
+            }
+            Value::Map(entries) => {
+                enclosing.extend(id);
+                let items: Vec<_> = entries
+                    .borrow()
+                    .iter()
+                    .map(|(key, value)| {
+                        format!("{}: {}", key.clone().into_value().repr(), value.repr_pretty_at(indent, depth + 1, enclosing))
+                    })
+                    .collect();
+                ('{', '}', items)
+            }
+            other => return other.to_string(),
+        };
+        enclosing.pop();
+        match indent {
+            Some(indent) if !items.is_empty() => {
+                let inner = " ".repeat(indent * (depth + 1));
+                let outer = " ".repeat(indent * depth);
+                let items: Vec<_> = items.iter().map(|item| format!("{}{}", inner, item)).collect();
+                format!("{}\n{}\n{}{}", open, items.join(",\n"), outer, close)
+            }
+            _ => format!("{}{}{}", open, items.join(", "), close),
+        }
+    }
+}
+
+/// Element by element, as `values_equal` compares.
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        values_equal(self, other)
+    }
+}
+
+/// Like a derived `Debug`, but with lists and maps in `repr` form, which
+/// stops at cycles.
+impl Debug for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FormatResult {
+        match self {
+            Value::Number(n) => write!(f, "Number({:?})", n),
+            Value::Str(s) => write!(f, "Str({:?})", s),
+            Value::Bool(b) => write!(f, "Bool({:?})", b),
+            Value::Null => write!(f, "Null"),
+            Value::List(_) => write!(f, "List({})", self.repr()),
+            Value::Map(_) => write!(f, "Map({})", self.repr()),
+            Value::Function(function) => write!(f, "Function({:?})", function),
+        }
+    }
+}
+
+/// MiniScript's `==`. Numbers compare numerically, so `0 == -0` and NaN
+/// equals nothing, not even itself. Strings compare by content, lists and
+/// maps element by element, and functions by identity. Values of different
+/// types are never equal, so `1 == "1"` is false, while `null == null` is
+/// true.
+pub fn values_equal(left: &Value, right: &Value) -> bool {
+    values_equal_within(left, right, &mut Vec::new())
+}
+
+/// `values_equal`, where `comparing` holds the pairs of lists or maps being
+/// compared around these values. Meeting a pair again means following a
+/// cycle in both, which holds no difference of its own.
+fn values_equal_within(left: &Value, right: &Value, comparing: &mut Vec<(usize, usize)>) -> bool {
+    let pair = container_id(left).zip(container_id(right));
+    if pair.is_some_and(|(left, right)| left == right || comparing.contains(&(left, right))) {
+        return true;
+    }
+    comparing.extend(pair);
+    let equal = match (left, right) {
+        (Value::Number(left), Value::Number(right)) => left == right,
+        (Value::Str(left), Value::Str(right)) => left == right,
+        (Value::Bool(left), Value::Bool(right)) => left == right,
+        (Value::Null, Value::Null) => true,
+        (Value::List(left), Value::List(right)) => {
+            let (left, right) = (left.borrow(), right.borrow());
+            left.len() == right.len() && left.iter().zip(right.iter()).all(|(left, right)| values_equal_within(left, right, comparing))
+        }
+        // Entries iterate in key order, so equal maps line up pairwise.
+        (Value::Map(left), Value::Map(right)) => {
+            let (left, right) = (left.borrow(), right.borrow());
+            left.len() == right.len()
+                && left.iter().zip(right.iter()).all(|((left_key, left), (right_key, right))| {
+                    left_key == right_key && values_equal_within(left, right, comparing)
+                })
+        }
+        (Value::Function(left), Value::Function(right)) => left == right,
+        _ => false,
+    };
+    if pair.is_some() {
+        comparing.pop();
+    }
+    equal
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FormatResult {
+        match self {
+            Value::Number(n) => write!(f, "{}", format_number(*n)),
+            Value::Str(s) => write!(f, "{}", s),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Null => write!(f, "null"),
+            Value::List(_) | Value::Map(_) => write!(f, "{}", self.repr()),
+            Value::Function(function) => write!(f, "{}", function),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RuntimeError {
+    UndefinedVariable(String),
+    TypeMismatch {
+        operator: &'static str,
+        left: &'static str,
+        right: Option<&'static str>,
+    },
+    InvalidAssignmentTarget,
+    ZeroRangeStep,
+    InvalidJson(String),
+    KeyNotFound(String),
+    IndexOutOfRange { index: i64, len: usize },
+    /// A list or string index that is NaN or infinite.
+    NonFiniteIndex(f64),
+    /// `.` applied to null, with the member that was asked for.
+    NullMemberAccess(String),
+    TooManyArguments { expected: usize, found: usize },
+    /// A string operation whose result would be longer than `MAX_STRING_LEN`.
+    StringTooLong,
+    /// A range whose start, end or step is infinite or NaN.
+    NonFiniteRange,
+    /// A range with more than `MAX_RANGE_LEN` elements.
+    RangeTooLong,
+    /// A value JSON cannot spell, such as a function or NaN.
+    NotJsonSerializable(&'static str),
+    /// Function calls nested deeper than `Environment::set_max_call_depth`
+    /// allows, as with runaway recursion.
+    StackOverflow,
+    /// A value that cannot become a `SharedValue`, such as a function.
+    NotShareable(&'static str),
+    Unsupported(&'static str),
+    /// An error raised inside script functions, with the calls it unwound
+    /// through, innermost first. Expressions carry no positions, so each
+    /// call is given as its source, such as `f(x)`.
+    Traced { error: Box<RuntimeError>, backtrace: Vec<String> },
+}
+
+impl RuntimeError {
+    pub(crate) fn unary(operator: &'static str, operand: &Value) -> Self {
+        RuntimeError::TypeMismatch { operator, left: operand.type_name(), right: None }
+    }
+    pub(crate) fn binary(operator: &'static str, left: &Value, right: &Value) -> Self {
+        RuntimeError::TypeMismatch {
+            operator,
+            left: left.type_name(),
+            right: Some(right.type_name()),
+        }
+    }
+    /// Records that the error unwound through `call`.
+    fn called_from(self, call: &Expression) -> Self {
+        match self {
+            RuntimeError::Traced { error, mut backtrace } => {
+                backtrace.push(call.to_string());
+                RuntimeError::Traced { error, backtrace }
+            }
+            error => RuntimeError::Traced { error: Box::new(error), backtrace: vec![call.to_string()] },
+        }
+    }
+    /// The error itself, without the calls it unwound through.
+    pub fn cause(&self) -> &RuntimeError {
+        match self {
+            RuntimeError::Traced { error, .. } => error,
+            error => error,
+        }
+    }
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FormatResult {
+        match self {
+            RuntimeError::UndefinedVariable(name) => write!(f, "Undefined variable '{}'", name),
+            RuntimeError::TypeMismatch { operator, left, right: Some(right) } => {
+                write!(f, "Cannot apply '{}' to {} and {}", operator, left, right)
+            }
+            RuntimeError::TypeMismatch { operator, left, right: None } => {
+                write!(f, "Cannot apply '{}' to {}", operator, left)
+            }
+            RuntimeError::InvalidAssignmentTarget => write!(f, "Invalid assignment target"),
+            RuntimeError::ZeroRangeStep => write!(f, "Range step must not be 0"),
+            RuntimeError::InvalidJson(message) => write!(f, "Invalid JSON: {}", message),
+            RuntimeError::KeyNotFound(key) => write!(f, "Key not found: '{}'", key),
+            RuntimeError::IndexOutOfRange { index, len } => {
+                write!(f, "Index {} is out of range for length {}", index, len)
+            }
+            RuntimeError::NonFiniteIndex(index) => write!(f, "Index {} is not a finite number", Value::Number(*index)),
+            RuntimeError::NullMemberAccess(member) => write!(f, "Cannot access '{}' of null", member),
+            RuntimeError::TooManyArguments { expected, found } => {
+                write!(f, "Too many arguments: expected at most {}, found {}", expected, found)
+            }
+            RuntimeError::StringTooLong => write!(f, "String would exceed {} bytes", MAX_STRING_LEN),
+            RuntimeError::NonFiniteRange => write!(f, "Range bounds and step must be finite"),
+            RuntimeError::RangeTooLong => write!(f, "Range would exceed {} elements", MAX_RANGE_LEN),
+            RuntimeError::NotJsonSerializable(what) => write!(f, "Cannot convert {} to JSON", what),
+            RuntimeError::StackOverflow => write!(f, "Call stack too deep"),
+            RuntimeError::NotShareable(what) => write!(f, "Cannot share {} between threads", what),
+            RuntimeError::Unsupported(what) => write!(f, "{} is not supported yet", what),
+            RuntimeError::Traced { error, backtrace } => {
+                write!(f, "{}", error)?;
+                backtrace.iter().try_for_each(|call| write!(f, "\n  in {}", call))
+            }
+        }
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+/// One scope's variables, shared between the environment and any functions
+/// defined while it was active.
+type Scope = Rc<RefCell<HashMap<String, Value>>>;
+
+/// Variables in a chain of scopes: the globals first, then the scopes of the
+/// function being run, innermost last. Reads search from the innermost scope
+/// outward, while assignment creates or updates the variable in the innermost
+/// scope, so a function can shadow an outer variable but changes it only
+/// through `outer` or `globals`.
+#[derive(Debug)]
+pub struct Environment {
+    scopes: Vec<Scope>,
+    rng: Rng,
+    print_sink: PrintSink,
+    output: OutputOptions,
+    /// Script function calls currently running.
+    call_depth: usize,
+    max_call_depth: usize,
+    /// Globals read through to when the global scope lacks a name.
+    shared_globals: Option<SharedGlobals>,
+}
+
+/// How deeply script functions may call each other before a call fails with
+/// `RuntimeError::StackOverflow`. Each call takes several native frames, so
+/// this stays well inside the 8 MiB main thread stack of a debug build.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 128;
+
+impl Default for Environment {
+    fn default() -> Self {
+        Self {
+            scopes: vec![Scope::default()],
+            rng: Rng::default(),
+            print_sink: PrintSink::Stdout,
+            output: OutputOptions::default(),
+            call_depth: 0,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            shared_globals: None,
+        }
+    }
+}
+
+/// Where `print` sends its lines.
+enum PrintSink {
+    Stdout,
+    /// Called with each line, without its ending.
+    Lines(Box<dyn FnMut(&str)>),
+    /// Called with each line followed by its ending.
+    Text(Box<dyn FnMut(&str)>),
+}
+
+/// How `print` lays out what it writes.
+#[derive(Debug, Clone)]
+pub struct OutputOptions {
+    /// Flushes standard output after every `print`, so that output shows up
+    /// at once even when it ends without a newline.
+    pub flush_each: bool,
+    /// Written between the values given to one `print`.
+    pub field_separator: String,
+    /// Written after each `print`. Sinks set with `set_print_sink` get
+    /// lines without it.
+    pub line_ending: String,
+}
+
+impl Default for OutputOptions {
+    fn default() -> Self {
+        Self { flush_each: false, field_separator: " ".to_string(), line_ending: "\n".to_string() }
+    }
+}
+
+impl Debug for PrintSink {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FormatResult {
+        write!(f, "PrintSink")
+    }
+}
+
+fn scope_map(scope: &Scope) -> Value {
+    Value::map(scope.borrow().iter().map(|(name, value)| (MiniKey::from(name.as_str()), value.clone())).collect())
+}
+
+/// A splitmix64 generator: small, fast and fine with any seed, including 0.
+#[derive(Debug)]
+struct Rng(u64);
+
+impl Default for Rng {
+    /// Seeds from the clock, so unseeded environments differ between runs.
+    fn default() -> Self {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_nanos() as u64)
+            .unwrap_or(0);
+        Rng(nanos)
+    }
+}
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+impl Environment {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// An environment whose globals start out as `globals`, for running
+    /// scripts on several threads against the same data. The first read of
+    /// a shared global copies it into this environment's global scope, and
+    /// assignments only ever change that scope.
+    pub fn with_shared_globals(globals: SharedGlobals) -> Self {
+        Self { shared_globals: Some(globals), ..Self::default() }
+    }
+    pub fn get(&self, name: &str) -> Option<Value> {
+        self.scopes.iter().rev().find_map(|scope| scope.borrow().get(name).cloned()).or_else(|| self.adopt_shared(name))
+    }
+    pub fn contains(&self, name: &str) -> bool {
+        self.scopes.iter().any(|scope| scope.borrow().contains_key(name))
+            || self.shared_globals.as_ref().is_some_and(|globals| globals.contains_key(name))
+    }
+    /// Copies the shared global `name` into the global scope, so changes to
+    /// a shared list or map last for the rest of the run.
+    fn adopt_shared(&self, name: &str) -> Option<Value> {
+        let value = Value::from(self.shared_globals.as_ref()?.get(name)?);
+        self.scopes[0].borrow_mut().insert(name.to_string(), value.clone());
+        Some(value)
+    }
+    /// The variables of scope `index`, with any shared globals not yet read
+    /// included for the global scope.
+    fn scope_map(&self, index: usize) -> Value {
+        if let (0, Some(globals)) = (index, &self.shared_globals) {
+            for name in globals.keys() {
+                if !self.scopes[0].borrow().contains_key(name) {
+                    self.adopt_shared(name);
+                }
+            }
+        }
+        scope_map(&self.scopes[index])
+    }
+    /// Same as `get`, which returns an owned copy since scopes became shared.
+    pub fn get_cloned(&self, name: &str) -> Option<Value> {
+        self.get(name)
+    }
+    pub fn set(&mut self, name: String, value: Value) {
+        self.scopes.last().expect("the global scope is never popped").borrow_mut().insert(name, value);
+    }
+    pub fn set_global(&mut self, name: String, value: Value) {
+        self.scopes[0].borrow_mut().insert(name, value);
+    }
+    /// Assigns in the scope enclosing the innermost one, which is the
+    /// globals at top level.
+    pub fn set_outer(&mut self, name: String, value: Value) {
+        let outer = self.scopes.len().saturating_sub(2);
+        self.scopes[outer].borrow_mut().insert(name, value);
+    }
+    /// Replaces what `print` does with each line, which by default is writing
+    /// it to standard output. The line comes without its newline.
+    pub fn set_print_sink(&mut self, sink: impl FnMut(&str) + 'static) {
+        self.print_sink = PrintSink::Lines(Box::new(sink));
+    }
+    /// Sends what `print` writes to `writer`, each line followed by its
+    /// ending. The caller keeps a handle to read it back, as tests do with a
+    /// `String`. Write errors are ignored, like those of standard output.
+    pub fn set_print_writer<W: std::fmt::Write + 'static>(&mut self, writer: Rc<RefCell<W>>) {
+        self.print_sink = PrintSink::Text(Box::new(move |text| {
+            let _ = writer.borrow_mut().write_str(text);
+        }));
+    }
+    pub fn set_output_options(&mut self, options: OutputOptions) {
+        self.output = options;
+    }
+    /// Limits how deeply script functions may call each other. Hosts that
+    /// run scripts on a thread with a larger stack can raise it.
+    pub fn set_max_call_depth(&mut self, limit: usize) {
+        self.max_call_depth = limit;
+    }
+    /// Writes one line made of `fields`, laid out as the output options say.
+    pub(crate) fn print(&mut self, fields: &[String]) {
+        let line = fields.join(&self.output.field_separator);
+        match &mut self.print_sink {
+            PrintSink::Stdout => {
+                use std::io::Write as _;
+                let mut stdout = std::io::stdout().lock();
+                let _ = write!(stdout, "{}{}", line, self.output.line_ending);
+                if self.output.flush_each {
+                    let _ = stdout.flush();
+                }
+            }
+            PrintSink::Lines(sink) => sink(&line),
+            PrintSink::Text(sink) => sink(&format!("{}{}", line, self.output.line_ending)),
+        }
+    }
+    /// Reads a variable for a script, falling back to the `locals`, `outer`
+    /// and `globals` maps and then the intrinsics when those names are not
+    /// variables themselves.
+    pub(crate) fn load(&self, name: &str) -> Result<Value, RuntimeError> {
+        match (self.get(name), name) {
+            (Some(value), _) => Ok(value),
+            (None, "locals") => Ok(self.locals()),
+            (None, "outer") => Ok(self.outer()),
+            (None, "globals") => Ok(self.globals()),
+            (None, _) => match Intrinsic::from_name(name) {
+                Some(intrinsic) => Ok(Value::Function(Rc::new(FunctionValue::intrinsic(intrinsic)))),
+                None => Err(RuntimeError::UndefinedVariable(name.to_string())),
+            },
+        }
+    }
+    /// Enters a function scope.
+    pub fn push_scope(&mut self) {
+        self.scopes.push(Scope::default());
+    }
+    /// Leaves the innermost function scope; the global scope always stays.
+    pub fn pop_scope(&mut self) {
+        if self.scopes.len() > 1 {
+            self.scopes.pop();
+        }
+    }
+    /// The innermost scope's variables, as MiniScript's `locals` map.
+    pub fn locals(&self) -> Value {
+        self.scope_map(self.scopes.len() - 1)
+    }
+    /// The global variables, as MiniScript's `globals` map.
+    pub fn globals(&self) -> Value {
+        self.scope_map(0)
+    }
+    /// The variables of the scope enclosing the innermost one, as
+    /// MiniScript's `outer` map.
+    pub fn outer(&self) -> Value {
+        self.scope_map(self.scopes.len().saturating_sub(2))
+    }
+    /// Restarts the random sequence, so environments seeded alike produce
+    /// the same numbers from `random` and `randint`.
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.rng = Rng(seed);
+    }
+    /// The next random number in `0..1`, as MiniScript's `rnd` returns.
+    pub fn random(&mut self) -> f64 {
+        (self.rng.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+    /// The next random integer between `low` and `high`, both inclusive.
+    pub fn randint(&mut self, low: i64, high: i64) -> i64 {
+        let (low, high) = if low <= high { (low, high) } else { (high, low) };
+        let span = high.wrapping_sub(low) as u64 as u128 + 1;
+        low.wrapping_add((self.rng.next_u64() as u128 % span) as i64)
+    }
+}
+
+/// Formats a number the way MiniScript converts it to a string: integers
+/// without a fraction, other values with one to six decimals, and very
+/// large or small magnitudes in exponent form.
+pub fn format_number(n: f64) -> String {
+    if n.is_nan() {
+        return "NaN".to_string();
+    }
+    if n.is_infinite() {
+        return if n > 0. { "INF" } else { "-INF" }.to_string();
+    }
+    if n.fract() == 0. {
+        let text = format!("{:.0}", n);
+        return if text == "-0" { "0".to_string() } else { text };
+    }
+    if n.abs() > 1e10 || n.abs() < 1e-6 {
+        let text = format!("{:.6E}", n);
+        let (mantissa, exponent) = text.split_once('E').unwrap();
+        let exponent: i32 = exponent.parse().unwrap();
+        let sign = if exponent < 0 { '-' } else { '+' };
+        return format!("{}E{}{:03}", mantissa, sign, exponent.abs());
+    }
+    let text = format!("{:.6}", n);
+    let trimmed = text.trim_end_matches('0');
+    if trimmed.ends_with('.') {
+        format!("{}0", trimmed)
+    } else {
+        trimmed.to_string()
+    }
+}
+
+/// MiniScript truthiness: `null`, `0`, `false` and empty strings, lists
+/// and maps are falsey; everything else is truthy.
+pub fn is_truthy(value: &Value) -> bool {
+    match value {
+        Value::Number(n) => *n != 0.,
+        Value::Str(s) => !s.is_empty(),
+        Value::Bool(b) => *b,
+        Value::Null => false,
+        Value::List(items) => !items.borrow().is_empty(),
+        Value::Map(entries) => !entries.borrow().is_empty(),
+        Value::Function(_) => true,
+    }
+}
+
+/// A prefix operator, applied to its evaluated operand.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOperator {
+    Not,
+    Negate,
+    Typeof,
+}
+
+impl UnaryOperator {
+    pub fn apply(self, operand: Value) -> Result<Value, RuntimeError> {
+        match (self, operand) {
+            (UnaryOperator::Not, operand) => Ok(Value::Bool(!is_truthy(&operand))),
+            (UnaryOperator::Negate, Value::Number(n)) => Ok(Value::Number(-n)),
+            (UnaryOperator::Negate, other) => Err(RuntimeError::unary("-", &other)),
+            (UnaryOperator::Typeof, operand) => Ok(Value::Str(operand.type_name().to_string())),
+        }
+    }
+}
+
+/// A binary operator that always evaluates both operands, applied to
+/// their values.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOperator {
+    Multiply,
+    Divide,
+    Remainder,
+    Power,
+    /// XOR of both operands truncated to 64-bit integers.
+    BitwiseXor,
+    Add,
+    Subtract,
+    Less,
+    LessEq,
+    Greater,
+    GreaterEq,
+    Equal,
+    NotEqual,
+}
+
+impl BinaryOperator {
+    pub fn symbol(self) -> &'static str {
+        match self {
+            BinaryOperator::Multiply => "*",
+            BinaryOperator::Divide => "/",
+            BinaryOperator::Remainder => "%",
+            BinaryOperator::Power | BinaryOperator::BitwiseXor => "^",
+            BinaryOperator::Add => "+",
+            BinaryOperator::Subtract => "-",
+            BinaryOperator::Less => "<",
+            BinaryOperator::LessEq => "<=",
+            BinaryOperator::Greater => ">",
+            BinaryOperator::GreaterEq => ">=",
+            BinaryOperator::Equal => "==",
+            BinaryOperator::NotEqual => "!=",
+        }
+    }
+
+    pub fn apply(self, left: Value, right: Value) -> Result<Value, RuntimeError> {
+        use BinaryOperator::*;
+        match (self, left, right) {
+            (Equal, l, r) => Ok(Value::Bool(values_equal(&l, &r))),
+            (NotEqual, l, r) => Ok(Value::Bool(!values_equal(&l, &r))),
+            (Add, Value::Str(l), Value::Str(r)) => Ok(Value::Str(l + &r)),
+            (Add, Value::Str(l), r) => Ok(Value::Str(l + &r.to_mini_string())),
+            (Add, l, Value::Str(r)) => Ok(Value::Str(l.to_mini_string() + &r)),
+            (Multiply, Value::Str(s), Value::Number(n)) => repeat_string(&s, n),
+            // Strings order lexicographically by code point, so `"B" < "a"`.
+            // Other mixes of types, such as a string and a number, have no
+            // order and fail with a type mismatch below.
+            (op @ (Less | LessEq | Greater | GreaterEq), Value::Str(l), Value::Str(r)) => Ok(Value::Bool(match op {
+                Less => l < r,
+                LessEq => l <= r,
+                Greater => l > r,
+                _ => l >= r,
+            })),
+            (op, Value::Number(l), Value::Number(r)) => Ok(match op {
+                Multiply => Value::Number(l * r),
+                // Division by zero follows IEEE 754 as MiniScript does, never
+                // failing: `1/0` is INF, `-1/0` is -INF, and `0/0` and `5 % 0`
+                // are NaN.
+                Divide => Value::Number(l / r),
+                Remainder => Value::Number(l % r),
+                Power => Value::Number(l.powf(r)),
+                BitwiseXor => Value::Number(((l as i64) ^ (r as i64)) as f64),
+                Add => Value::Number(l + r),
+                Subtract => Value::Number(l - r),
+                Less => Value::Bool(l < r),
+                LessEq => Value::Bool(l <= r),
+                Greater => Value::Bool(l > r),
+                GreaterEq => Value::Bool(l >= r),
+                Equal | NotEqual => unreachable!("equality is handled above"),
+            }),
+            (op, l, r) => Err(RuntimeError::binary(op.symbol(), &l, &r)),
+        }
+    }
+}
+
+/// The longest string, in bytes, that repeating a string with `*` may build.
+pub const MAX_STRING_LEN: usize = 1 << 24;
+
+/// `s * n`: `s` repeated `n` times, rounding `n` down, or the empty string
+/// when `n` is below 1.
+fn repeat_string(s: &str, n: f64) -> Result<Value, RuntimeError> {
+    if n < 1. || n.is_nan() {
+        return Ok(Value::Str(String::new()));
+    }
+    let count = n as usize;
+    match s.len().checked_mul(count) {
+        Some(len) if len <= MAX_STRING_LEN => Ok(Value::Str(s.repeat(count))),
+        _ => Err(RuntimeError::StringTooLong),
+    }
+}
+
+/// The most elements `start to end by step` and `range` may build.
+pub const MAX_RANGE_LEN: usize = 1 << 24;
+
+/// Materializes `start to end by step`. Without a step the range counts
+/// towards `end` by 1; a step pointing away from `end` gives an empty list.
+pub fn make_range(start: Value, end: Value, step: Option<Value>) -> Result<Value, RuntimeError> {
+    let (start, end) = match (start, end) {
+        (Value::Number(start), Value::Number(end)) => (start, end),
+        (start, end) => return Err(RuntimeError::binary("to", &start, &end)),
+    };
+    let step = match step {
+        None if end < start => -1.,
+        None => 1.,
+        Some(Value::Number(0.)) => return Err(RuntimeError::ZeroRangeStep),
+        Some(Value::Number(step)) => step,
+        Some(other) => return Err(RuntimeError::unary("by", &other)),
+    };
+    if !(start.is_finite() && end.is_finite() && step.is_finite()) {
+        return Err(RuntimeError::NonFiniteRange);
+    }
+    // Negative when the step points away from `end`; may overflow to infinity.
+    if (end - start) / step >= MAX_RANGE_LEN as f64 {
+        return Err(RuntimeError::RangeTooLong);
+    }
+    let mut items = Vec::new();
+    let mut n = start;
+    while (step > 0. && n <= end) || (step < 0. && n >= end) {
+        items.push(Value::Number(n));
+        n = start + step * items.len() as f64;
+    }
+    Ok(Value::list(items))
+}
+
+/// How a binary operation combines its left value with its right side.
+#[derive(Clone, Copy)]
+enum Link {
+    Apply(BinaryOperator),
+    // `a and b` is `a` when `a` is falsey and `b` otherwise; `a or b` is `a`
+    // when `a` is truthy and `b` otherwise. The right side is evaluated only
+    // when the left does not decide, so `false and f()` never calls `f`.
+    // Unlike MiniScript proper the result is not normalized to 1 or 0:
+    // `0 or "x"` is "x".
+    And,
+    Or,
+}
+
+/// The sides of a binary operation and how they combine.
+fn binary_link(expr: &Expression) -> Option<(&Expression, Link, &Expression)> {
+    let (left, link, right) = match expr {
+        Multiplication(left, right) => (left, Link::Apply(BinaryOperator::Multiply), right),
+        Division(left, right) => (left, Link::Apply(BinaryOperator::Divide), right),
+        Remainder(left, right) => (left, Link::Apply(BinaryOperator::Remainder), right),
+        Power(left, right) => (left, Link::Apply(BinaryOperator::Power), right),
+        BitwiseXor(left, right) => (left, Link::Apply(BinaryOperator::BitwiseXor), right),
+        Addition(left, right) => (left, Link::Apply(BinaryOperator::Add), right),
+        Subtraction(left, right) => (left, Link::Apply(BinaryOperator::Subtract), right),
+        LessThan(left, right) => (left, Link::Apply(BinaryOperator::Less), right),
+        LessThanEq(left, right) => (left, Link::Apply(BinaryOperator::LessEq), right),
+        GreaterThan(left, right) => (left, Link::Apply(BinaryOperator::Greater), right),
+        GreaterThanEq(left, right) => (left, Link::Apply(BinaryOperator::GreaterEq), right),
+        Equality(left, right) => (left, Link::Apply(BinaryOperator::Equal), right),
+        Inequality(left, right) => (left, Link::Apply(BinaryOperator::NotEqual), right),
+        LogicalAnd(left, right) => (left, Link::And, right),
+        LogicalOr(left, right) => (left, Link::Or, right),
+        _ => return None,
+    };
+    Some((left, link, right))
+}
+
+/// Evaluates a binary operation together with those nested down its left
+/// side, such as all of `a + b - c`, in a loop rather than by recursion.
+/// The parser leaves such chains out of `ParseOptions::max_depth`, so a long
+/// sum needs no more stack than a short one.
+fn eval_binary_chain(expr: &Expression, env: &mut Environment) -> Result<Value, RuntimeError> {
+    let mut links = Vec::new();
+    let mut leftmost = expr;
+    while let Some((left, link, right)) = binary_link(leftmost) {
+        links.push((link, right));
+        leftmost = left;
+    }
+    let mut value = eval_expression(leftmost, env)?;
+    for (link, right) in links.into_iter().rev() {
+        value = match link {
+            Link::Apply(operator) => operator.apply(value, eval_expression(right, env)?)?,
+            Link::And if is_truthy(&value) => eval_expression(right, env)?,
+            Link::Or if !is_truthy(&value) => eval_expression(right, env)?,
+            Link::And | Link::Or => value,
+        };
+    }
+    Ok(value)
+}
+
+/// The `<` or `<=` used by a side of a `Between`.
+fn between_operator(inclusive: bool) -> BinaryOperator {
+    if inclusive { BinaryOperator::LessEq } else { BinaryOperator::Less }
+}
+
+pub fn eval_expression(expr: &Expression, env: &mut Environment) -> Result<Value, RuntimeError> {
+    match expr {
+        StringValue(s) => Ok(Value::Str(s.clone())),
+        NumberValue(n) => Ok(Value::Number(*n)),
+        BoolValue(b) => Ok(Value::Bool(*b)),
+        NullValue => Ok(Value::Null),
+        Variable(name) => env.load(name),
+        LogicalNot(operand) => UnaryOperator::Not.apply(eval_expression(operand, env)?),
+        UnaryNegation(operand) => UnaryOperator::Negate.apply(eval_expression(operand, env)?),
+        // `typeof` does not swallow errors from its operand: `typeof null.x`
+        // fails like `null.x` does, just as `typeof undefinedName` fails with
+        // an undefined variable rather than naming a type.
+        Typeof(operand) => UnaryOperator::Typeof.apply(eval_expression(operand, env)?),
+        Multiplication(..)
+        | Division(..)
+        | Remainder(..)
+        | Power(..)
+        | BitwiseXor(..)
+        | Addition(..)
+        | Subtraction(..)
+        | LessThan(..)
+        | LessThanEq(..)
+        | GreaterThan(..)
+        | GreaterThanEq(..)
+        | Equality(..)
+        | Inequality(..)
+        | LogicalAnd(..)
+        | LogicalOr(..) => eval_binary_chain(expr, env),
+        Conditional(cond, then, otherwise) => {
+            if is_truthy(&eval_expression(cond, env)?) {
+                eval_expression(then, env)
+            } else {
+                eval_expression(otherwise, env)
+            }
+        }
+        // Short-circuits like `low < value and value < high`, evaluating `value` once.
+        Between { low, value, high, low_inclusive, high_inclusive } => {
+            let low = eval_expression(low, env)?;
+            let value = eval_expression(value, env)?;
+            if !is_truthy(&between_operator(*low_inclusive).apply(low, value.clone())?) {
+                return Ok(Value::Bool(false));
+            }
+            between_operator(*high_inclusive).apply(value, eval_expression(high, env)?)
+        }
+        Range { start, end, step } => {
+            let start = eval_expression(start, env)?;
+            let end = eval_expression(end, env)?;
+            let step = step.as_ref().map(|step| eval_expression(step, env)).transpose()?;
+            make_range(start, end, step)
+        }
+        Assignment(target, value) => {
+            let value = eval_expression(value, env)?;
+            assign(target, value.clone(), env)?;
+            Ok(value)
+        }
+        // The right side is only evaluated when the target is null, an
+        // undeclared variable or a missing map entry, so `m.a ??= 5` adds `a`.
+        // The target is evaluated once to read it and again to store.
+        NullCoalescingAssignment(target, value) => {
+            let current = match target.as_ref() {
+                Variable(name) => env.get(name),
+                MemberAccess(..) | Index(..) => match eval_expression(target, env) {
+                    Ok(current) => Some(current),
+                    Err(RuntimeError::KeyNotFound(_)) => None,
+                    Err(e) => return Err(e),
+                },
+                _ => return Err(RuntimeError::InvalidAssignmentTarget),
+            };
+            match current {
+                Some(current) if current != Value::Null => Ok(current),
+                _ => {
+                    let value = eval_expression(value, env)?;
+                    assign(target, value.clone(), env)?;
+                    Ok(value)
+                }
+            }
+        }
+        MemberAccess(target, member) => match (eval_expression(target, env)?, member.as_ref()) {
+            (Value::Null, member) => Err(RuntimeError::NullMemberAccess(member.to_string())),
+            (target, Variable(name)) => member_value(target, name),
+            _ => Err(RuntimeError::Unsupported(COMPUTED_MEMBER_ACCESS)),
+        },
+        Index(target, index) => {
+            let target = eval_expression(target, env)?;
+            index_value(target, eval_expression(index, env)?)
+        }
+        ListLiteral(items) => Ok(Value::list(items.iter().map(|item| eval_expression(item, env)).collect::<Result<_, _>>()?)),
+        MapLiteral(entries) => {
+            let mut map = BTreeMap::new();
+            for (key, value) in entries {
+                let key = MiniKey::from_value(eval_expression(key, env)?)?;
+                map.insert(key, eval_expression(value, env)?);
+            }
+            Ok(Value::map(map))
+        }
+        FunctionCall(callee, args) => {
+            let callee = eval_expression(callee, env)?;
+            let args = args.iter().map(|arg| eval_expression(arg, env)).collect::<Result<Vec<_>, _>>()?;
+            let traced = matches!(&callee, Value::Function(function) if matches!(function.body, FunctionBody::Script { .. }));
+            call_function(&callee, args, env).map_err(|err| if traced { err.called_from(expr) } else { err })
+        }
+        AddressOf(operand) => eval_expression(operand, env),
+        New(_) => Err(RuntimeError::Unsupported("'new'")),
+        IsA(..) => Err(RuntimeError::Unsupported("'isa'")),
+        Function { params, body } => Ok(closure(params, body, env)),
+    }
+}
+
+pub(crate) const COMPUTED_MEMBER_ACCESS: &str = "Computed member access";
+
+/// The function a literal evaluates to, closing over the current scopes.
+pub(crate) fn closure(params: &[(String, Option<Expression>)], body: &[Statement], env: &Environment) -> Value {
+    Value::Function(Rc::new(FunctionValue {
+        params: params.to_vec(),
+        body: FunctionBody::Script { statements: body.to_vec(), scopes: env.scopes.clone() },
+    }))
+}
+
+/// Resolves a list or string index, counting negative ones from the end.
+/// Fractional indices are truncated; NaN and infinities are errors.
+fn element_position(index: &Value, len: usize, container_type: &'static str) -> Result<usize, RuntimeError> {
+    let Value::Number(n) = index else {
+        return Err(RuntimeError::TypeMismatch { operator: "[]", left: container_type, right: Some(index.type_name()) });
+    };
+    if !n.is_finite() {
+        return Err(RuntimeError::NonFiniteIndex(*n));
+    }
+    let index = n.trunc() as i64;
+    let position = if index < 0 { index + len as i64 } else { index };
+    if position < 0 || position >= len as i64 {
+        return Err(RuntimeError::IndexOutOfRange { index, len });
+    }
+    Ok(position as usize)
+}
+
+/// `target.name`: the map entry `name`, such as `outer.count`, or else an
+/// intrinsic method such as `len` bound to `target`. Entries shadow methods.
+pub(crate) fn member_value(target: Value, name: &str) -> Result<Value, RuntimeError> {
+    if let Value::Map(entries) = &target {
+        if let Some(value) = entries.borrow().get(&MiniKey::from(name)) {
+            return Ok(value.clone());
+        }
+    }
+    match (Intrinsic::from_name(name).filter(|intrinsic| intrinsic.is_method()), target) {
+        (Some(intrinsic), target) => Ok(Value::Function(Rc::new(FunctionValue::method(intrinsic, target)))),
+        (None, Value::Map(_)) => Err(RuntimeError::KeyNotFound(name.to_string())),
+        (None, other) => Err(RuntimeError::unary(".", &other)),
+    }
+}
+
+/// `container[index]`: a list item, a one-character string, or a map entry.
+pub(crate) fn index_value(container: Value, index: Value) -> Result<Value, RuntimeError> {
+    match (container, index) {
+        (Value::Map(entries), key) => {
+            let key = MiniKey::from_value(key)?;
+            entries.borrow().get(&key).cloned().ok_or_else(|| RuntimeError::KeyNotFound(key.to_string()))
+        }
+        (Value::List(items), index) => {
+            let items = items.borrow();
+            let position = element_position(&index, items.len(), "list")?;
+            Ok(items[position].clone())
+        }
+        (Value::Str(s), index) => {
+            let chars: Vec<char> = s.chars().collect();
+            let position = element_position(&index, chars.len(), "string")?;
+            Ok(Value::Str(chars[position].to_string()))
+        }
+        (container, index) => Err(RuntimeError::binary("[]", &container, &index)),
+    }
+}
+
+/// Stores `value` through an assignment target: a variable, a scope member
+/// such as `globals.x` or `globals["x"]`, or an element of a list or map
+/// held by another target, as in `x[0] = 1` or `m.k = v`.
+fn assign(target: &Expression, value: Value, env: &mut Environment) -> Result<(), RuntimeError> {
+    let (container, key) = match target {
+        Variable(name) => {
+            env.set(name.clone(), value);
+            return Ok(());
+        }
+        MemberAccess(container, member) => match member.as_ref() {
+            Variable(name) => (container, Value::Str(name.clone())),
+            _ => return Err(RuntimeError::InvalidAssignmentTarget),
+        },
+        Index(container, index) => (container, eval_expression(index, env)?),
+        _ => return Err(RuntimeError::InvalidAssignmentTarget),
+    };
+    match container.as_ref() {
+        Variable(scope) if names_scope(scope, env) => store_in_scope(scope, key, value, env),
+        container => assign_element(eval_expression(container, env)?, key, value),
+    }
+}
+
+/// Whether `name` is `globals`, `outer` or `locals` and no variable
+/// shadows it.
+pub(crate) fn names_scope(name: &str, env: &Environment) -> bool {
+    matches!(name, "globals" | "outer" | "locals") && !env.contains(name)
+}
+
+/// `scope[key] = value` for a name that `names_scope` accepts. `globals` and
+/// the like are copies of a scope, so storing into one stores into the
+/// scope itself.
+pub(crate) fn store_in_scope(scope: &str, key: Value, value: Value, env: &mut Environment) -> Result<(), RuntimeError> {
+    let Value::Str(name) = key else {
+        return Err(RuntimeError::binary("[]=", &Value::map(BTreeMap::new()), &key));
+    };
+    match scope {
+        "globals" => env.set_global(name, value),
+        "outer" => env.set_outer(name, value),
+        _ => env.set(name, value),
+    }
+    Ok(())
+}
+
+/// Replaces one element of a list or map, in place, so every holder of the
+/// collection sees the change.
+pub(crate) fn assign_element(collection: Value, index: Value, value: Value) -> Result<(), RuntimeError> {
+    match (&collection, index) {
+        (Value::Map(entries), key) => {
+            entries.borrow_mut().insert(MiniKey::from_value(key)?, value);
+        }
+        (Value::List(items), index) => {
+            let mut items = items.borrow_mut();
+            let position = element_position(&index, items.len(), "list")?;
+            items[position] = value;
+        }
+        (collection, index) => return Err(RuntimeError::binary("[]=", collection, &index)),
+    }
+    Ok(())
+}
+
+/// Calls a function value with evaluated arguments. The body runs in a new
+/// scope on top of the scopes captured where the function was defined;
+/// parameters without an argument take their default, or null without one.
+/// Defaults are evaluated in that new scope, so they see the variables where
+/// the function was defined, and earlier parameters, rather than the caller's.
+pub fn call_function(callee: &Value, args: Vec<Value>, env: &mut Environment) -> Result<Value, RuntimeError> {
+    let Value::Function(function) = callee else {
+        return Err(RuntimeError::unary("call", callee));
+    };
+    let variadic = matches!(function.body, FunctionBody::Intrinsic(intrinsic) if intrinsic.is_variadic());
+    if args.len() > function.params.len() && !variadic {
+        return Err(RuntimeError::TooManyArguments { expected: function.params.len(), found: args.len() });
+    }
+    let mut args = args.into_iter();
+    let (statements, scopes) = match &function.body {
+        FunctionBody::Script { statements, scopes } => (statements, scopes),
+        FunctionBody::Intrinsic(intrinsic) | FunctionBody::Method(intrinsic, _) => {
+            let mut bound = Vec::new();
+            if let FunctionBody::Method(_, receiver) = &function.body {
+                bound.push(receiver.clone());
+            }
+            for (_, default) in &function.params {
+                bound.push(argument(args.next(), default.as_ref(), env)?);
+            }
+            bound.extend(args);
+            return intrinsic.call(bound, env);
+        }
+    };
+    let mut scopes = scopes.clone();
+    scopes.push(Scope::default());
+    if env.call_depth >= env.max_call_depth {
+        return Err(RuntimeError::StackOverflow);
+    }
+    let caller_scopes = std::mem::replace(&mut env.scopes, scopes);
+    env.call_depth += 1;
+    let mut bind_and_run = || {
+        for (name, default) in &function.params {
+            let value = argument(args.next(), default.as_ref(), env)?;
+            env.set(name.clone(), value);
+        }
+        exec_block(statements, env)
+    };
+    let result = bind_and_run();
+    env.call_depth -= 1;
+    env.scopes = caller_scopes;
+    match result? {
+        Flow::Return(value) => Ok(value),
+        _ => Ok(Value::Null),
+    }
+}
+
+/// The value a parameter takes: its argument, else its default, else null.
+fn argument(arg: Option<Value>, default: Option<&Expression>, env: &mut Environment) -> Result<Value, RuntimeError> {
+    match (arg, default) {
+        (Some(value), _) => Ok(value),
+        (None, Some(default)) => eval_expression(default, env),
+        (None, None) => Ok(Value::Null),
+    }
+}
+
+/// How a statement finished: by falling through to the next one, or by a
+/// `break`, `continue` or `return` unwinding to the enclosing loop or function.
+/// A labeled `break` or `continue` unwinds to the loop carrying that label.
+#[derive(Debug, PartialEq)]
+pub enum Flow {
+    Next,
+    Break(Option<String>),
+    Continue(Option<String>),
+    Return(Value),
+}
+
+/// The values a `for` loop visits: list items, the characters of a string,
+/// or `{"key": k, "value": v}` maps for the entries of a map.
+/// The items are those present when the loop starts, even if the body
+/// changes the collection.
+fn iteration_items(iterable: Value) -> Result<Vec<Value>, RuntimeError> {
+    match iterable {
+        Value::List(items) => Ok(items.borrow().clone()),
+        Value::Str(s) => Ok(s.chars().map(|c| Value::Str(c.to_string())).collect()),
+        Value::Map(entries) => Ok(entries
+            .borrow()
+            .iter()
+            .map(|(key, value)| {
+                Value::map(BTreeMap::from([(MiniKey::from("key"), key.clone().into_value()), (MiniKey::from("value"), value.clone())]))
+            })
+            .collect()),
+        other => Err(RuntimeError::unary("for", &other)),
+    }
+}
+
+/// Runs a loop body once, returning the flow the loop itself should take.
+/// Jumps naming another label pass through to the loop that has it.
+fn exec_loop_body(body: &[Statement], label: Option<&str>, env: &mut Environment) -> Result<Option<Flow>, RuntimeError> {
+    let targets_this = |target: &Option<String>| target.is_none() || target.as_deref() == label;
+    match exec_block(body, env)? {
+        Flow::Next => Ok(None),
+        Flow::Continue(target) if targets_this(&target) => Ok(None),
+        Flow::Break(target) if targets_this(&target) => Ok(Some(Flow::Next)),
+        flow => Ok(Some(flow)),
+    }
+}
+
+/// Runs a `while` or `for` loop, which `label` names if it is labeled.
+fn exec_loop(statement: &Statement, label: Option<&str>, env: &mut Environment) -> Result<Flow, RuntimeError> {
+    match statement {
+        Statement::While { condition, body } => {
+            while is_truthy(&eval_expression(condition, env)?) {
+                if let Some(flow) = exec_loop_body(body, label, env)? {
+                    return Ok(flow);
+                }
+            }
+            Ok(Flow::Next)
+        }
+        Statement::For { var, iterable, body } => {
+            for item in iteration_items(eval_expression(iterable, env)?)? {
+                env.set(var.clone(), item);
+                if let Some(flow) = exec_loop_body(body, label, env)? {
+                    return Ok(flow);
+                }
+            }
+            Ok(Flow::Next)
+        }
+        other => exec_statement(other, env),
+    }
+}
+
+pub fn exec_statement(statement: &Statement, env: &mut Environment) -> Result<Flow, RuntimeError> {
+    match statement {
+        Statement::Expression(expr) => eval_expression(expr, env).map(|_| Flow::Next),
+        Statement::If { branches, else_branch } => {
+            for (condition, body) in branches {
+                if is_truthy(&eval_expression(condition, env)?) {
+                    return exec_block(body, env);
+                }
+            }
+            match else_branch {
+                Some(body) => exec_block(body, env),
+                None => Ok(Flow::Next),
+            }
+        }
+        Statement::While { .. } | Statement::For { .. } => exec_loop(statement, None, env),
+        Statement::Labeled(label, statement) => exec_loop(statement, Some(label), env),
+        Statement::Return(value) => Ok(Flow::Return(match value {
+            Some(value) => eval_expression(value, env)?,
+            None => Value::Null,
+        })),
+        Statement::Break(label) => Ok(Flow::Break(label.clone())),
+        Statement::Continue(label) => Ok(Flow::Continue(label.clone())),
+        // Outside a block there is nothing to wait for, so it runs right away.
+        Statement::Defer(statement) => exec_statement(statement, env).map(|_| Flow::Next),
+    }
+}
+
+/// Runs statements in order, stopping early at the first one that does not
+/// fall through. Deferred statements run when the block exits, most recent
+/// first, whether it finishes normally, unwinds or fails; an error from the
+/// block itself takes precedence over one from a deferred statement.
+pub fn exec_block(statements: &[Statement], env: &mut Environment) -> Result<Flow, RuntimeError> {
+    run_statements(statements, env, &mut Value::Null)
+}
+
+/// `exec_block`, also recording the value of each expression statement
+/// directly in the block into `last`.
+fn run_statements(statements: &[Statement], env: &mut Environment, last: &mut Value) -> Result<Flow, RuntimeError> {
+    let mut deferred = Vec::new();
+    let mut result = Ok(Flow::Next);
+    for statement in statements {
+        let flow = match statement {
+            Statement::Defer(statement) => {
+                deferred.push(statement.as_ref());
+                continue;
+            }
+            Statement::Expression(expr) => eval_expression(expr, env).map(|value| {
+                *last = value;
+                Flow::Next
+            }),
+            statement => exec_statement(statement, env),
+        };
+        match flow {
+            Ok(Flow::Next) => (),
+            other => {
+                result = other;
+                break;
+            }
+        }
+    }
+    for statement in deferred.into_iter().rev() {
+        if let Err(e) = exec_statement(statement, env) {
+            if result.is_ok() {
+                result = Err(e);
+            }
+        }
+    }
+    result
+}
+
+/// Any error from lexing, parsing or running a program.
+#[derive(Debug)]
+pub enum InterpretError {
+    Lexer(LexerError),
+    Parse(ParseError),
+    Runtime(RuntimeError),
+}
+
+impl Display for InterpretError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FormatResult {
+        match self {
+            InterpretError::Lexer(e) => write!(f, "{}", e),
+            InterpretError::Parse(e) => write!(f, "{}", e),
+            InterpretError::Runtime(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for InterpretError {}
+
+impl From<LexerError> for InterpretError {
+    fn from(e: LexerError) -> Self {
+        InterpretError::Lexer(e)
+    }
+}
+
+impl From<ParseError> for InterpretError {
+    fn from(e: ParseError) -> Self {
+        InterpretError::Parse(e)
+    }
+}
+
+impl From<RuntimeError> for InterpretError {
+    fn from(e: RuntimeError) -> Self {
+        InterpretError::Runtime(e)
+    }
+}
+
+/// Runs `source` in `env`, returning the value of the last top-level
+/// expression statement (or of a top-level `return`) along with the
+/// environment, so state carries over into later calls.
+pub fn run_program<'e>(source: &str, env: &'e mut Environment) -> Result<(Value, &'e mut Environment), InterpretError> {
+    let program = parser::parse_program(&lexer::lex(source)?)?;
+    let value = exec_program(&program, env)?;
+    Ok((value, env))
+}
+
+/// Runs an already parsed program in `env`, returning the value of the last
+/// top-level expression statement or of a top-level `return`.
+pub fn exec_program(program: &[Statement], env: &mut Environment) -> Result<Value, RuntimeError> {
+    let mut last = Value::Null;
+    if let Flow::Return(value) = run_statements(program, env, &mut last)? {
+        last = value;
+    }
+    Ok(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(source: &str) -> Result<Value, InterpretError> {
+        run_program(source, &mut Environment::new()).map(|(value, _)| value)
+    }
+
+    #[test]
+    fn repeating_a_string_past_the_limit_fails() {
+        assert_eq!(run(r#""ab" * 3"#).unwrap(), Value::Str("ababab".to_string()));
+        assert_eq!(run(r#""ab" * 0.5"#).unwrap(), Value::Str(String::new()));
+        assert!(matches!(run(r#""x" * 1e20"#), Err(InterpretError::Runtime(RuntimeError::StringTooLong))));
+        assert!(matches!(run(r#""xx" * 8388609"#), Err(InterpretError::Runtime(RuntimeError::StringTooLong))));
+    }
+
+    #[test]
+    fn null_coalescing_assignment_stores_through_members_and_indices() {
+        assert_eq!(run("m = {}; m.a ??= 5; m.a ??= 6; m.a").unwrap(), Value::Number(5.));
+        assert_eq!(run("m = {\"a\": null}; m[\"a\"] ??= 1; m.a").unwrap(), Value::Number(1.));
+        assert_eq!(run("xs = [null, 2]; xs[0] ??= 7; xs[1] ??= 8; xs").unwrap(), run("[7, 2]").unwrap());
+        assert_eq!(run("globals.g ??= 3; g").unwrap(), Value::Number(3.));
+        assert!(matches!(run("xs = []; xs[0] ??= 1"), Err(InterpretError::Runtime(RuntimeError::IndexOutOfRange { .. }))));
+    }
+
+    const COUNTDOWN: &str = "f = function(n)\n  if n == 0 then\n    return 0\n  end if\n  return f(n - 1) + 1\nend function\n";
+
+    #[test]
+    fn calls_nest_up_to_the_configured_depth() {
+        let mut env = Environment::new();
+        env.set_max_call_depth(20);
+        assert_eq!(run_program(&format!("{}f(19)", COUNTDOWN), &mut env).unwrap().0, Value::Number(19.));
+        assert!(matches!(run_program("f(20)", &mut env), Err(InterpretError::Runtime(e)) if matches!(e.cause(), RuntimeError::StackOverflow)));
+        // A failed call unwinds the depth, so later calls start from zero again.
+        assert_eq!(run_program("f(5)", &mut env).unwrap().0, Value::Number(5.));
+    }
+
+    #[test]
+    fn runaway_recursion_fails_instead_of_overflowing_the_stack() {
+        let result = std::thread::Builder::new()
+            .stack_size(16 << 20)
+            .spawn(|| matches!(run("g = function()\n  return g()\nend function\ng()"), Err(InterpretError::Runtime(e)) if matches!(e.cause(), RuntimeError::StackOverflow)))
+            .unwrap()
+            .join()
+            .unwrap();
+        assert!(result);
+    }
+
+    #[test]
+    fn errors_in_functions_carry_the_calls_they_unwound_through() {
+        let source = "inner = function(x)\n  return x + missing\nend function\nouter = function()\n  return inner(1)\nend function\nouter()";
+        let Err(InterpretError::Runtime(err)) = run(source) else { panic!("expected a runtime error") };
+        assert!(matches!(err.cause(), RuntimeError::UndefinedVariable(name) if name == "missing"));
+        assert_eq!(err.to_string(), "Undefined variable 'missing'\n  in inner(1)\n  in outer()");
+        // Intrinsics add no frame of their own.
+        let Err(InterpretError::Runtime(err)) = run("len(1)") else { panic!("expected a runtime error") };
+        assert!(matches!(err, RuntimeError::TypeMismatch { .. }));
+    }
+
+    #[test]
+    fn defaults_are_evaluated_where_the_function_was_defined() {
+        let source = "y = 5\nf = function(a, b = y)\n  return b\nend function\ng = function()\n  y = 99\n  return f(1)\nend function\ng()";
+        assert_eq!(run(source).unwrap(), Value::Number(5.));
+        assert_eq!(run("f = function(a, b = a * 2)\n  return b\nend function\nf(3)").unwrap(), Value::Number(6.));
+        assert_eq!(run("f = function(a = 1)\n  return a\nend function\nf(7) + f()").unwrap(), Value::Number(8.));
+    }
+
+    #[test]
+    fn address_of_evaluates_to_the_function_without_calling_it() {
+        assert_eq!(run("f = function()\n  return 3\nend function\ng = @f\ng()").unwrap(), Value::Number(3.));
+        assert_eq!(run("typeof @len").unwrap(), Value::Str("function".to_string()));
+    }
+
+    #[test]
+    fn non_finite_indices_are_errors() {
+        for source in ["[1, 2][0/0]", "\"ab\"[1/0]", "xs = [1]; xs[-1/0] = 2"] {
+            assert!(matches!(run(source), Err(InterpretError::Runtime(RuntimeError::NonFiniteIndex(_)))), "{}", source);
+        }
+        let Err(InterpretError::Runtime(err)) = run("[1][0/0]") else { panic!("expected a runtime error") };
+        assert_eq!(err.to_string(), "Index NaN is not a finite number");
+    }
+
+    #[test]
+    fn lists_and_maps_are_shared_by_reference() {
+        assert_eq!(run("a = [1, 2]\nb = a\nb[0] = 9\na[0]").unwrap(), Value::Number(9.));
+        assert_eq!(run("m = {}\nn = m\nn.k = 1\nm.k").unwrap(), Value::Number(1.));
+        assert_eq!(run("a = [[0]]\nf = function(x)\n  x[0][0] = 5\nend function\nf(a)\na[0][0]").unwrap(), Value::Number(5.));
+        assert_eq!(run("globals[\"g\"] = 4\ng").unwrap(), Value::Number(4.));
+    }
+
+    #[test]
+    fn lists_and_maps_inside_themselves_print_and_compare() {
+        assert_eq!(run("a = [1]\na[0] = a\na").unwrap().repr(), "[[...]]");
+        assert_eq!(run("m = {}\nm.self = m\nm").unwrap().repr(), r#"{"self": {...}}"#);
+        assert_eq!(run("a = [1]\na[0] = a\nb = [1]\nb[0] = b\na == a and a == b").unwrap(), Value::Bool(true));
+    }
+
+    #[test]
+    fn conditional_expressions_evaluate_only_the_chosen_branch() {
+        assert_eq!(run("x = if 1 then 2 else missing\nx").unwrap(), Value::Number(2.));
+        assert_eq!(run("x = if \"\" then missing else \"no\"\nx").unwrap(), Value::Str("no".to_string()));
+        assert!(matches!(run("x = if 0 then 1 else missing"), Err(InterpretError::Runtime(RuntimeError::UndefinedVariable(_)))));
+    }
+
+    #[test]
+    fn expressions_evaluate_to_values() {
+        let eval = |source: &str| eval_expression(&source.parse().unwrap(), &mut Environment::new());
+        assert_eq!(eval("1 + 2 * 3 - 4 / 8").unwrap(), Value::Number(6.5));
+        assert_eq!(eval("7 % 3").unwrap(), Value::Number(1.));
+        assert_eq!(eval("-(2)").unwrap(), Value::Number(-2.));
+        assert_eq!(eval("not 0").unwrap(), Value::Bool(true));
+        assert_eq!(eval("\"a\" + \"b\"").unwrap(), Value::Str("ab".to_string()));
+        assert_eq!(eval("2 < 3").unwrap(), Value::Bool(true));
+        assert_eq!(eval("1 + true").unwrap_err().to_string(), "Cannot apply '+' to number and boolean");
+        assert_eq!(eval("undefinedThing").unwrap_err().to_string(), "Undefined variable 'undefinedThing'");
+    }
+
+    #[test]
+    fn concatenation_turns_numbers_and_null_into_text() {
+        assert_eq!(run("\"a\" + 1").unwrap(), Value::Str("a1".to_string()));
+        assert_eq!(run("1.5 + \"x\"").unwrap(), Value::Str("1.5x".to_string()));
+        assert_eq!(run("\"x\" + null").unwrap(), Value::Str("x".to_string()));
+        assert_eq!(run("\"ab\" * -1").unwrap(), Value::Str(String::new()));
+        assert!(run("\"x\" / 2").is_err());
+    }
+
+    #[test]
+    fn maps_print_in_sorted_key_order() {
+        let value = run("{\"b\": 1, \"a\": [\"x\", 2], 3: null, null: true}").unwrap();
+        assert_eq!(value.repr(), r#"{null: true, 3: null, "a": ["x", 2], "b": 1}"#);
+        assert_eq!(value.to_string(), value.repr());
+        assert_eq!(format!("{:?}", value), r#"Map({null: true, 3: null, "a": ["x", 2], "b": 1})"#);
+        assert_eq!(Value::Str("q\"".to_string()).repr(), r#""q""""#);
+        assert_eq!(Value::Str("q".to_string()).to_string(), "q");
+    }
+
+    #[test]
+    fn empty_and_zero_values_are_falsey() {
+        for source in ["null", "0", "-0", "false", "\"\"", "[]", "{}"] {
+            assert!(!is_truthy(&run(source).unwrap()), "{}", source);
+        }
+        for source in ["1", "0.5", "true", "\"0\"", "[0]", "{0: 0}", "@len"] {
+            assert!(is_truthy(&run(source).unwrap()), "{}", source);
+        }
+        assert_eq!(run("x = if [] then 1 else 2\nx").unwrap(), Value::Number(2.));
+        assert_eq!(run("not \"\"").unwrap(), Value::Bool(true));
+    }
+
+    #[test]
+    fn if_statements_run_the_first_truthy_branch() {
+        let program = |n: i32| format!("n = {}\nif n < 0 then\n  x = \"neg\"\nelse if n == 0 then\n  x = \"zero\"\nelse\n  x = \"pos\"\nend if\nx", n);
+        assert_eq!(run(&program(-1)).unwrap(), Value::Str("neg".to_string()));
+        assert_eq!(run(&program(0)).unwrap(), Value::Str("zero".to_string()));
+        assert_eq!(run(&program(1)).unwrap(), Value::Str("pos".to_string()));
+    }
+
+    #[test]
+    fn while_loops_run_until_the_condition_is_falsey() {
+        assert_eq!(run("i = 0\nwhile i < 5\n  i = i + 2\nend while\ni").unwrap(), Value::Number(6.));
+        assert_eq!(run("i = 0\nwhile i\n  i = 1\nend while\ni").unwrap(), Value::Number(0.));
+    }
+
+    #[test]
+    fn for_loops_visit_list_items_characters_and_map_entries() {
+        assert_eq!(run("s = 0\nfor i in [1, 2, 3]\n  s = s + i\nend for\ns").unwrap(), Value::Number(6.));
+        assert_eq!(run("s = \"\"\nfor c in \"abc\"\n  s = c + s\nend for\ns").unwrap(), Value::Str("cba".to_string()));
+        assert_eq!(run("s = \"\"\nfor e in {\"b\": 2, \"a\": 1}\n  s = s + e.key + e.value\nend for\ns").unwrap(), Value::Str("a1b2".to_string()));
+        assert_eq!(run("l = [1, 2]\nfor i in l\n  l[1] = 5\n  s = i\nend for\ns").unwrap(), Value::Number(2.));
+        assert!(run("for i in 5\nend for").is_err());
+    }
+
+    #[test]
+    fn return_break_and_continue_leave_their_function_or_loop() {
+        assert_eq!(run("f = function()\n  return 1\n  return 2\nend function\nf()").unwrap(), Value::Number(1.));
+        assert_eq!(run("f = function()\n  return\nend function\nf()").unwrap(), Value::Null);
+        let source = "s = 0\nfor i in range(1, 10)\n  if i % 2 then\n    continue\n  end if\n  if i > 6 then\n    break\n  end if\n  s = s + i\nend for\ns";
+        assert_eq!(run(source).unwrap(), Value::Number(12.));
+    }
+
+    #[test]
+    fn pretty_output_puts_each_element_on_its_own_line() {
+        let value = run("{\"a\": [1, []], \"b\": {}}").unwrap();
+        assert_eq!(value.repr_pretty(2), "{\n  \"a\": [\n    1,\n    []\n  ],\n  \"b\": {}\n}");
+        assert_eq!(run("[\"x\"]").unwrap().repr_pretty(0), "[\n\"x\"\n]");
+        assert_eq!(run("3").unwrap().repr_pretty(4), "3");
+    }
+
+    #[test]
+    fn hosts_can_ask_whether_a_variable_exists() {
+        let mut env = Environment::new();
+        run_program("x = null", &mut env).unwrap();
+        assert!(env.contains("x"));
+        assert!(!env.contains("y"));
+        assert!(!env.contains("len"));
+        assert_eq!(env.get_cloned("x"), Some(Value::Null));
+        assert_eq!(env.get_cloned("y"), None);
+    }
+
+    /// Runs `source`, returning its result and the lines it printed.
+    fn run_printing(source: &str) -> (Result<Value, InterpretError>, Vec<String>) {
+        let lines = Rc::new(RefCell::new(Vec::new()));
+        let mut env = Environment::new();
+        let sink = Rc::clone(&lines);
+        env.set_print_sink(move |line| sink.borrow_mut().push(line.to_string()));
+        let result = run_program(source, &mut env).map(|(value, _)| value);
+        (result, lines.take())
+    }
+
+    #[test]
+    fn print_writes_to_a_fmt_write_target() {
+        let out = Rc::new(RefCell::new(String::new()));
+        let mut env = Environment::new();
+        env.set_print_writer(Rc::clone(&out));
+        run_program("print(\"x\", 1)\nprint()", &mut env).unwrap();
+        assert_eq!(*out.borrow(), "x 1\n\n");
+    }
+
+    #[test]
+    fn print_lays_out_values_as_the_output_options_say() {
+        let out = Rc::new(RefCell::new(String::new()));
+        let mut env = Environment::new();
+        env.set_print_writer(Rc::clone(&out));
+        env.set_output_options(OutputOptions { flush_each: true, field_separator: "|".to_string(), line_ending: "\r\n".to_string() });
+        run_program("print(1, 2)\nprint(\"a\")", &mut env).unwrap();
+        assert_eq!(out.borrow().as_bytes(), b"1|2\r\na\r\n");
+        // Line sinks get the joined values but no ending.
+        let lines = Rc::new(RefCell::new(Vec::new()));
+        let sink = Rc::clone(&lines);
+        env.set_print_sink(move |line| sink.borrow_mut().push(line.to_string()));
+        run_program("print(1, 2)", &mut env).unwrap();
+        assert_eq!(*lines.borrow(), ["1|2"]);
+    }
+
+    #[test]
+    fn deferred_statements_run_at_block_exit_most_recent_first() {
+        let source = "f = function()\n  defer print(\"a\")\n  defer print(\"b\")\n  print(\"body\")\n  return 1\n  print(\"never\")\nend function\nf()";
+        let (result, lines) = run_printing(source);
+        assert_eq!(result.unwrap(), Value::Number(1.));
+        assert_eq!(lines, ["body", "b", "a"]);
+        let (result, lines) = run_printing("f = function()\n  defer print(\"cleanup\")\n  return missing\nend function\nf()");
+        assert!(matches!(result, Err(InterpretError::Runtime(e)) if matches!(e.cause(), RuntimeError::UndefinedVariable(_))));
+        assert_eq!(lines, ["cleanup"]);
+        let (_, lines) = run_printing("for i in [1, 2]\n  defer print(i)\n  print(i * 10)\nend for");
+        assert_eq!(lines, ["10", "1", "20", "2"]);
+    }
+
+    #[test]
+    fn programs_return_their_last_value_and_keep_the_environment() {
+        let mut env = Environment::new();
+        let (value, env) = run_program("x = 1\nx + 1\nif x then\n  x + 5\nend if", &mut env).unwrap();
+        assert_eq!(value, Value::Number(2.));
+        assert_eq!(run_program("return x * 10\n3", env).unwrap().0, Value::Number(10.));
+        assert_eq!(run_program("y = 2", env).unwrap().0, Value::Number(2.));
+        assert_eq!(env.get("y"), Some(Value::Number(2.)));
+        assert_eq!(run_program("$", env).unwrap_err().to_string(), "Invalid character '$' at 1:1");
+        assert_eq!(run_program("(", env).unwrap_err().to_string(), "Expected primary but found end of input at 1:2");
+    }
+
+    #[test]
+    fn ranges_count_toward_their_end_by_the_step() {
+        assert_eq!(run("1 to 5").unwrap().repr(), "[1, 2, 3, 4, 5]");
+        assert_eq!(run("5 to 1").unwrap().repr(), "[5, 4, 3, 2, 1]");
+        assert_eq!(run("0 to 1 by 0.25").unwrap().repr(), "[0, 0.25, 0.5, 0.75, 1]");
+        assert_eq!(run("3 to 1 by 1").unwrap().repr(), "[]");
+        assert!(matches!(run("1 to 3 by 0"), Err(InterpretError::Runtime(RuntimeError::ZeroRangeStep))));
+    }
+
+    #[test]
+    fn ranges_must_be_finite_and_bounded_in_length() {
+        for source in ["1 to 1/0", "range(0, 1/0)", "0 to 1 by 0/0", "-1/0 to 0", "range(0, 1, 0/0)"] {
+            assert!(matches!(run(source), Err(InterpretError::Runtime(RuntimeError::NonFiniteRange))), "{}", source);
+        }
+        // A step of 1e-300, spelled as a division since exponents are unsigned.
+        for source in ["0 to 1 by 1 / 1e300", "range(0, 1e300)", "-1e308 to 1e308"] {
+            assert!(matches!(run(source), Err(InterpretError::Runtime(RuntimeError::RangeTooLong))), "{}", source);
+        }
+        assert_eq!(run("0 to 1e300 by -1").unwrap().repr(), "[]");
+    }
+
+    #[test]
+    fn xor_truncates_its_operands_to_integers() {
+        assert_eq!(run("5 ^ 3").unwrap(), Value::Number(6.));
+        assert_eq!(run("5.9 ^ 1").unwrap(), Value::Number(4.));
+        assert_eq!(run("-5.7 ^ 1").unwrap(), Value::Number(-6.));
+        assert!(run("\"a\" ^ 1").is_err());
+    }
+
+    #[test]
+    fn and_or_return_the_deciding_operand() {
+        assert_eq!(run("0 and 1").unwrap(), Value::Number(0.));
+        assert_eq!(run("2 and 3").unwrap(), Value::Number(3.));
+        assert_eq!(run("\"\" or \"x\"").unwrap(), Value::Str("x".to_string()));
+        assert_eq!(run("[] or null").unwrap(), Value::Null);
+        assert_eq!(run("[1] or null").unwrap().repr(), "[1]");
+    }
+
+    #[test]
+    fn and_or_skip_the_right_side_once_decided() {
+        assert_eq!(run("false and nope").unwrap(), Value::Bool(false));
+        assert_eq!(run("true or nope").unwrap(), Value::Bool(true));
+        assert!(run("true and nope").is_err());
+        let (_, printed) = run_printing("f = function()\n  print(\"called\")\n  return 1\nend function\nx = 0 and f()\ny = 1 or f()\nz = 1 and f()");
+        assert_eq!(printed, ["called"]);
+    }
+
+    #[test]
+    fn keyword_members_read_and_write_map_entries() {
+        assert_eq!(run("m = {\"if\": 1}\nm.typeof = 2\nm.if + m[\"typeof\"]").unwrap(), Value::Number(3.));
+    }
+
+    #[test]
+    fn numbers_print_like_miniscript() {
+        for (n, text) in [(5., "5"), (5.5, "5.5"), (1. / 3., "0.333333"), (1e6, "1000000"), (-0., "0"), (1e11 + 0.5, "1.000000E+011"), (-1e-7, "-1.000000E-007")] {
+            assert_eq!(Value::Number(n).to_mini_string(), text);
+        }
+        assert_eq!(Value::Null.to_mini_string(), "");
+        assert_eq!(Value::Str("q".to_string()).to_mini_string(), "q");
+        assert_eq!(run("\"a\" + null + 1/3 + -0").unwrap(), Value::Str("a0.3333330".to_string()));
+    }
+
+    #[test]
+    fn labeled_break_and_continue_reach_the_outer_loop() {
+        let source = "outer: for i in [1, 2, 3]\n  for j in [1, 2, 3]\n    if j == 2 then continue outer\n    if i == 3 then break outer\n    print(i * 10 + j)\n  end for\nend for\nprint(\"done\")";
+        let (result, printed) = run_printing(source);
+        result.unwrap();
+        assert_eq!(printed, ["11", "21", "done"]);
+        let (result, printed) = run_printing("outer: while true\n  while true\n    break outer\n  end while\n  print(\"inner\")\nend while");
+        result.unwrap();
+        assert!(printed.is_empty());
+    }
+
+    #[test]
+    fn seeded_environments_repeat_their_random_sequence() {
+        let draws = |seed: u64| {
+            let mut env = Environment::new();
+            env.seed_rng(seed);
+            (0..20).map(|_| env.random()).collect::<Vec<_>>()
+        };
+        assert_eq!(draws(42), draws(42));
+        assert_ne!(draws(42), draws(43));
+        assert!(draws(0).iter().all(|n| (0. ..1.).contains(n)));
+        assert_ne!(draws(0)[0], draws(0)[1]);
+
+        let mut env = Environment::new();
+        env.seed_rng(1);
+        let rolls: Vec<_> = (0..200).map(|_| env.randint(6, 1)).collect();
+        assert!(rolls.iter().all(|roll| (1..=6).contains(roll)));
+        assert!((1..=6).all(|face| rolls.contains(&face)));
+        assert_eq!(env.randint(5, 5), 5);
+        // The full range must not overflow the span.
+        env.randint(i64::MIN, i64::MAX);
+    }
+
+    #[test]
+    fn scopes_read_outward_and_assign_locally() {
+        let mut env = Environment::new();
+        env.set("x".to_string(), Value::Number(1.));
+        env.push_scope();
+        assert_eq!(env.get("x"), Some(Value::Number(1.)));
+        env.set("x".to_string(), Value::Number(2.));
+        env.set_outer("y".to_string(), Value::Number(3.));
+        assert_eq!(env.get("x"), Some(Value::Number(2.)));
+        assert_eq!(env.locals().repr(), "{\"x\": 2}");
+        assert_eq!(env.globals().repr(), "{\"x\": 1, \"y\": 3}");
+        env.pop_scope();
+        env.pop_scope();
+        assert_eq!(env.get("x"), Some(Value::Number(1.)));
+        assert!(env.contains("y"));
+    }
+
+    #[test]
+    fn functions_shadow_globals_unless_assigning_through_globals() {
+        let shadowed = run("x = 1\nf = function()\n  x = 2\n  return [x, globals.x, locals.x]\nend function\n[f(), x]").unwrap();
+        assert_eq!(shadowed.repr(), "[[2, 1, 2], 1]");
+        assert_eq!(run("x = 1\nf = function()\n  globals.x = x + 5\nend function\nf()\nx").unwrap(), Value::Number(6.));
+        let nested = "f = function()\n  y = 3\n  g = function()\n    return outer.y\n  end function\n  return g()\nend function\nf()";
+        assert_eq!(run(nested).unwrap(), Value::Number(3.));
+        assert_eq!(run("locals == globals").unwrap(), Value::Bool(true));
+    }
+
+    #[test]
+    fn members_of_null_are_errors_even_under_typeof() {
+        for source in ["null.x", "typeof null.x", "m = {\"a\": null}\nm.a.b"] {
+            assert!(matches!(run(source), Err(InterpretError::Runtime(RuntimeError::NullMemberAccess(_)))), "{}", source);
+        }
+        assert_eq!(run("null.x").unwrap_err().to_string(), "Cannot access 'x' of null");
+        assert!(matches!(run("typeof nope"), Err(InterpretError::Runtime(RuntimeError::UndefinedVariable(_)))));
+        assert_eq!(run("typeof null").unwrap(), Value::Str("null".to_string()));
+    }
+
+    #[test]
+    fn division_by_zero_follows_ieee_754() {
+        assert_eq!(run("1/0").unwrap(), Value::Number(f64::INFINITY));
+        assert_eq!(run("-1/0").unwrap(), Value::Number(f64::NEG_INFINITY));
+        for source in ["0/0", "5 % 0", "-5 % 0"] {
+            assert!(matches!(run(source).unwrap(), Value::Number(n) if n.is_nan()), "{}", source);
+        }
+        assert_eq!(run("str(1/0) + str(-1/0) + str(0/0)").unwrap(), Value::Str("INF-INFNaN".to_string()));
+        assert_eq!(run("-7 % 3").unwrap(), Value::Number(-1.));
+    }
+
+    #[test]
+    fn members_look_up_map_keys_before_methods() {
+        assert_eq!(run("m = {\"a\": {\"b\": 3}}\nm.a.b").unwrap(), Value::Number(3.));
+        assert_eq!(run("m = {\"len\": 7}\nm.len").unwrap(), Value::Number(7.));
+        assert_eq!(run("m = {\"a\": 1}\nm.len()").unwrap(), Value::Number(1.));
+        assert!(matches!(run("m = {}\nm.nope"), Err(InterpretError::Runtime(RuntimeError::KeyNotFound(_)))));
+    }
+
+    #[test]
+    fn methods_bind_their_value_as_self() {
+        assert_eq!(run("\"hello\".len()").unwrap(), Value::Number(5.));
+        assert_eq!(run("[1, 2, 3].indexOf(2)").unwrap(), Value::Number(1.));
+        assert_eq!(run("f = \"hello\".len\nf()").unwrap(), Value::Number(5.));
+        assert!(matches!(run("\"hello\".len").unwrap(), Value::Function(_)));
+        assert_eq!(run("\"a\".nope").unwrap_err().to_string(), "Cannot apply '.' to string");
+        assert_eq!(run("x = 5\nx.len()").unwrap_err().to_string(), "Cannot apply 'len' to number");
+        assert!(matches!(run("null.len"), Err(InterpretError::Runtime(RuntimeError::NullMemberAccess(_)))));
+    }
+
+    #[test]
+    fn equal_numbers_are_the_same_key() {
+        let hash = |key: &MiniKey| {
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            key.hash(&mut hasher);
+            hasher.finish()
+        };
+        let one = MiniKey::from_value(Value::Number(1.)).unwrap();
+        assert_eq!(one, MiniKey::from_value(Value::Number(1.0)).unwrap());
+        let zero = MiniKey::from_value(Value::Number(0.)).unwrap();
+        let negative_zero = MiniKey::from_value(Value::Number(-0.)).unwrap();
+        assert_eq!((&zero, hash(&zero)), (&negative_zero, hash(&negative_zero)));
+        assert_ne!(one, MiniKey::from("1"));
+        assert_eq!(run("m = {}\nm[1] = \"a\"\nm[1.0]").unwrap(), Value::Str("a".to_string()));
+        assert_eq!(run("m = {-0: 1, \"k\": 2}\nm[0] + m[\"k\"]").unwrap(), Value::Number(3.));
+    }
+
+    #[test]
+    fn keys_sort_null_then_numbers_then_strings() {
+        assert_eq!(run("{\"b\": 2, 1: 3, null: 1, \"a\": 4, -5: 0}").unwrap().repr(), "{null: 1, -5: 0, 1: 3, \"a\": 4, \"b\": 2}");
+        for source in ["{[1]: 2}", "{true: 1}", "{0/0: 1}", "m = {}\nm[{}] = 1"] {
+            assert!(run(source).is_err(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn equality_is_structural_and_never_crosses_types() {
+        for (source, equal) in [
+            ("[1,2] == [1,2]", true),
+            ("{\"a\":1} == {\"a\":1}", true),
+            ("{\"a\":1} == {\"a\":2}", false),
+            ("[1] != [1, 2]", true),
+            ("1 == \"1\"", false),
+            ("null == null", true),
+            ("null == 0", false),
+            ("-0 == 0", true),
+            ("0/0 == 0/0", false),
+            ("[0/0] == [0/0]", false),
+            ("a = [1]\na[0] = a\nb = [1]\nb[0] = b\na == b", true),
+        ] {
+            assert_eq!(run(source).unwrap(), Value::Bool(equal), "{}", source);
+        }
+        assert!(values_equal(&Value::Number(2.), &Value::Number(2.)));
+        assert!(!values_equal(&Value::Bool(true), &Value::Number(1.)));
+    }
+
+    #[test]
+    fn strings_order_by_code_point_and_do_not_compare_with_numbers() {
+        for (source, result) in [
+            ("\"a\" < \"b\"", true),
+            ("\"b\" < \"a\"", false),
+            ("\"a\" < \"a\"", false),
+            ("\"a\" <= \"a\"", true),
+            ("\"a\" >= \"a\"", true),
+            ("\"B\" < \"a\"", true),
+            ("\"ab\" > \"a\"", true),
+            ("\"\u{e9}\" > \"z\"", true),
+        ] {
+            assert_eq!(run(source).unwrap(), Value::Bool(result), "{}", source);
+        }
+        assert_eq!(run("1 < \"2\"").unwrap_err().to_string(), "Cannot apply '<' to number and string");
+        assert!(matches!(run("\"2\" >= 1"), Err(InterpretError::Runtime(RuntimeError::TypeMismatch { .. }))));
+    }
+
+    #[test]
+    fn map_keys_iterate_in_the_same_order_in_every_map() {
+        let keys = |source: &str| {
+            let Value::Map(entries) = run(source).unwrap() else { panic!("expected a map") };
+            let keys: Vec<_> = entries.borrow().keys().cloned().collect();
+            keys
+        };
+        let source = "m = {}\nm[\"b\"] = 1\nm[2] = 2\nm[null] = 3\nm[\"a\"] = 4\nm[-1] = 5\nm";
+        let expected = [MiniKey::Null, MiniKey::Number(-1.), MiniKey::Number(2.), MiniKey::from("a"), MiniKey::from("b")];
+        assert_eq!(keys(source), expected);
+        assert_eq!(keys(source), keys(source));
+        assert_eq!(keys("{-1: 5, \"a\": 4, null: 3, 2: 2, \"b\": 1}"), expected);
+        assert_eq!(run("zeta = 1\nalpha = 2\nmid = 3\nglobals").unwrap().repr(), "{\"alpha\": 2, \"mid\": 3, \"zeta\": 1}");
+    }
+
+    #[test]
+    fn expression_statements_run_for_their_side_effects() {
+        let mut env = Environment::new();
+        let (value, env) = run_program("x = 0; x = x + 1; x;", &mut env).unwrap();
+        assert_eq!(value, Value::Number(1.));
+        assert_eq!(env.get("x"), Some(Value::Number(1.)));
+        let (result, printed) = run_printing("n = 0\nbump = function()\n  globals.n = n + 1\nend function\nbump(); bump();\nprint(n)");
+        result.unwrap();
+        assert_eq!(printed, ["2"]);
+    }
+}
@@ -0,0 +1,361 @@
+//! Constant folding: subtrees whose operands are all literals are evaluated
+//! ahead of time with the evaluator's own rules and replaced by the result.
+//! Constant propagation feeds it variables that provably hold a literal.
+
+use std::collections::HashMap;
+
+use crate::{
+    eval::{eval_expression, is_truthy, Environment, Value},
+    parser::{Expression, Statement},
+    visit::{walk_expression, walk_expression_mut, walk_statement, Visitor, VisitorMut},
+};
+use Expression::*;
+
+fn is_literal(expr: &Expression) -> bool {
+    matches!(expr, StringValue(_) | NumberValue(_) | BoolValue(_) | NullValue)
+}
+
+fn literal_value(expr: &Expression) -> Option<Value> {
+    match expr {
+        StringValue(s) => Some(Value::Str(s.clone())),
+        NumberValue(n) => Some(Value::Number(*n)),
+        BoolValue(b) => Some(Value::Bool(*b)),
+        NullValue => Some(Value::Null),
+        _ => None,
+    }
+}
+
+/// The literal spelling a value back, if it has one. NaN and infinities
+/// have none.
+fn to_literal(value: Value) -> Option<Expression> {
+    match value {
+        Value::Number(n) if n.is_finite() => Some(NumberValue(n)),
+        Value::Str(s) => Some(StringValue(s)),
+        Value::Bool(b) => Some(BoolValue(b)),
+        Value::Null => Some(NullValue),
+        _ => None,
+    }
+}
+
+/// Whether `expr` is an operator applied only to literals.
+fn has_literal_operands(expr: &Expression) -> bool {
+    match expr {
+        LogicalNot(operand) | UnaryNegation(operand) | Typeof(operand) => is_literal(operand),
+        Multiplication(left, right)
+        | Division(left, right)
+        | Remainder(left, right)
+        | Power(left, right)
+        | BitwiseXor(left, right)
+        | Addition(left, right)
+        | Subtraction(left, right)
+        | LessThan(left, right)
+        | LessThanEq(left, right)
+        | GreaterThan(left, right)
+        | GreaterThanEq(left, right)
+        | Equality(left, right)
+        | Inequality(left, right)
+        | LogicalAnd(left, right)
+        | LogicalOr(left, right) => is_literal(left) && is_literal(right),
+        Between { low, value, high, .. } => is_literal(low) && is_literal(value) && is_literal(high),
+        _ => false,
+    }
+}
+
+/// Whether `expr` divides by a literal zero. What that yields (infinity,
+/// NaN or an error) is the evaluator's business, so it is never folded, even
+/// where the current result would happen to have a literal spelling.
+fn divides_by_zero(expr: &Expression) -> bool {
+    matches!(expr, Division(_, divisor) | Remainder(_, divisor) if matches!(**divisor, NumberValue(n) if n == 0.))
+}
+
+struct ConstantFolder;
+
+impl VisitorMut for ConstantFolder {
+    fn visit_expression_mut(&mut self, expr: &mut Expression) {
+        walk_expression_mut(self, expr);
+        if let Conditional(cond, then, otherwise) = expr {
+            if let Some(cond) = literal_value(cond) {
+                let branch = if is_truthy(&cond) { then } else { otherwise };
+                *expr = std::mem::replace(branch.as_mut(), NullValue);
+            }
+            return;
+        }
+        if !has_literal_operands(expr) || divides_by_zero(expr) {
+            return;
+        }
+        // Runtime errors such as type mismatches are left for run time.
+        if let Some(literal) = eval_expression(expr, &mut Environment::new()).ok().and_then(to_literal) {
+            *expr = literal;
+        }
+    }
+}
+
+/// Folds every constant subtree of `expr`, including those nested in
+/// function bodies.
+pub fn fold_constants(mut expr: Expression) -> Expression {
+    ConstantFolder.visit_expression_mut(&mut expr);
+    expr
+}
+
+/// Counts the places each variable can be written: assignments, `??=`,
+/// loop variables and parameters, anywhere in the program. Also notes whether
+/// `globals`, `locals` or `outer` appear, since those write variables by name.
+#[derive(Default)]
+struct AssignmentCounter {
+    counts: HashMap<String, usize>,
+    aliased: bool,
+}
+
+impl AssignmentCounter {
+    fn count(&mut self, name: &str) {
+        *self.counts.entry(name.to_string()).or_default() += 1;
+    }
+}
+
+impl Visitor for AssignmentCounter {
+    fn visit_expression(&mut self, expr: &Expression) {
+        match expr {
+            Variable(name) if matches!(name.as_str(), "globals" | "locals" | "outer") => self.aliased = true,
+            Assignment(target, _) | NullCoalescingAssignment(target, _) => {
+                if let Variable(name) = target.as_ref() {
+                    self.count(name);
+                }
+            }
+            Function { params, .. } => params.iter().for_each(|(name, _)| self.count(name)),
+            _ => (),
+        }
+        walk_expression(self, expr);
+    }
+
+    fn visit_statement(&mut self, statement: &Statement) {
+        if let Statement::For { var, .. } = statement {
+            self.count(var);
+        }
+        walk_statement(self, statement);
+    }
+}
+
+/// Substitutes variables known to hold a literal into the expressions that
+/// read them, block by block, folding each statement afterwards.
+struct ConstantPropagator {
+    counts: HashMap<String, usize>,
+    known: HashMap<String, Expression>,
+}
+
+impl ConstantPropagator {
+    fn block(&mut self, statements: &mut [Statement]) {
+        statements.iter_mut().for_each(|statement| self.statement(statement));
+    }
+
+    /// Runs `block` with the current knowledge, then forgets whatever it
+    /// learned: assignments in a branch or loop body may not have run.
+    fn nested_block(&mut self, statements: &mut [Statement]) {
+        let known = self.known.clone();
+        self.block(statements);
+        self.known = known;
+    }
+
+    fn expression(&mut self, expr: &mut Expression) {
+        self.visit_expression_mut(expr);
+        *expr = fold_constants(std::mem::replace(expr, NullValue));
+    }
+
+    fn statement(&mut self, statement: &mut Statement) {
+        match statement {
+            Statement::Expression(expr) => {
+                self.expression(expr);
+                if let Assignment(target, value) = expr {
+                    if let Variable(name) = target.as_ref() {
+                        if self.counts.get(name) == Some(&1) && is_literal(value) {
+                            self.known.insert(name.clone(), value.as_ref().clone());
+                        }
+                    }
+                }
+            }
+            Statement::Return(Some(expr)) => self.expression(expr),
+            Statement::If { branches, else_branch } => {
+                for (condition, body) in branches {
+                    self.expression(condition);
+                    self.nested_block(body);
+                }
+                if let Some(body) = else_branch {
+                    self.nested_block(body);
+                }
+            }
+            Statement::While { condition: expr, body } | Statement::For { iterable: expr, body, .. } => {
+                self.expression(expr);
+                self.nested_block(body);
+            }
+            Statement::Labeled(_, statement) => self.statement(statement),
+            Statement::Defer(statement) => {
+                let known = self.known.clone();
+                self.statement(statement);
+                self.known = known;
+            }
+            Statement::Return(None) | Statement::Break(_) | Statement::Continue(_) => (),
+        }
+    }
+
+    /// Visits the reads inside an assignment target, such as an index, but
+    /// not the variables being written.
+    fn target(&mut self, target: &mut Expression) {
+        match target {
+            Variable(_) => (),
+            Index(container, index) => {
+                self.target(container);
+                self.visit_expression_mut(index);
+            }
+            MemberAccess(container, member) => {
+                self.target(container);
+                if !matches!(member.as_ref(), Variable(_)) {
+                    self.visit_expression_mut(member);
+                }
+            }
+            _ => self.visit_expression_mut(target),
+        }
+    }
+}
+
+impl VisitorMut for ConstantPropagator {
+    fn visit_expression_mut(&mut self, expr: &mut Expression) {
+        match expr {
+            Variable(name) => {
+                if let Some(value) = self.known.get(name) {
+                    *expr = value.clone();
+                }
+            }
+            MemberAccess(container, member) => {
+                self.visit_expression_mut(container);
+                if !matches!(member.as_ref(), Variable(_)) {
+                    self.visit_expression_mut(member);
+                }
+            }
+            Assignment(target, value) | NullCoalescingAssignment(target, value) => {
+                self.target(target);
+                self.visit_expression_mut(value);
+            }
+            AddressOf(_) => (),
+            // A function body is its own scope and runs at some later time,
+            // so it starts knowing nothing. Defaults are evaluated when it is
+            // called and are left alone.
+            Function { body, .. } => {
+                let known = std::mem::take(&mut self.known);
+                self.block(body);
+                self.known = known;
+            }
+            _ => walk_expression_mut(self, expr),
+        }
+    }
+}
+
+/// Replaces reads of variables that hold a literal with that literal, then
+/// folds the statements they appear in.
+///
+/// A variable qualifies once the statement `name = <literal>` (after
+/// folding) has run in the same block, provided nothing else in the program
+/// ever writes `name`. Programs that mention `globals`, `locals` or `outer`
+/// are returned untouched, as a call could write any variable through them.
+/// Function bodies are propagated within themselves but never see the
+/// enclosing scope's constants.
+pub fn propagate_constants(program: &mut [Statement]) {
+    let mut counter = AssignmentCounter::default();
+    program.iter().for_each(|statement| counter.visit_statement(statement));
+    if counter.aliased {
+        return;
+    }
+    ConstantPropagator { counts: counter.counts, known: HashMap::new() }.block(program);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oversized_repetition_is_left_for_run_time() {
+        let expr: Expression = r#""x" * 1e20"#.parse().unwrap();
+        assert_eq!(fold_constants(expr.clone()), expr);
+    }
+
+    fn folded(source: &str) -> String {
+        fold_constants(source.parse().unwrap()).to_string()
+    }
+
+    #[test]
+    fn literal_subtrees_are_replaced_by_their_value() {
+        assert_eq!(folded("x + 2 * 3"), "x + 6");
+        assert_eq!(folded("\"a\" + 1 + y"), "\"a1\" + y");
+        assert_eq!(folded("if 1 < 2 then a else b"), "a");
+        assert_eq!(folded("not (1 == 1) or z"), "false or z");
+        assert_eq!(folded("f(function()\n  return 2 ^ 3\nend function)"), "f(function()\n  return 1\nend function)");
+        assert_eq!(folded("1 + true"), "1 + true");
+        assert_eq!(folded("x * (1 + 2)"), "x * 3");
+    }
+
+    #[test]
+    fn division_by_a_literal_zero_is_left_for_run_time() {
+        assert_eq!(folded("1 / 0"), "1 / 0");
+        assert_eq!(folded("5 % 0"), "5 % 0");
+        assert_eq!(folded("x + 0 / 0.0"), "x + 0 / 0");
+        assert_eq!(folded("1 / (2 - 2)"), "1 / 0");
+        assert_eq!(folded("1 / 2"), "0.5");
+        assert_eq!(folded("7 % 4"), "3");
+        let expr: Expression = "1 / 0".parse().unwrap();
+        assert!(matches!(fold_constants(expr), Division(..)));
+    }
+
+    fn propagated(source: &str) -> String {
+        let mut program = crate::parse_program(&crate::lex(source).unwrap()).unwrap();
+        propagate_constants(&mut program);
+        program.iter().map(|statement| format!("{}\n", statement)).collect()
+    }
+
+    #[test]
+    fn constants_are_propagated_into_later_statements() {
+        assert_eq!(propagated("x = 2\ny = x * 3"), "x = 2\ny = 6\n");
+        assert_eq!(propagated("x = 1 + 1\nprint(x * 3)"), "x = 2\nprint(6)\n");
+        assert_eq!(propagated("y = x * 3\nx = 2"), "y = x * 3\nx = 2\n");
+        assert_eq!(propagated("x = 2\nif c then\n  y = x\nend if"), "x = 2\nif c then\n  y = 2\nend if\n");
+    }
+
+    #[test]
+    fn reassigned_variables_are_not_propagated() {
+        assert_eq!(propagated("x = 2\nx = f()\ny = x * 3"), "x = 2\nx = f()\ny = x * 3\n");
+        assert_eq!(propagated("x = 2\nx = 5\ny = x * 3"), "x = 2\nx = 5\ny = x * 3\n");
+        assert_eq!(propagated("x = 2\nfor x in [1]\nend for\ny = x"), "x = 2\nfor x in [1]\nend for\ny = x\n");
+        assert_eq!(propagated("x = 2\nx ??= 3\ny = x"), "x = 2\nx ??= 3\ny = x\n");
+    }
+
+    #[test]
+    fn propagation_stops_at_scopes_it_cannot_follow() {
+        // Assigned only in a branch, which may not have run.
+        assert_eq!(propagated("if c then\n  x = 2\nend if\ny = x"), "if c then\n  x = 2\nend if\ny = x\n");
+        // Function bodies run later, in their own scope.
+        assert_eq!(propagated("x = 2\nf = function()\n  return x\nend function"), "x = 2\nf = function()\n  return x\nend function\n");
+        // A call could rewrite x through globals.
+        assert_eq!(propagated("x = 2\ng()\ny = x\nglobals.x = 3"), "x = 2\ng()\ny = x\nglobals.x = 3\n");
+        // Member names and addresses are not reads of the variable.
+        assert_eq!(propagated("x = 2\ny = m.x + @x"), "x = 2\ny = m.x + @x\n");
+    }
+
+    #[test]
+    fn negated_radix_literals_fold_to_negative_numbers() {
+        for (source, value) in [("-0xFF", -255.), ("-0o10", -8.), ("-0b101", -5.)] {
+            assert_eq!(fold_constants(source.parse().unwrap()), NumberValue(value), "{}", source);
+        }
+        let NumberValue(zero) = fold_constants("-0b0".parse().unwrap()) else { panic!("expected a number") };
+        assert_eq!(zero, 0.);
+        assert!(matches!(fold_constants("a - 0xFF".parse().unwrap()), Subtraction(..)));
+        assert_eq!(folded("a - 0xFF"), "a - 255");
+    }
+
+    #[test]
+    fn folded_negative_numbers_format_back_to_the_same_tree() {
+        let power = crate::ParseOptions { caret_is_power: true, ..crate::ParseOptions::default() };
+        for (source, formatted) in [("(0-2)^x", "(-2) ^ x"), ("(0-1).foo", "(-1).foo"), ("(0-1)[i]", "(-1)[i]"), ("a - (0-2)", "a - -2")] {
+            let parse = |source: &str| crate::Parser::with_options(&crate::lex(source).unwrap(), power.clone()).parse_expression().unwrap();
+            let expr = fold_constants(parse(source));
+            assert_eq!(expr.to_string(), formatted);
+            assert_eq!(fold_constants(parse(&expr.to_string())), expr, "{}", source);
+        }
+    }
+}
@@ -0,0 +1,344 @@
+//! Source formatting with options, for tools that print expressions back
+//! to users. `Display` on `Expression` renders the default options; this
+//! module lets the caller choose spacing and how many parentheses to write.
+//! Either way the output parses back to the same tree, so formatting is
+//! stable: formatting the parse of formatted source gives the same source
+//! again.
+
+use crate::parser::{is_keyword_name, precedence, write_block, write_name, Expression};
+use Expression::*;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Parentheses {
+    /// Only where the tree needs them to read back the same.
+    #[default]
+    Minimal,
+    /// Around every operand that is itself an operation, as in
+    /// `(a * b) + (-c)`.
+    Explicit,
+}
+
+#[derive(Debug, Clone)]
+pub struct FormatOptions {
+    /// Writes `a + b` rather than `a+b`. Word operators such as `and` and
+    /// `isa` are always spaced.
+    pub spaces_around_operators: bool,
+    pub parentheses: Parentheses,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self { spaces_around_operators: true, parentheses: Parentheses::Minimal }
+    }
+}
+
+/// Formats `expr` as MiniScript source. Bodies of function literals are
+/// written as `Display` writes statements, with the default options.
+pub fn format_expression(expr: &Expression, options: &FormatOptions) -> String {
+    let mut out = String::new();
+    Writer { options, out: &mut out }.expression(expr);
+    out
+}
+
+struct Writer<'a> {
+    options: &'a FormatOptions,
+    out: &'a mut String,
+}
+
+impl Writer<'_> {
+    /// Writes `expr`, parenthesized when it binds looser than
+    /// `min_precedence`, or with `Parentheses::Explicit` when it is an
+    /// operation at all.
+    fn operand(&mut self, expr: &Expression, min_precedence: u8) {
+        let explicit = self.options.parentheses == Parentheses::Explicit && expr.precedence() < precedence::POSTFIX;
+        if explicit || expr.precedence() < min_precedence {
+            self.parenthesized(expr);
+        } else {
+            self.expression(expr);
+        }
+    }
+
+    fn parenthesized(&mut self, expr: &Expression) {
+        self.out.push('(');
+        self.expression(expr);
+        self.out.push(')');
+    }
+
+    /// Writes a symbol operator, spaced as configured.
+    fn operator(&mut self, operator: &str) {
+        if self.options.spaces_around_operators {
+            self.out.push(' ');
+            self.out.push_str(operator);
+            self.out.push(' ');
+        } else {
+            self.out.push_str(operator);
+        }
+    }
+
+    /// Writes a left-associative binary operation.
+    fn binary(&mut self, left: &Expression, operator: &str, right: &Expression, precedence: u8) {
+        self.operand(left, precedence);
+        self.operator(operator);
+        self.operand(right, precedence + 1);
+    }
+
+    /// Writes a binary operation spelled with a keyword, which needs
+    /// spaces to lex apart from its operands.
+    fn word_binary(&mut self, left: &Expression, operator: &str, right: &Expression, precedence: u8) {
+        self.operand(left, precedence);
+        self.out.push_str(&format!(" {} ", operator));
+        self.operand(right, precedence + 1);
+    }
+
+    /// Writes comma-separated items, each delimited already by the brackets
+    /// around them.
+    fn items<'e>(&mut self, items: impl IntoIterator<Item = &'e Expression>) {
+        for (i, item) in items.into_iter().enumerate() {
+            if i > 0 {
+                self.out.push_str(", ");
+            }
+            self.expression(item);
+        }
+    }
+
+    fn expression(&mut self, expr: &Expression) {
+        match expr {
+            StringValue(s) => {
+                self.out.push('"');
+                for c in s.chars() {
+                    match c {
+                        '\n' => self.out.push_str("\\n"),
+                        '\t' => self.out.push_str("\\t"),
+                        '\r' => self.out.push_str("\\r"),
+                        '\\' | '"' => {
+                            self.out.push('\\');
+                            self.out.push(c);
+                        }
+                        c => self.out.push(c),
+                    }
+                }
+                self.out.push('"');
+            }
+            NumberValue(n) => self.out.push_str(&n.to_string()),
+            BoolValue(b) => self.out.push_str(&b.to_string()),
+            NullValue => self.out.push_str("null"),
+            Variable(name) => self.name(name),
+            MemberAccess(left, right) => {
+                self.operand(left, precedence::POSTFIX);
+                self.out.push('.');
+                match &**right {
+                    Variable(name) if is_keyword_name(name) => self.out.push_str(name),
+                    right => self.operand(right, precedence::ATOM),
+                }
+            }
+            Index(target, index) => {
+                self.operand(target, precedence::POSTFIX);
+                self.out.push('[');
+                self.expression(index);
+                self.out.push(']');
+            }
+            ListLiteral(items) => {
+                self.out.push('[');
+                self.items(items);
+                self.out.push(']');
+            }
+            MapLiteral(entries) => {
+                self.out.push('{');
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        self.out.push_str(", ");
+                    }
+                    self.expression(key);
+                    self.out.push_str(": ");
+                    self.expression(value);
+                }
+                self.out.push('}');
+            }
+            FunctionCall(callee, args) => {
+                self.operand(callee, precedence::POSTFIX);
+                self.out.push('(');
+                self.items(args);
+                self.out.push(')');
+            }
+            LogicalNot(operand) => {
+                self.out.push_str("not ");
+                self.operand(operand, precedence::UNARY);
+            }
+            UnaryNegation(operand) => {
+                self.out.push('-');
+                self.operand(operand, precedence::UNARY);
+            }
+            Typeof(operand) => {
+                self.out.push_str("typeof ");
+                self.operand(operand, precedence::UNARY);
+            }
+            New(operand) => {
+                self.out.push_str("new ");
+                self.operand(operand, precedence::POSTFIX);
+            }
+            AddressOf(operand) => {
+                self.out.push('@');
+                self.operand(operand, precedence::POSTFIX);
+            }
+            Range { start, end, step } => {
+                self.operand(start, precedence::ADDITIVE);
+                self.out.push_str(" to ");
+                self.operand(end, precedence::ADDITIVE);
+                if let Some(step) = step {
+                    self.out.push_str(" by ");
+                    self.operand(step, precedence::ADDITIVE);
+                }
+            }
+            Multiplication(left, right) => self.binary(left, "*", right, precedence::MULTIPLICATIVE),
+            Division(left, right) => self.binary(left, "/", right, precedence::MULTIPLICATIVE),
+            Remainder(left, right) => self.binary(left, "%", right, precedence::MULTIPLICATIVE),
+            // Right-associative, and the exponent may carry a sign: `2 ^ -x`.
+            Power(base, exponent) => {
+                self.operand(base, precedence::POSTFIX);
+                self.operator("^");
+                self.operand(exponent, precedence::UNARY);
+            }
+            BitwiseXor(left, right) => self.binary(left, "^", right, precedence::XOR),
+            Addition(left, right) => self.binary(left, "+", right, precedence::ADDITIVE),
+            Subtraction(left, right) => self.binary(left, "-", right, precedence::ADDITIVE),
+            LessThan(left, right) | LessThanEq(left, right) => {
+                // As in `Display`, `(a < b) < c` must not read back as a `Between`.
+                if matches!(left.as_ref(), LessThan(..) | LessThanEq(..)) {
+                    self.parenthesized(left);
+                } else {
+                    self.operand(left, precedence::RELATIONAL);
+                }
+                self.operator(if matches!(expr, LessThan(..)) { "<" } else { "<=" });
+                self.operand(right, precedence::RANGE);
+            }
+            GreaterThan(left, right) => self.binary(left, ">", right, precedence::RELATIONAL),
+            GreaterThanEq(left, right) => self.binary(left, ">=", right, precedence::RELATIONAL),
+            IsA(left, right) => self.word_binary(left, "isa", right, precedence::RELATIONAL),
+            Equality(left, right) => self.binary(left, "==", right, precedence::EQUALITY),
+            Inequality(left, right) => self.binary(left, "!=", right, precedence::EQUALITY),
+            LogicalAnd(left, right) => self.word_binary(left, "and", right, precedence::AND),
+            LogicalOr(left, right) => self.word_binary(left, "or", right, precedence::OR),
+            // Assignment is right-associative, so only the target may need parentheses.
+            Assignment(target, value) => {
+                self.operand(target, precedence::CONDITIONAL);
+                self.operator("=");
+                self.operand(value, precedence::ASSIGNMENT);
+            }
+            NullCoalescingAssignment(target, value) => {
+                self.operand(target, precedence::CONDITIONAL);
+                self.operator("??=");
+                self.operand(value, precedence::ASSIGNMENT);
+            }
+            Conditional(cond, then, otherwise) => {
+                self.out.push_str("if ");
+                self.operand(cond, precedence::ASSIGNMENT);
+                self.out.push_str(" then ");
+                self.operand(then, precedence::ASSIGNMENT);
+                self.out.push_str(" else ");
+                self.operand(otherwise, precedence::ASSIGNMENT);
+            }
+            Between { low, value, high, low_inclusive, high_inclusive } => {
+                self.operand(low, precedence::RELATIONAL);
+                self.operator(if *low_inclusive { "<=" } else { "<" });
+                self.operand(value, precedence::RANGE);
+                self.operator(if *high_inclusive { "<=" } else { "<" });
+                self.operand(high, precedence::RANGE);
+            }
+            Function { params, body } => {
+                self.out.push_str("function(");
+                for (i, (name, default)) in params.iter().enumerate() {
+                    if i > 0 {
+                        self.out.push_str(", ");
+                    }
+                    self.name(name);
+                    if let Some(default) = default {
+                        self.out.push('=');
+                        self.expression(default);
+                    }
+                }
+                self.out.push_str(")\n");
+                write_block(self.out, body).expect("writing to a String cannot fail");
+                self.out.push_str("end function");
+            }
+        }
+    }
+
+    fn name(&mut self, name: &str) {
+        write_name(self.out, name).expect("writing to a String cannot fail");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{ParseOptions, Parser};
+
+    #[test]
+    fn display_is_format_expression_with_default_options() {
+        for source in ["a.b[1](2, \"q\\\"\")", "-x ^ -y * (1 + 2)", "(a < b) < c", "x = function(a, b=[1])\n  return a\nend function"] {
+            let expr: Expression = source.parse().unwrap();
+            assert_eq!(expr.to_string(), format_expression(&expr, &FormatOptions::default()));
+        }
+    }
+
+    #[test]
+    fn formatting_is_idempotent_under_every_option() {
+        let sources = [
+            // Precedence and associativity.
+            "a - b - c",
+            "a - (b - c)",
+            "a / (b * c) % d",
+            "(a + b) * c ^ d",
+            "a ^ b ^ c",
+            "(a ^ b) ^ c",
+            "not a and b or c",
+            "(a or b) and not (c == d)",
+            "a == b != c",
+            "(a < b) < c",
+            "a isa b and c >= d",
+            "x = y = z + 1",
+            "m[k] ??= a or b",
+            "if a then b else c + 1",
+            "x = if a then (if b then c else d) else e",
+            // Unary and postfix chains.
+            "-a.b[c](d)",
+            "- -x",
+            "not not x",
+            "-(a + b) * -c",
+            "@f(1)",
+            "new m",
+            "typeof a.b",
+            "a.b.c[1][2](3)(4)",
+            "(a + b).c[d]",
+            "-x ^ -y",
+            "(-x)[0]",
+            // Ranges.
+            "1 to 5",
+            "a + 1 to b * 2 by -1",
+            "(a to b)[0]",
+            "(1 to 3) == [1, 2, 3]",
+            // Literals, including function literals.
+            "{\"k\": [1, 2.5, \"q\\\"\\n\"], 2: null, true: -0.5}",
+            "f = function(a, b=[1, {\"k\": 2}])\n  return a + b\nend function",
+            "g(function(x)\n  return x * 2\nend function, 3)",
+            "function()\n  if a then\n    return function()\n      return 1\n    end function\n  end if\nend function",
+        ];
+        let power = ParseOptions { caret_is_power: true, ..ParseOptions::default() };
+        for parse_options in [ParseOptions::default(), power] {
+            let parse = |source: &str| {
+                let tokens = crate::lex(source).unwrap_or_else(|e| panic!("{:?}: {}", source, e));
+                Parser::with_options(&tokens, parse_options.clone()).parse_expression().unwrap_or_else(|e| panic!("{:?}: {}", source, e))
+            };
+            for spaces_around_operators in [true, false] {
+                for parentheses in [Parentheses::Minimal, Parentheses::Explicit] {
+                    let options = FormatOptions { spaces_around_operators, parentheses };
+                    for source in sources {
+                        let once = format_expression(&parse(source), &options);
+                        let twice = format_expression(&parse(&once), &options);
+                        assert_eq!(twice, once, "{:?} with {:?}", source, options);
+                    }
+                }
+            }
+        }
+    }
+}
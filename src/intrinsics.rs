@@ -0,0 +1,209 @@
+//! Built-in functions that scripts can call without defining them. A name
+//! resolves to an intrinsic only when no variable of that name is in scope.
+//! Intrinsics whose first parameter is `self` are also methods: `s.len()`
+//! calls `len` with `s` as `self`.
+
+use crate::{
+    eval::{make_range, Environment, RuntimeError, Value},
+    parser::Expression,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Intrinsic {
+    Print,
+    Len,
+    Str,
+    Val,
+    Range,
+    IndexOf,
+    Random,
+    Randint,
+}
+
+impl Intrinsic {
+    pub fn from_name(name: &str) -> Option<Intrinsic> {
+        match name {
+            "print" => Some(Intrinsic::Print),
+            "len" => Some(Intrinsic::Len),
+            "str" => Some(Intrinsic::Str),
+            "val" => Some(Intrinsic::Val),
+            "range" => Some(Intrinsic::Range),
+            "indexOf" => Some(Intrinsic::IndexOf),
+            "random" => Some(Intrinsic::Random),
+            "randint" => Some(Intrinsic::Randint),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Intrinsic::Print => "print",
+            Intrinsic::Len => "len",
+            Intrinsic::Str => "str",
+            Intrinsic::Val => "val",
+            Intrinsic::Range => "range",
+            Intrinsic::IndexOf => "indexOf",
+            Intrinsic::Random => "random",
+            Intrinsic::Randint => "randint",
+        }
+    }
+
+    /// The parameters as MiniScript declares them, with their defaults.
+    pub(crate) fn params(self) -> Vec<(String, Option<Expression>)> {
+        let params: &[(&str, Option<Expression>)] = match self {
+            Intrinsic::Print => &[("s", Some(Expression::StringValue(String::new())))],
+            Intrinsic::Len | Intrinsic::Str => &[("self", None)],
+            Intrinsic::Val => &[("self", Some(Expression::NumberValue(0.)))],
+            Intrinsic::Range => &[
+                ("from", Some(Expression::NumberValue(0.))),
+                ("to", Some(Expression::NumberValue(0.))),
+                ("step", Some(Expression::NullValue)),
+            ],
+            Intrinsic::IndexOf => &[("self", None), ("x", None)],
+            Intrinsic::Random => &[],
+            Intrinsic::Randint => &[("a", None), ("b", None)],
+        };
+        params.iter().map(|(name, default)| (name.to_string(), default.clone())).collect()
+    }
+
+    /// Whether the intrinsic takes `self` first, so that it can be looked up
+    /// as a member of the value to pass as `self`.
+    pub(crate) fn is_method(self) -> bool {
+        matches!(self, Intrinsic::Len | Intrinsic::Str | Intrinsic::Val | Intrinsic::IndexOf)
+    }
+
+    /// Whether arguments past the declared parameters are passed along too,
+    /// as `print` takes any number of values to write on one line.
+    pub(crate) fn is_variadic(self) -> bool {
+        matches!(self, Intrinsic::Print)
+    }
+
+    /// Runs the intrinsic with one argument per parameter, defaults filled in and
+    /// any extra arguments of a variadic one after them.
+    pub(crate) fn call(self, args: Vec<Value>, env: &mut Environment) -> Result<Value, RuntimeError> {
+        let mut args = args.into_iter();
+        let mut arg = || args.next().unwrap_or(Value::Null);
+        match self {
+            // Values are written on one line, separated as the environment's
+            // output options say.
+            Intrinsic::Print => {
+                env.print(&args.map(|value| value.to_mini_string()).collect::<Vec<_>>());
+                Ok(Value::Null)
+            }
+            Intrinsic::Len => match arg() {
+                Value::Str(s) => Ok(Value::Number(s.chars().count() as f64)),
+                Value::List(items) => Ok(Value::Number(items.borrow().len() as f64)),
+                Value::Map(entries) => Ok(Value::Number(entries.borrow().len() as f64)),
+                other => Err(RuntimeError::unary("len", &other)),
+            },
+            Intrinsic::Str => Ok(Value::Str(arg().to_mini_string())),
+            // Text that is not a number reads as 0, as in MiniScript.
+            Intrinsic::Val => match arg() {
+                Value::Number(n) => Ok(Value::Number(n)),
+                Value::Str(s) => Ok(Value::Number(s.trim().parse().unwrap_or(0.))),
+                other => Err(RuntimeError::unary("val", &other)),
+            },
+            Intrinsic::Range => {
+                let (from, to) = (arg(), arg());
+                let step = match arg() {
+                    Value::Null => None,
+                    step => Some(step),
+                };
+                make_range(from, to, step)
+            }
+            // The first list index, character index or map key holding `x`,
+            // or null if there is none.
+            Intrinsic::IndexOf => {
+                let (container, x) = (arg(), arg());
+                Ok(match container {
+                    Value::List(items) => {
+                        items.borrow().iter().position(|item| *item == x).map_or(Value::Null, |i| Value::Number(i as f64))
+                    }
+                    Value::Str(s) => s
+                        .find(&x.to_mini_string())
+                        .map_or(Value::Null, |byte| Value::Number(s[..byte].chars().count() as f64)),
+                    Value::Map(entries) => {
+                        entries.borrow().iter().find(|(_, value)| **value == x).map_or(Value::Null, |(key, _)| key.clone().into_value())
+                    }
+                    other => return Err(RuntimeError::unary("indexOf", &other)),
+                })
+            }
+            Intrinsic::Random => Ok(Value::Number(env.random())),
+            // Fractional bounds are truncated, so `randint(1, 6.5)` rolls 1 to 6.
+            Intrinsic::Randint => match (arg(), arg()) {
+                (Value::Number(a), Value::Number(b)) => Ok(Value::Number(env.randint(a as i64, b as i64) as f64)),
+                (a, b) => Err(RuntimeError::binary("randint", &a, &b)),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval::run_program;
+
+    fn run_seeded(source: &str, seed: u64) -> Value {
+        let mut env = Environment::new();
+        env.seed_rng(seed);
+        run_program(source, &mut env).unwrap().0
+    }
+
+    #[test]
+    fn random_and_randint_draw_from_the_environment_rng() {
+        let mut env = Environment::new();
+        env.seed_rng(7);
+        let expected = (env.random(), env.randint(1, 6));
+        assert_eq!(run_seeded("[random(), randint(1, 6)]", 7), Value::list(vec![Value::Number(expected.0), Value::Number(expected.1 as f64)]));
+        for seed in 0..50 {
+            let Value::Number(n) = run_seeded("randint(3, 5)", seed) else { panic!("randint returned a non-number") };
+            assert!((3. ..=5.).contains(&n));
+        }
+        assert!(run_program("randint(\"a\", 2)", &mut Environment::new()).is_err());
+    }
+
+    fn run(source: &str) -> Result<Value, crate::eval::InterpretError> {
+        run_program(source, &mut Environment::new()).map(|(value, _)| value)
+    }
+
+    #[test]
+    fn print_writes_each_line_to_the_sink() {
+        let lines = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let mut env = Environment::new();
+        let sink = std::rc::Rc::clone(&lines);
+        env.set_print_sink(move |line| sink.borrow_mut().push(line.to_string()));
+        let (value, _) = run_program("print(1/2)\nprint(\"a\" + 1)\nprint(null)\nprint()", &mut env).unwrap();
+        assert_eq!(value, Value::Null);
+        assert_eq!(*lines.borrow(), ["0.5", "a1", "", ""]);
+    }
+
+    #[test]
+    fn len_str_and_val_convert_and_measure() {
+        assert_eq!(run("[len(\"h\u{e9}\"), len([1, 2, 3]), len({\"a\": 1}), len(\"\")]").unwrap().repr(), "[2, 3, 1, 0]");
+        assert!(run("len(5)").is_err());
+        assert_eq!(run("str(1/3)").unwrap(), Value::Str("0.333333".to_string()));
+        assert_eq!(run("str(null)").unwrap(), Value::Str(String::new()));
+        assert_eq!(run("[val(\"  2.5 \"), val(\"x\"), val(7)]").unwrap().repr(), "[2.5, 0, 7]");
+        assert!(run("val([])").is_err());
+    }
+
+    #[test]
+    fn range_counts_in_either_direction() {
+        assert_eq!(run("range(1, 3)").unwrap().repr(), "[1, 2, 3]");
+        assert_eq!(run("range(3)").unwrap().repr(), "[3, 2, 1, 0]");
+        assert_eq!(run("range(0, 10, 5)").unwrap().repr(), "[0, 5, 10]");
+        assert!(run("range(1, 2, 0)").is_err());
+    }
+
+    #[test]
+    fn index_of_finds_list_items_substrings_and_map_keys() {
+        assert_eq!(run("[indexOf([5, 6, 6], 6), indexOf([5], 9), indexOf(\"h\u{e9}llo\", \"l\"), indexOf({\"k\": 2}, 2)]").unwrap().repr(), "[1, null, 2, \"k\"]");
+    }
+
+    #[test]
+    fn variables_shadow_intrinsics() {
+        assert_eq!(run("len = function(x)\n  return 9\nend function\nlen(\"ab\")").unwrap(), Value::Number(9.));
+        assert_eq!(Intrinsic::from_name("indexOf").map(Intrinsic::name), Some("indexOf"));
+        assert_eq!(Intrinsic::from_name("nope"), None);
+    }
+}
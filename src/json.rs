@@ -0,0 +1,314 @@
+//! Conversion between values and JSON text for exchanging data with hosts.
+
+use crate::eval::{container_id, MiniKey, RuntimeError, Value};
+use std::{collections::BTreeMap, iter::Peekable, str::Chars};
+
+impl Value {
+    /// Renders the value as compact JSON. Map keys come out sorted, and
+    /// number and null keys as strings, since JSON keys are strings. JSON has
+    /// no spelling for NaN, the infinities, functions or a list or map that
+    /// contains itself, so those fail.
+    pub fn to_json(&self) -> Result<String, RuntimeError> {
+        let mut out = String::new();
+        write_json(self, &mut out, &mut Vec::new())?;
+        Ok(out)
+    }
+
+    /// Reads a JSON document. Integers and floats both become numbers.
+    /// Arrays and objects may nest at most `MAX_DEPTH` deep.
+    pub fn from_json(text: &str) -> Result<Value, RuntimeError> {
+        let mut chars = text.chars().peekable();
+        let value = parse_value(&mut chars, 0)?;
+        skip_whitespace(&mut chars);
+        match chars.next() {
+            None => Ok(value),
+            Some(c) => Err(invalid(format!("unexpected '{}' after the value", c))),
+        }
+    }
+}
+
+/// How deeply `Value::from_json` lets arrays and objects nest.
+pub const MAX_DEPTH: usize = 128;
+
+/// `enclosing` holds the lists and maps being written around `value`.
+fn write_json(value: &Value, out: &mut String, enclosing: &mut Vec<usize>) -> Result<(), RuntimeError> {
+    let id = container_id(value);
+    if let Some(id) = id {
+        if enclosing.contains(&id) {
+            return Err(RuntimeError::NotJsonSerializable("a list or map inside itself"));
+        }
+        enclosing.push(id);
+    }
+    match value {
+        Value::Number(n) if n.is_nan() => return Err(RuntimeError::NotJsonSerializable("NaN")),
+        Value::Number(n) if n.is_infinite() => return Err(RuntimeError::NotJsonSerializable("infinity")),
+        Value::Number(n) => out.push_str(&n.to_string()),
+        Value::Function(_) => return Err(RuntimeError::NotJsonSerializable("function")),
+        Value::Null => out.push_str("null"),
+        Value::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+        Value::Str(s) => write_json_string(s, out),
+        Value::List(items) => {
+            out.push('[');
+            for (i, item) in items.borrow().iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_json(item, out, enclosing)?;
+            }
+            out.push(']');
+        }
+        Value::Map(entries) => {
+            out.push('{');
+            for (i, (key, value)) in entries.borrow().iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_json_string(&key.to_string(), out);
+                out.push(':');
+                write_json(value, out, enclosing)?;
+            }
+            out.push('}');
+        }
+    }
+    if id.is_some() {
+        enclosing.pop();
+    }
+    Ok(())
+}
+
+fn write_json_string(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn invalid(message: String) -> RuntimeError {
+    RuntimeError::InvalidJson(message)
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while chars.next_if(|c| matches!(c, ' ' | '\t' | '\n' | '\r')).is_some() {}
+}
+
+fn expect(chars: &mut Peekable<Chars>, expected: char) -> Result<(), RuntimeError> {
+    skip_whitespace(chars);
+    match chars.next() {
+        Some(c) if c == expected => Ok(()),
+        Some(c) => Err(invalid(format!("expected '{}' but found '{}'", expected, c))),
+        None => Err(invalid(format!("expected '{}' but found end of input", expected))),
+    }
+}
+
+/// Parses the value at `chars`, which sits inside `depth` arrays and objects.
+fn parse_value(chars: &mut Peekable<Chars>, depth: usize) -> Result<Value, RuntimeError> {
+    skip_whitespace(chars);
+    if matches!(chars.peek(), Some('{' | '[')) && depth >= MAX_DEPTH {
+        return Err(invalid(format!("nesting exceeds the limit of {}", MAX_DEPTH)));
+    }
+    match chars.peek().copied() {
+        Some('{') => parse_object(chars, depth + 1),
+        Some('[') => parse_array(chars, depth + 1),
+        Some('"') => parse_string(chars).map(Value::Str),
+        Some('-' | '0'..='9') => parse_number(chars),
+        Some('t' | 'f' | 'n') => {
+            let mut word = String::new();
+            while let Some(c) = chars.next_if(char::is_ascii_alphabetic) {
+                word.push(c);
+            }
+            match word.as_str() {
+                "true" => Ok(Value::Bool(true)),
+                "false" => Ok(Value::Bool(false)),
+                "null" => Ok(Value::Null),
+                _ => Err(invalid(format!("unknown literal '{}'", word))),
+            }
+        }
+        Some(c) => Err(invalid(format!("unexpected '{}'", c))),
+        None => Err(invalid("unexpected end of input".to_string())),
+    }
+}
+
+fn parse_object(chars: &mut Peekable<Chars>, depth: usize) -> Result<Value, RuntimeError> {
+    expect(chars, '{')?;
+    let mut entries = BTreeMap::new();
+    skip_whitespace(chars);
+    if chars.next_if_eq(&'}').is_some() {
+        return Ok(Value::map(entries));
+    }
+    loop {
+        skip_whitespace(chars);
+        if chars.peek() != Some(&'"') {
+            return Err(invalid("expected a string key".to_string()));
+        }
+        let key = parse_string(chars)?;
+        expect(chars, ':')?;
+        entries.insert(MiniKey::Str(key), parse_value(chars, depth)?);
+        skip_whitespace(chars);
+        match chars.next() {
+            Some(',') => (),
+            Some('}') => return Ok(Value::map(entries)),
+            _ => return Err(invalid("expected ',' or '}' in object".to_string())),
+        }
+    }
+}
+
+fn parse_array(chars: &mut Peekable<Chars>, depth: usize) -> Result<Value, RuntimeError> {
+    expect(chars, '[')?;
+    let mut items = Vec::new();
+    skip_whitespace(chars);
+    if chars.next_if_eq(&']').is_some() {
+        return Ok(Value::list(items));
+    }
+    loop {
+        items.push(parse_value(chars, depth)?);
+        skip_whitespace(chars);
+        match chars.next() {
+            Some(',') => (),
+            Some(']') => return Ok(Value::list(items)),
+            _ => return Err(invalid("expected ',' or ']' in array".to_string())),
+        }
+    }
+}
+
+fn parse_hex4(chars: &mut Peekable<Chars>) -> Result<u32, RuntimeError> {
+    let digits: String = (0..4).filter_map(|_| chars.next()).collect();
+    u32::from_str_radix(&digits, 16)
+        .ok()
+        .filter(|_| digits.len() == 4)
+        .ok_or_else(|| invalid(format!("invalid unicode escape '\\u{}'", digits)))
+}
+
+fn parse_string(chars: &mut Peekable<Chars>) -> Result<String, RuntimeError> {
+    chars.next();
+    let mut out = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(out),
+            Some('\\') => match chars.next() {
+                Some('"') => out.push('"'),
+                Some('\\') => out.push('\\'),
+                Some('/') => out.push('/'),
+                Some('b') => out.push('\u{8}'),
+                Some('f') => out.push('\u{c}'),
+                Some('n') => out.push('\n'),
+                Some('r') => out.push('\r'),
+                Some('t') => out.push('\t'),
+                Some('u') => {
+                    let mut code = parse_hex4(chars)?;
+                    // A high surrogate must be followed by an escaped low one.
+                    if (0xD800..0xDC00).contains(&code) {
+                        if chars.next() != Some('\\') || chars.next() != Some('u') {
+                            return Err(invalid("unpaired surrogate in string".to_string()));
+                        }
+                        let low = parse_hex4(chars)?;
+                        if !(0xDC00..0xE000).contains(&low) {
+                            return Err(invalid("unpaired surrogate in string".to_string()));
+                        }
+                        code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+                    }
+                    out.push(char::from_u32(code).ok_or_else(|| invalid("unpaired surrogate in string".to_string()))?);
+                }
+                _ => return Err(invalid("invalid escape in string".to_string())),
+            },
+            Some(c) if (c as u32) < 0x20 => return Err(invalid("control character in string".to_string())),
+            Some(c) => out.push(c),
+            None => return Err(invalid("unterminated string".to_string())),
+        }
+    }
+}
+
+/// Appends the digits at `chars` to `text`, failing unless there is at
+/// least one.
+fn read_digits(chars: &mut Peekable<Chars>, text: &mut String) -> Result<(), RuntimeError> {
+    let start = text.len();
+    while let Some(c) = chars.next_if(char::is_ascii_digit) {
+        text.push(c);
+    }
+    if text.len() == start {
+        return Err(invalid(format!("invalid number '{}'", text)));
+    }
+    Ok(())
+}
+
+/// Parses a number as the JSON grammar spells it: no leading zeros, no
+/// leading `+`, and digits on both sides of a decimal point.
+fn parse_number(chars: &mut Peekable<Chars>) -> Result<Value, RuntimeError> {
+    let mut text = String::new();
+    if let Some(c) = chars.next_if_eq(&'-') {
+        text.push(c);
+    }
+    match chars.next_if_eq(&'0') {
+        Some(zero) => text.push(zero),
+        None => read_digits(chars, &mut text)?,
+    }
+    if let Some(c) = chars.next_if(char::is_ascii_digit) {
+        text.push(c);
+        return Err(invalid(format!("invalid number '{}'", text)));
+    }
+    if let Some(point) = chars.next_if_eq(&'.') {
+        text.push(point);
+        read_digits(chars, &mut text)?;
+    }
+    if let Some(e) = chars.next_if(|c| matches!(c, 'e' | 'E')) {
+        text.push(e);
+        if let Some(sign) = chars.next_if(|c| matches!(c, '+' | '-')) {
+            text.push(sign);
+        }
+        read_digits(chars, &mut text)?;
+    }
+    text.parse().map(Value::Number).map_err(|_| invalid(format!("invalid number '{}'", text)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval::{run_program, Environment};
+
+    fn eval(source: &str) -> Value {
+        run_program(source, &mut Environment::new()).unwrap().0
+    }
+
+    #[test]
+    fn nested_values_round_trip() {
+        let value = eval(r#"{"name": "a\"b\n", "items": [1, 2.5, -0.125, 1e300, true, null, [], {}], "nested": {"k": [{"x": "é"}]}}"#);
+        let json = value.to_json().unwrap();
+        assert_eq!(Value::from_json(&json).unwrap(), value);
+        assert_eq!(eval("[1, \"two\"]").to_json().unwrap(), r#"[1,"two"]"#);
+        assert_eq!(eval("a = [1]\n[a, a]").to_json().unwrap(), "[[1],[1]]");
+    }
+
+    #[test]
+    fn functions_nan_infinities_and_cycles_do_not_serialize() {
+        for source in ["function()\nend function", "[0 / 0]", "{\"k\": 1 / 0}", "-1 / 0", "a = [1]\na[0] = a\na"] {
+            assert!(matches!(eval(source).to_json(), Err(RuntimeError::NotJsonSerializable(_))), "{}", source);
+        }
+    }
+
+    #[test]
+    fn numbers_follow_the_json_grammar() {
+        for text in ["0", "-0", "12", "1.5", "-0.25", "1e5", "1E+5", "2.5e-3"] {
+            assert!(Value::from_json(text).is_ok(), "{}", text);
+        }
+        for text in ["01", "-01", "1.", ".5", "+1", "1e", "1e+", "-", "1.e5", "0x10"] {
+            assert!(matches!(Value::from_json(text), Err(RuntimeError::InvalidJson(_))), "{}", text);
+        }
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let nested = |depth: usize| "[".repeat(depth) + &"]".repeat(depth);
+        assert!(Value::from_json(&nested(MAX_DEPTH)).is_ok());
+        assert!(matches!(Value::from_json(&nested(MAX_DEPTH + 1)), Err(RuntimeError::InvalidJson(_))));
+        assert!(Value::from_json(&"{\"k\":".repeat(100_000)).is_err());
+        assert!(Value::from_json(&"[".repeat(100_000)).is_err());
+    }
+}
@@ -1,288 +1,1057 @@
-use TokenKind::*;
-use std::{str::FromStr, collections::VecDeque, num::ParseFloatError, iter::Peekable};
-
-#[derive(Debug, Clone, PartialEq)]
-pub struct NumLiteralData {
-    pub value: f64,
-    str_len: usize
-}
-
-#[derive(Debug, Clone, PartialEq)]
-pub enum TokenKind {
-    Identifier(String),
-
-    // Literals
-    StrLiteral(String),
-    NumLiteral(NumLiteralData),
-
-    // keywords
-    TypeofKeyword,
-
-    // Operators and Symbols
-    SingleEqual,
-    SemiColon,
-    Dot,
-    Comma,
-    DoubleEqual,
-    ExclEqual,
-    LessThan,
-    LessThanEq,
-    GreaterThan,
-    GreaterThanEq,
-    Plus,
-    Minus,
-    Asterisk,
-    Slash,
-    Percent,
-    LeftParen,
-    RightParen,
-    LeftCurly,
-    RightCurly,
-    LeftBracket,
-    RightBracket,
-    Exclamation,
-    DoubleAnd,
-    DoublePipe
-}
-
-impl TokenKind {
-    #[inline(always)]
-    fn new_num_literal(value: f64, str_len: usize) -> TokenKind {
-        NumLiteral(NumLiteralData { value, str_len })
-    }
-    fn try_into_float(value: &String) -> Result<TokenKind, ParseFloatError> {
-        <f64 as FromStr>::from_str(value)
-        .map(|n| Self::new_num_literal(n, value.len()))
-    }
-    pub fn get_str_len(&self) -> usize {
-        match self {
-            StrLiteral(s) => s.len(),
-            Identifier(id) => id.len(),
-            NumLiteral(num) => num.str_len,
-            SingleEqual | SemiColon | LessThan | GreaterThan | Plus | Minus |
-            Asterisk | Slash | Percent | LeftParen | RightParen | LeftCurly |
-            Dot | RightCurly | LeftBracket | RightBracket | Exclamation | Comma => 1,
-            DoubleEqual | ExclEqual | LessThanEq | GreaterThanEq | DoubleAnd | DoublePipe => 2,
-            TypeofKeyword => 6
-        }
-    }
-}
-
-#[derive(Debug, PartialEq)]
-pub struct Token {
-    pub kind: TokenKind,
-    pub line: usize,
-    pub column: usize
-}
-
-#[inline(always)]
-fn read_numchars<I: Iterator<Item = char>>(chars: &mut Peekable<I>, out: &mut String) {
-    while let Some(c) = chars.next_if(|c| c.is_ascii_digit()) {
-        out.push(c);
-    }
-}
-
-#[inline(always)]
-fn parse_int_with_prefix<I>(chars: &mut Peekable<I>, mut len_init: usize, radix: u32) -> TokenKind
-    where I: Iterator<Item = char> + Clone {
-    if let Some('0'..='9') = chars.clone().nth(1) {} else {
-        return TokenKind::new_num_literal(0., 1)
-    }
-    chars.next();
-    let mut val: f64 = 0.0;
-    while let Some(c) = chars.next_if(|c| c.is_digit(radix)) {
-        val *= radix as f64;
-        val += u8::from_str_radix(&c.to_string(), radix).unwrap() as f64;
-        len_init += 1;
-    }
-    TokenKind::new_num_literal(val, len_init)
-}
-
-#[inline(always)]
-fn parse_number_starting_with_0<I>(chars: &mut Peekable<I>) -> Result<TokenKind, ParseFloatError>
-    where I: Iterator<Item = char> + Clone {
-    Ok(match chars.peek() {
-        Some('x') => parse_int_with_prefix(chars, 2, 16),
-        Some('o') => parse_int_with_prefix(chars, 2, 8),
-        Some('b') => parse_int_with_prefix(chars, 2, 2),
-        Some('.') => {
-            let mut text = '0'.to_string();
-            if let Some('0'..='9') = chars.clone().nth(1) {
-                chars.next();
-                text.push('.');
-                read_numchars(chars, &mut text);
-                if chars.peek() == Some(&'e') {
-                    if let Some('1'..='9') = chars.clone().nth(1) {
-                        chars.next();
-                        text.push('e');
-                        read_numchars(chars, &mut text);
-                    }
-                }
-            }
-            TokenKind::try_into_float(&text)?
-        },
-        _ => TokenKind::new_num_literal(0., 1)
-    })
-}
-
-#[derive(Debug)]
-pub struct ParseState {
-    tokens: VecDeque<Token>,
-    pub line: usize,
-    pub column: usize
-}
-
-impl ParseState {
-    fn new() -> Self {
-        Self {
-            tokens: VecDeque::new(),
-            line: 1,
-            column: 1
-        }
-    }
-    fn push_token(&mut self, kind: TokenKind) {
-        let len = kind.get_str_len();
-        self.tokens.push_back(Token {
-            kind,
-            line: self.line,
-            column: self.column
-        });
-        self.column += len;
-    }
-}
-
-#[derive(Debug)]
-pub enum LexerErrorKind {
-    InvalidFloatLiteral,
-    InvalidStringEscapeSequence,
-    UnterminatedStringLiteral,
-    InvalidCharacter(char)
-}
-
-#[derive(Debug)]
-pub struct LexerError {
-    pub state: ParseState,
-    pub kind: LexerErrorKind
-}
-
-impl LexerError {    
-    fn new(state: ParseState, kind: LexerErrorKind) -> Self {
-        Self { state, kind }
-    }
-}
-
-pub fn parse(input: &str) -> Result<VecDeque<Token>, LexerError> {
-    let mut state = ParseState::new();
-    let mut input = input.chars().peekable();
-    while let Some(c) = input.next() {
-        match c {
-            '0' => state.push_token(
-                match parse_number_starting_with_0(&mut input) {
-                    Ok(t) => t,
-                    _ => return Err(LexerError::new(state, LexerErrorKind::InvalidFloatLiteral))
-                }
-            ),
-            '1'..='9' => {
-                let mut text = c.to_string();
-                read_numchars(&mut input, &mut text);
-                if input.peek() == Some(&'.') {
-                    if let Some('0'..='9') = input.clone().nth(1) {
-                        input.next();
-                        text.push('.');
-                        read_numchars(&mut input, &mut text);
-                    }
-                }
-                if input.peek() == Some(&'e') {
-                    if let Some('1'..='9') = input.clone().nth(1) {
-                        input.next();
-                        text.push('e');
-                        read_numchars(&mut input, &mut text);
-                    }
-                }
-                state.push_token(TokenKind::try_into_float(&text).expect("Invalid float literal"));
-            },
-            '"' => {
-                let mut text = '"'.to_string();
-                while let Some(c) = input.next() {
-                    match c {
-                        '\\' => match input.peek() {
-                            Some('n' | 't' | 'r' | '\\' | '"') => {
-                                text.push('\\');
-                                text.push(input.next().unwrap());
-                            },
-                            _ => return Err(LexerError::new(state, LexerErrorKind::InvalidStringEscapeSequence))
-                        },
-                        '\n' => return Err(LexerError::new(state, LexerErrorKind::UnterminatedStringLiteral)),
-                        '"' => break,
-                        _ => text.push(c)
-                    }
-                }
-                text.push('"');
-                state.push_token(StrLiteral(text));
-            },
-            'a'..='z' | 'A'..='Z' | '_' => {
-                let mut text = c.to_string();
-                while let Some(c) = input.next_if(|c| match c {
-                    'a'..='z' | 'A'..='Z' | '_' | '0'..='9' => true,
-                    _ => false
-                }) {
-                    text.push(c);
-                }
-                state.push_token(match text.as_str() {
-                    "typeof" => TypeofKeyword,
-                    _ => Identifier(text)
-                });
-            },
-            '=' => state.push_token(match input.next_if_eq(&'=') {
-                None => SingleEqual,
-                _ => DoubleEqual
-            }),
-            '!' => state.push_token(match input.next_if_eq(&'=') {
-                None => Exclamation,
-                _ => ExclEqual
-            }),
-            '<' => state.push_token(match input.next_if_eq(&'=') {
-                None => LessThan,
-                _ => LessThanEq,
-            }),
-            '>' => state.push_token(match input.next_if_eq(&'=') {
-                None => GreaterThan,
-                _ => GreaterThanEq
-            }),
-            '\n' => {
-                state.line += 1;
-                state.column = 1;
-            },
-            '+' => state.push_token(Plus),
-            '-' => state.push_token(Minus),
-            '*' => state.push_token(Asterisk),
-            '/' => state.push_token(Slash),
-            '%' => state.push_token(Percent),
-            ';' => state.push_token(SemiColon),
-            '.' => state.push_token(Dot),
-            ',' => state.push_token(Comma),
-            '(' => state.push_token(LeftParen),
-            ')' => state.push_token(RightParen),
-            '{' => state.push_token(LeftCurly),
-            '}' => state.push_token(RightCurly),
-            '[' => state.push_token(LeftBracket),
-            ']' => state.push_token(RightBracket),
-            ' ' | '\t' => state.column += 1,
-            '\r' => (),
-            '&' => if let Some(_) = input.next_if_eq(&'&') {
-                    input.next();
-                    state.push_token(DoublePipe);
-                } else {
-                    return Err(LexerError::new(state, LexerErrorKind::InvalidCharacter(c)))
-                },
-            '|' => if let Some(_) = input.next_if_eq(&'|') {
-                    input.next();
-                    state.push_token(DoubleAnd);
-                } else {
-                    return Err(LexerError::new(state, LexerErrorKind::InvalidCharacter(c)))
-                },
-            _ => return Err(LexerError::new(state, LexerErrorKind::InvalidCharacter(c)))
-        }
-    }
-    Ok(state.tokens)
-}
+use TokenKind::*;
+use std::{str::FromStr, collections::VecDeque, num::ParseFloatError, fmt};
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NumLiteralData {
+    pub value: f64,
+    /// The literal as written in the source, kept only with
+    /// `LexerOptions::keep_number_text`.
+    pub raw: Option<String>,
+    /// Whether the literal was written without a fraction or exponent, as in
+    /// `5` or `0xFF` but not `5.0` or `1e3`, so a printer can keep the form.
+    pub is_integer: bool,
+    /// 16, 8 or 2 for `0x`, `0o` and `0b` literals, otherwise 10.
+    pub radix: u32,
+    str_len: usize
+}
+
+impl NumLiteralData {
+    /// Spells the literal for source output: the original text when it was
+    /// kept, otherwise the value in the literal's radix, with a `.0` if it
+    /// was not written as an integer.
+    pub fn to_source(&self) -> String {
+        match &self.raw {
+            Some(raw) => raw.clone(),
+            None if self.radix != 10 && self.value >= 0. && self.value <= u64::MAX as f64 => match self.radix {
+                16 => format!("0x{:X}", self.value as u64),
+                8 => format!("0o{:o}", self.value as u64),
+                _ => format!("0b{:b}", self.value as u64)
+            },
+            None if !self.is_integer && self.value.is_finite() && self.value.fract() == 0. => format!("{:.1}", self.value),
+            None => self.value.to_string()
+        }
+    }
+
+    /// How many source characters the literal spans, including a prefix
+    /// such as `0x` and any suffix read by `LexerOptions::on_number`: 4 for
+    /// `3.14`. This is the token's width as `TokenKind::get_str_len` reports it.
+    pub fn str_len(&self) -> usize {
+        self.str_len
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TokenKind {
+    Identifier(String),
+
+    // Literals
+    StrLiteral(String),
+    NumLiteral(NumLiteralData),
+
+    // keywords
+    TypeofKeyword,
+    IfKeyword,
+    ThenKeyword,
+    ElseKeyword,
+    EndKeyword,
+    WhileKeyword,
+    ForKeyword,
+    InKeyword,
+    FunctionKeyword,
+    ReturnKeyword,
+    BreakKeyword,
+    ContinueKeyword,
+    DeferKeyword,
+    ToKeyword,
+    ByKeyword,
+    IsaKeyword,
+    NewKeyword,
+    NullKeyword,
+    TrueKeyword,
+    FalseKeyword,
+    AndKeyword,
+    OrKeyword,
+    NotKeyword,
+
+    // Operators and Symbols
+    Newline,
+    SingleEqual,
+    SemiColon,
+    Colon,
+    Dot,
+    Comma,
+    DoubleEqual,
+    ExclEqual,
+    LessThan,
+    LessThanEq,
+    GreaterThan,
+    GreaterThanEq,
+    Plus,
+    Minus,
+    Asterisk,
+    Slash,
+    Percent,
+    Caret,
+    LeftParen,
+    RightParen,
+    LeftCurly,
+    RightCurly,
+    LeftBracket,
+    RightBracket,
+    Exclamation,
+    At,
+    DoubleAnd,
+    DoublePipe,
+    DoubleQuestionEqual,
+
+    // trivia, only with `LexerOptions::keep_trivia`
+    /// A `//` comment, without the slashes.
+    Comment(String),
+    /// A run of spaces and tabs.
+    Whitespace(String)
+}
+
+impl TokenKind {
+    #[inline(always)]
+    fn new_num_literal(value: f64, str_len: usize, is_integer: bool) -> TokenKind {
+        NumLiteral(NumLiteralData { value, raw: None, is_integer, radix: 10, str_len })
+    }
+    fn try_into_float(value: &str) -> Result<TokenKind, ParseFloatError> {
+        <f64 as FromStr>::from_str(value)
+        .map(|n| Self::new_num_literal(n, value.len(), !value.contains(['.', 'e'])))
+    }
+    /// Whether this is one of the reserved words, which the parser still
+    /// accepts as a member name after `.`.
+    pub fn is_keyword(&self) -> bool {
+        matches!(
+            self,
+            TypeofKeyword | IfKeyword | ThenKeyword | ElseKeyword | EndKeyword | WhileKeyword | ForKeyword |
+            InKeyword | FunctionKeyword | ReturnKeyword | BreakKeyword | ContinueKeyword | DeferKeyword |
+            ToKeyword | ByKeyword | IsaKeyword | NewKeyword | NullKeyword | TrueKeyword | FalseKeyword |
+            AndKeyword | OrKeyword | NotKeyword
+        )
+    }
+    /// How many columns, that is characters, the token takes up in source.
+    pub fn get_str_len(&self) -> usize {
+        match self {
+            StrLiteral(s) => s.chars().count(),
+            Identifier(id) => id.chars().count(),
+            Comment(text) => text.chars().count() + 2,
+            Whitespace(run) => run.chars().count(),
+            NumLiteral(num) => num.str_len,
+            Newline | SingleEqual | SemiColon | Colon | LessThan | GreaterThan | Plus | Minus |
+            Asterisk | Slash | Percent | Caret | LeftParen | RightParen | LeftCurly |
+            Dot | RightCurly | LeftBracket | RightBracket | Exclamation | At | Comma => 1,
+            DoubleEqual | ExclEqual | LessThanEq | GreaterThanEq | DoubleAnd | DoublePipe |
+            IfKeyword | OrKeyword | InKeyword | ToKeyword | ByKeyword => 2,
+            IsaKeyword | NewKeyword | EndKeyword | ForKeyword | AndKeyword | NotKeyword | DoubleQuestionEqual => 3,
+            ThenKeyword | ElseKeyword | NullKeyword | TrueKeyword => 4,
+            FalseKeyword => 5,
+            WhileKeyword | BreakKeyword | DeferKeyword => 5,
+            TypeofKeyword | ReturnKeyword => 6,
+            FunctionKeyword | ContinueKeyword => 8
+        }
+    }
+}
+
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Identifier(id) => write!(f, "{}", id),
+            Comment(text) => write!(f, "//{}", text),
+            Whitespace(run) => write!(f, "{}", run),
+            StrLiteral(s) => write!(f, "{}", s),
+            NumLiteral(num) => write!(f, "{}", num.to_source()),
+            TypeofKeyword => write!(f, "typeof"),
+            IfKeyword => write!(f, "if"),
+            ThenKeyword => write!(f, "then"),
+            ElseKeyword => write!(f, "else"),
+            EndKeyword => write!(f, "end"),
+            WhileKeyword => write!(f, "while"),
+            ForKeyword => write!(f, "for"),
+            InKeyword => write!(f, "in"),
+            FunctionKeyword => write!(f, "function"),
+            ReturnKeyword => write!(f, "return"),
+            BreakKeyword => write!(f, "break"),
+            ContinueKeyword => write!(f, "continue"),
+            DeferKeyword => write!(f, "defer"),
+            ToKeyword => write!(f, "to"),
+            ByKeyword => write!(f, "by"),
+            IsaKeyword => write!(f, "isa"),
+            NewKeyword => write!(f, "new"),
+            NullKeyword => write!(f, "null"),
+            TrueKeyword => write!(f, "true"),
+            FalseKeyword => write!(f, "false"),
+            AndKeyword => write!(f, "and"),
+            OrKeyword => write!(f, "or"),
+            NotKeyword => write!(f, "not"),
+            Newline => write!(f, "\\n"),
+            SingleEqual => write!(f, "="),
+            SemiColon => write!(f, ";"),
+            Colon => write!(f, ":"),
+            Dot => write!(f, "."),
+            Comma => write!(f, ","),
+            DoubleEqual => write!(f, "=="),
+            ExclEqual => write!(f, "!="),
+            LessThan => write!(f, "<"),
+            LessThanEq => write!(f, "<="),
+            GreaterThan => write!(f, ">"),
+            GreaterThanEq => write!(f, ">="),
+            Plus => write!(f, "+"),
+            Minus => write!(f, "-"),
+            Asterisk => write!(f, "*"),
+            Slash => write!(f, "/"),
+            Percent => write!(f, "%"),
+            Caret => write!(f, "^"),
+            LeftParen => write!(f, "("),
+            RightParen => write!(f, ")"),
+            LeftCurly => write!(f, "{{"),
+            RightCurly => write!(f, "}}"),
+            LeftBracket => write!(f, "["),
+            RightBracket => write!(f, "]"),
+            Exclamation => write!(f, "!"),
+            At => write!(f, "@"),
+            DoubleAnd => write!(f, "&&"),
+            DoublePipe => write!(f, "||"),
+            DoubleQuestionEqual => write!(f, "??=")
+        }
+    }
+}
+
+/// Decodes the source text of a string literal (quotes included) into its
+/// value. Both doubled quotes and backslash escapes are understood.
+pub fn unescape_str_literal(raw: &str) -> String {
+    let inner = raw.strip_prefix('"').and_then(|s| s.strip_suffix('"')).unwrap_or(raw);
+    let mut out = String::with_capacity(inner.len());
+    let mut chars = inner.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '"' {
+            chars.next_if_eq(&'"');
+            out.push(c);
+            continue;
+        }
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some('r') => out.push('\r'),
+            Some(c) => out.push(c),
+            None => out.push('\\')
+        }
+    }
+    out
+}
+
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Token {
+    pub kind: TokenKind,
+    pub line: usize,
+    pub column: usize,
+    /// Byte offset of the token's first character in the source.
+    pub start: usize,
+    /// Byte offset just past the token's last character.
+    pub end: usize
+}
+
+impl Token {
+    /// The token exactly as written in `source`, which must be the input it
+    /// was lexed from. Tokens with a decoded value keep their source form
+    /// here: a string literal comes with its quotes and escapes, and an
+    /// escaped identifier with its backticks.
+    pub fn text<'a>(&self, source: &'a str) -> &'a str {
+        &source[self.start..self.end]
+    }
+}
+
+/// A view of the input still to be lexed. Looking one or two characters
+/// ahead reads straight from the remaining `&str`, so it costs nothing no
+/// matter how much input is left.
+#[derive(Clone, Copy)]
+struct Cursor<'a> {
+    rest: &'a str
+}
+
+impl<'a> Cursor<'a> {
+    fn new(input: &'a str) -> Self {
+        Self { rest: input }
+    }
+    fn peek(&self) -> Option<char> {
+        self.rest.chars().next()
+    }
+    fn peek_second(&self) -> Option<char> {
+        self.rest.chars().nth(1)
+    }
+    fn next_if(&mut self, pred: impl FnOnce(&char) -> bool) -> Option<char> {
+        match self.peek() {
+            Some(c) if pred(&c) => self.next(),
+            _ => None
+        }
+    }
+    fn next_if_eq(&mut self, expected: char) -> Option<char> {
+        self.next_if(|c| *c == expected)
+    }
+}
+
+impl Iterator for Cursor<'_> {
+    type Item = char;
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.rest = &self.rest[c.len_utf8()..];
+        Some(c)
+    }
+}
+
+#[inline(always)]
+fn read_numchars(chars: &mut Cursor, out: &mut String) {
+    while let Some(c) = chars.next_if(|c| c.is_ascii_digit()) {
+        out.push(c);
+    }
+}
+
+#[inline(always)]
+fn read_number_suffix(chars: &mut Cursor, out: &mut String) {
+    while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+        out.push(c);
+    }
+}
+
+#[inline(always)]
+fn parse_int_with_prefix(chars: &mut Cursor, text: &mut String, radix: u32) -> Result<TokenKind, LexerErrorKind> {
+    // A prefix letter must be followed by at least one digit of its radix.
+    if !chars.peek_second().is_some_and(|c| c.is_digit(radix)) {
+        return Err(LexerErrorKind::InvalidFloatLiteral)
+    }
+    text.push(chars.next().unwrap());
+    let mut val: f64 = 0.0;
+    while let Some(c) = chars.next_if(|c| c.is_digit(radix)) {
+        val *= radix as f64;
+        val += u8::from_str_radix(&c.to_string(), radix).unwrap() as f64;
+        text.push(c);
+    }
+    Ok(NumLiteral(NumLiteralData { value: val, raw: None, is_integer: true, radix, str_len: text.len() }))
+}
+
+#[inline(always)]
+fn parse_number_starting_with_0(chars: &mut Cursor, text: &mut String) -> Result<TokenKind, LexerErrorKind> {
+    Ok(match chars.peek() {
+        Some('x' | 'X') => parse_int_with_prefix(chars, text, 16)?,
+        Some('o' | 'O') => parse_int_with_prefix(chars, text, 8)?,
+        Some('b' | 'B') => parse_int_with_prefix(chars, text, 2)?,
+        Some('.') => {
+            if let Some('0'..='9') = chars.peek_second() {
+                chars.next();
+                text.push('.');
+                read_numchars(chars, text);
+                if chars.peek() == Some('e') {
+                    if let Some('1'..='9') = chars.peek_second() {
+                        chars.next();
+                        text.push('e');
+                        read_numchars(chars, text);
+                    }
+                }
+            }
+            TokenKind::try_into_float(text).map_err(|_| LexerErrorKind::InvalidFloatLiteral)?
+        },
+        _ => TokenKind::new_num_literal(0., 1, true)
+    })
+}
+
+pub type NumberHook = dyn Fn(&str) -> Result<f64, String>;
+
+#[derive(Default)]
+pub struct LexerOptions {
+    /// Overrides how numeric literals are turned into values. The hook gets
+    /// the literal's source text, including any letters directly following
+    /// it (so a host can accept suffixes like `5px`), and either returns the
+    /// value or rejects the literal with a message.
+    pub on_number: Option<Box<NumberHook>>,
+    /// Stores each numeric literal's source text in `NumLiteralData::raw`,
+    /// so `1.50` or `0x0F` can be reproduced exactly.
+    pub keep_number_text: bool,
+    /// Emits `Comment` and `Whitespace` tokens instead of skipping them, for
+    /// formatters and other tools that need to reproduce the source. The
+    /// parser does not accept these tokens.
+    pub keep_trivia: bool,
+    /// Rejects integer literals that `f64` cannot hold exactly, such as
+    /// `9007199254740993` (2^53 + 1), with `NumberLiteralTooLarge` instead
+    /// of rounding them to a neighbor. Not applied to literals read by
+    /// `on_number`.
+    pub exact_integers: bool,
+    /// Fails with `InputTooLarge` before lexing input longer than this many
+    /// bytes. Unlimited when `None`.
+    pub max_input_bytes: Option<usize>,
+    /// Fails with `InputTooLarge` at the first token past this many,
+    /// counting trivia tokens. Unlimited when `None`.
+    pub max_tokens: Option<usize>
+}
+
+/// Whether the integer literal `text` has exactly the value `value`. Digits
+/// past the range of `u128` are never exact.
+fn is_exact_integer(num: &NumLiteralData, text: &str) -> bool {
+    let digits = if num.radix == 10 { text } else { &text[2..] };
+    u128::from_str_radix(digits, num.radix).is_ok_and(|n| n as f64 == num.value && num.value as u128 == n)
+}
+
+fn number_token(
+    on_number: Option<&NumberHook>,
+    keep_text: bool,
+    exact_integers: bool,
+    kind: TokenKind,
+    text: &str
+) -> Result<TokenKind, LexerErrorKind> {
+    let kind = match (on_number, kind) {
+        (Some(hook), NumLiteral(num)) => hook(text)
+            .map(|value| NumLiteral(NumLiteralData { value, raw: None, str_len: text.len(), ..num }))
+            .map_err(LexerErrorKind::RejectedNumberLiteral)?,
+        (None, NumLiteral(num)) if exact_integers && num.is_integer && !is_exact_integer(&num, text) => {
+            return Err(LexerErrorKind::NumberLiteralTooLarge(text.to_string()))
+        },
+        (_, kind) => kind
+    };
+    Ok(match kind {
+        NumLiteral(num) if keep_text => NumLiteral(NumLiteralData { raw: Some(text.to_string()), ..num }),
+        kind => kind
+    })
+}
+
+#[derive(Debug)]
+pub struct ParseState {
+    tokens: VecDeque<Token>,
+    pub line: usize,
+    pub column: usize
+}
+
+impl ParseState {
+    fn new() -> Self {
+        Self {
+            tokens: VecDeque::new(),
+            line: 1,
+            column: 1
+        }
+    }
+    fn push_token(&mut self, kind: TokenKind) {
+        let len = kind.get_str_len();
+        // The lexer fills in the offsets once the token's characters are read.
+        self.tokens.push_back(Token {
+            kind,
+            line: self.line,
+            column: self.column,
+            start: 0,
+            end: 0
+        });
+        self.column += len;
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum LexerErrorKind {
+    InvalidFloatLiteral,
+    InvalidStringEscapeSequence,
+    UnterminatedStringLiteral,
+    InvalidEscapedIdentifier,
+    RejectedNumberLiteral(String),
+    /// An integer literal that `f64` cannot represent exactly; only with
+    /// `LexerOptions::exact_integers`.
+    NumberLiteralTooLarge(String),
+    /// The input is over `LexerOptions::max_input_bytes`, or has more
+    /// tokens than `LexerOptions::max_tokens`.
+    InputTooLarge,
+    InvalidCharacter(char)
+}
+
+#[derive(Debug)]
+pub struct LexerError {
+    pub state: ParseState,
+    pub kind: LexerErrorKind
+}
+
+impl LexerError {    
+    fn new(state: ParseState, kind: LexerErrorKind) -> Self {
+        Self { state, kind }
+    }
+}
+
+impl fmt::Display for LexerErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LexerErrorKind::InvalidFloatLiteral => write!(f, "Invalid float literal"),
+            LexerErrorKind::InvalidStringEscapeSequence => write!(f, "Invalid string escape sequence"),
+            LexerErrorKind::UnterminatedStringLiteral => write!(f, "Unterminated string literal"),
+            LexerErrorKind::InvalidEscapedIdentifier => write!(f, "Invalid escaped identifier"),
+            LexerErrorKind::RejectedNumberLiteral(message) => write!(f, "Rejected number literal: {}", message),
+            LexerErrorKind::NumberLiteralTooLarge(text) => {
+                write!(f, "Number literal {} cannot be represented exactly", text)
+            },
+            LexerErrorKind::InputTooLarge => write!(f, "Input exceeds the size limit"),
+            LexerErrorKind::InvalidCharacter(c) => write!(f, "Invalid character '{}'", c)
+        }
+    }
+}
+
+impl fmt::Display for LexerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {}:{}", self.kind, self.state.line, self.state.column)
+    }
+}
+
+impl std::error::Error for LexerError {}
+
+/// Turns tokens back into source text, spacing them out to their recorded
+/// columns. Escaped identifiers come back without their backticks.
+pub fn render_tokens<'a>(tokens: impl IntoIterator<Item = &'a Token>) -> String {
+    let mut out = String::new();
+    let mut column = 1;
+    for token in tokens {
+        while column < token.column {
+            out.push(' ');
+            column += 1;
+        }
+        match token.kind {
+            Newline => {
+                out.push('\n');
+                column = 1;
+            },
+            _ => {
+                out.push_str(&token.kind.to_string());
+                column += token.kind.get_str_len();
+            }
+        }
+    }
+    out
+}
+
+pub fn parse(input: &str) -> Result<VecDeque<Token>, LexerError> {
+    Lexer::new(input).collect()
+}
+
+/// Lexes `input` into a plain vector, in source order.
+pub fn lex(input: &str) -> Result<Vec<Token>, LexerError> {
+    Lexer::new(input).collect()
+}
+
+/// Lexes `input` into `tokens`, replacing its contents but reusing its
+/// allocation, for hosts lexing many inputs in a loop. After an error,
+/// `tokens` holds the tokens before it.
+pub fn lex_into(input: &str, tokens: &mut Vec<Token>) -> Result<(), LexerError> {
+    tokens.clear();
+    for token in Lexer::new(input) {
+        tokens.push(token?);
+    }
+    Ok(())
+}
+
+/// Lexes `input` keeping comments and whitespace runs as tokens.
+pub fn tokenize_with_trivia(input: &str) -> Result<VecDeque<Token>, LexerError> {
+    parse_with_options(input, &LexerOptions { keep_trivia: true, ..LexerOptions::default() })
+}
+
+pub fn parse_with_options(input: &str, options: &LexerOptions) -> Result<VecDeque<Token>, LexerError> {
+    Lexer::with_options(input, options).collect()
+}
+
+/// Lexes its input lazily, one token per `next()` call. Iteration ends
+/// after the first error.
+pub struct Lexer<'a> {
+    input: Cursor<'a>,
+    input_len: usize,
+    on_number: Option<&'a NumberHook>,
+    keep_number_text: bool,
+    keep_trivia: bool,
+    exact_integers: bool,
+    /// Whether the input is over `LexerOptions::max_input_bytes`, reported
+    /// by the first `next()`.
+    oversized: bool,
+    max_tokens: Option<usize>,
+    /// Tokens returned so far.
+    token_count: usize,
+    state: ParseState,
+    failed: bool
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(input: &'a str) -> Self {
+        Self {
+            input: Cursor::new(input),
+            input_len: input.len(),
+            on_number: None,
+            keep_number_text: false,
+            keep_trivia: false,
+            exact_integers: false,
+            oversized: false,
+            max_tokens: None,
+            token_count: 0,
+            state: ParseState::new(),
+            failed: false
+        }
+    }
+
+    pub fn with_options(input: &'a str, options: &'a LexerOptions) -> Self {
+        Self {
+            on_number: options.on_number.as_deref(),
+            keep_number_text: options.keep_number_text,
+            keep_trivia: options.keep_trivia,
+            exact_integers: options.exact_integers,
+            oversized: options.max_input_bytes.is_some_and(|max| input.len() > max),
+            max_tokens: options.max_tokens,
+            ..Self::new(input)
+        }
+    }
+
+    /// Byte offset of the next character to lex.
+    fn offset(&self) -> usize {
+        self.input_len - self.input.rest.len()
+    }
+
+    /// Ends iteration with an error at the current position.
+    fn fail(&mut self, kind: LexerErrorKind) -> LexerError {
+        self.failed = true;
+        LexerError::new(ParseState { tokens: VecDeque::new(), ..self.state }, kind)
+    }
+
+    /// Lexes the construct starting with `c`, which yields at most one token.
+    fn lex_char(&mut self, c: char) -> Result<(), LexerErrorKind> {
+        let input = &mut self.input;
+        let state = &mut self.state;
+        match c {
+            '0' => {
+                let mut text = c.to_string();
+                let kind = parse_number_starting_with_0(input, &mut text)?;
+                if self.on_number.is_some() {
+                    read_number_suffix(input, &mut text);
+                }
+                match number_token(self.on_number, self.keep_number_text, self.exact_integers, kind, &text) {
+                    Ok(kind) => state.push_token(kind),
+                    Err(kind) => return Err(kind)
+                }
+            },
+            '1'..='9' => {
+                let mut text = c.to_string();
+                read_numchars(input, &mut text);
+                if input.peek() == Some('.') {
+                    if let Some('0'..='9') = input.peek_second() {
+                        input.next();
+                        text.push('.');
+                        read_numchars(input, &mut text);
+                    }
+                }
+                if input.peek() == Some('e') {
+                    if let Some('1'..='9') = input.peek_second() {
+                        input.next();
+                        text.push('e');
+                        read_numchars(input, &mut text);
+                    }
+                }
+                let kind = TokenKind::try_into_float(&text).expect("Invalid float literal");
+                if self.on_number.is_some() {
+                    read_number_suffix(input, &mut text);
+                }
+                match number_token(self.on_number, self.keep_number_text, self.exact_integers, kind, &text) {
+                    Ok(kind) => state.push_token(kind),
+                    Err(kind) => return Err(kind)
+                }
+            },
+            '"' => {
+                let mut text = '"'.to_string();
+                loop {
+                    let Some(c) = input.next() else {
+                        return Err(LexerErrorKind::UnterminatedStringLiteral)
+                    };
+                    match c {
+                        '\\' => match input.peek() {
+                            Some('n' | 't' | 'r' | '\\' | '"') => {
+                                text.push('\\');
+                                text.push(input.next().unwrap());
+                            },
+                            _ => return Err(LexerErrorKind::InvalidStringEscapeSequence)
+                        },
+                        '\n' => return Err(LexerErrorKind::UnterminatedStringLiteral),
+                        // MiniScript's own escape for a quote is doubling it.
+                        '"' if input.next_if_eq('"').is_some() => text.push_str("\"\""),
+                        '"' => break,
+                        _ => text.push(c)
+                    }
+                }
+                text.push('"');
+                state.push_token(StrLiteral(text));
+            },
+            // `name` is always an identifier, even when `name` is a keyword or
+            // contains spaces. It may not be empty or span lines.
+            '`' => {
+                let mut text = String::new();
+                loop {
+                    match input.next() {
+                        Some('`') if !text.is_empty() => break,
+                        Some('`' | '\n') | None => return Err(LexerErrorKind::InvalidEscapedIdentifier),
+                        Some(c) => text.push(c)
+                    }
+                }
+                state.push_token(Identifier(text));
+                state.column += 2;
+            },
+            'a'..='z' | 'A'..='Z' | '_' => {
+                let mut text = c.to_string();
+                while let Some(c) = input.next_if(|c| matches!(c, 'a'..='z' | 'A'..='Z' | '_' | '0'..='9')) {
+                    text.push(c);
+                }
+                state.push_token(match text.as_str() {
+                    "typeof" => TypeofKeyword,
+                    "if" => IfKeyword,
+                    "then" => ThenKeyword,
+                    "else" => ElseKeyword,
+                    "end" => EndKeyword,
+                    "while" => WhileKeyword,
+                    "for" => ForKeyword,
+                    "in" => InKeyword,
+                    "function" => FunctionKeyword,
+                    "return" => ReturnKeyword,
+                    "break" => BreakKeyword,
+                    "continue" => ContinueKeyword,
+                    "defer" => DeferKeyword,
+                    "to" => ToKeyword,
+                    "by" => ByKeyword,
+                    "isa" => IsaKeyword,
+                    "new" => NewKeyword,
+                    "null" => NullKeyword,
+                    "true" => TrueKeyword,
+                    "false" => FalseKeyword,
+                    "and" => AndKeyword,
+                    "or" => OrKeyword,
+                    "not" => NotKeyword,
+                    _ => Identifier(text)
+                });
+            },
+            '=' => state.push_token(match input.next_if_eq('=') {
+                None => SingleEqual,
+                _ => DoubleEqual
+            }),
+            '!' => state.push_token(match input.next_if_eq('=') {
+                None => Exclamation,
+                _ => ExclEqual
+            }),
+            '<' => state.push_token(match input.next_if_eq('=') {
+                None => LessThan,
+                _ => LessThanEq,
+            }),
+            '>' => state.push_token(match input.next_if_eq('=') {
+                None => GreaterThan,
+                _ => GreaterThanEq
+            }),
+            '\n' => {
+                state.push_token(Newline);
+                state.line += 1;
+                state.column = 1;
+            },
+            '+' => state.push_token(Plus),
+            '-' => state.push_token(Minus),
+            '*' => state.push_token(Asterisk),
+            '/' if input.next_if_eq('/').is_some() => {
+                let mut text = String::new();
+                while let Some(c) = input.next_if(|c| *c != '\n') {
+                    text.push(c);
+                }
+                let comment = Comment(text);
+                if self.keep_trivia {
+                    state.push_token(comment);
+                } else {
+                    state.column += comment.get_str_len();
+                }
+            },
+            '/' => state.push_token(Slash),
+            '%' => state.push_token(Percent),
+            '^' => state.push_token(Caret),
+            ';' => state.push_token(SemiColon),
+            ':' => state.push_token(Colon),
+            '.' => state.push_token(Dot),
+            ',' => state.push_token(Comma),
+            '@' => state.push_token(At),
+            '(' => state.push_token(LeftParen),
+            ')' => state.push_token(RightParen),
+            '{' => state.push_token(LeftCurly),
+            '}' => state.push_token(RightCurly),
+            '[' => state.push_token(LeftBracket),
+            ']' => state.push_token(RightBracket),
+            ' ' | '\t' if self.keep_trivia => {
+                let mut run = c.to_string();
+                while let Some(c) = input.next_if(|c| *c == ' ' || *c == '\t') {
+                    run.push(c);
+                }
+                state.push_token(Whitespace(run));
+            },
+            ' ' | '\t' => state.column += 1,
+            '\r' => (),
+            '&' if input.next_if_eq('&').is_some() => state.push_token(DoubleAnd),
+            '|' if input.next_if_eq('|').is_some() => state.push_token(DoublePipe),
+            '?' if input.next_if_eq('?').is_some() => match input.next_if_eq('=') {
+                Some(_) => state.push_token(DoubleQuestionEqual),
+                None => return Err(LexerErrorKind::InvalidCharacter(c))
+            },
+            _ => return Err(LexerErrorKind::InvalidCharacter(c))
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for Lexer<'_> {
+    type Item = Result<Token, LexerError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        if self.oversized {
+            return Some(Err(self.fail(LexerErrorKind::InputTooLarge)));
+        }
+        loop {
+            if let Some(token) = self.state.tokens.pop_front() {
+                self.token_count += 1;
+                if self.max_tokens.is_some_and(|max| self.token_count > max) {
+                    let state = ParseState { tokens: VecDeque::new(), line: token.line, column: token.column };
+                    self.failed = true;
+                    return Some(Err(LexerError::new(state, LexerErrorKind::InputTooLarge)));
+                }
+                return Some(Ok(token));
+            }
+            let start = self.offset();
+            let c = self.input.next()?;
+            if let Err(kind) = self.lex_char(c) {
+                return Some(Err(self.fail(kind)));
+            }
+            let end = self.offset();
+            if let Some(token) = self.state.tokens.back_mut() {
+                token.start = start;
+                token.end = end;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn columns_count_characters_not_bytes() {
+        let tokens = lex("s = \"h\u{e9}llo\" + `\u{e9}t\u{e9}` // \u{e9}\nx").unwrap();
+        let columns: Vec<_> = tokens.iter().map(|token| (token.line, token.column)).collect();
+        assert_eq!(columns, [(1, 1), (1, 3), (1, 5), (1, 13), (1, 15), (1, 25), (2, 1)]);
+    }
+
+    #[test]
+    fn backticks_escape_keywords_and_spaces_in_identifiers() {
+        let tokens = lex("`end` `a b`").unwrap();
+        assert_eq!(tokens[0].kind, Identifier("end".to_string()));
+        assert_eq!(tokens[1].kind, Identifier("a b".to_string()));
+        assert_eq!(tokens[1].column, 7);
+        for source in ["``", "`ab", "`a\nb`"] {
+            assert!(matches!(lex(source).unwrap_err().kind, LexerErrorKind::InvalidEscapedIdentifier), "{}", source);
+        }
+    }
+
+    #[test]
+    fn number_hook_reads_literals_with_their_suffixes() {
+        let hook = |text: &str| text.strip_suffix("px").unwrap_or(text).parse::<f64>().map_err(|_| format!("bad number {}", text));
+        let options = LexerOptions { on_number: Some(Box::new(hook)), ..LexerOptions::default() };
+        let tokens = parse_with_options("5px + 1.5", &options).unwrap();
+        let values: Vec<_> = tokens.iter().filter_map(|token| match &token.kind { NumLiteral(num) => Some(num.value), _ => None }).collect();
+        assert_eq!(values, [5., 1.5]);
+        assert_eq!(tokens[1].column, 5);
+        let err = parse_with_options("1 + 2x", &options).unwrap_err();
+        assert!(matches!(err.kind, LexerErrorKind::RejectedNumberLiteral(ref message) if message == "bad number 2x"));
+        assert_eq!(err.state.column, 5);
+    }
+
+    #[test]
+    fn two_character_operators_need_their_second_character() {
+        let kinds = |source: &str| lex(source).unwrap().into_iter().map(|token| token.kind.to_string()).collect::<Vec<_>>();
+        assert_eq!(kinds("a<=b<c==d=e!=f>=g>h"), ["a", "<=", "b", "<", "c", "==", "d", "=", "e", "!=", "f", ">=", "g", ">", "h"]);
+        assert_eq!(kinds("x ??= 1 / 2 // c"), ["x", "??=", "1", "/", "2"]);
+        assert_eq!(kinds("1.5.x"), ["1.5", ".", "x"]);
+        assert_eq!(kinds("! ="), ["!", "="]);
+    }
+
+    #[test]
+    fn the_lexer_yields_tokens_lazily_and_stops_after_an_error() {
+        let mut lexer = Lexer::new("1 + $ 2");
+        assert_eq!(lexer.next().unwrap().unwrap().kind.to_string(), "1");
+        assert_eq!(lexer.next().unwrap().unwrap().kind, Plus);
+        assert!(matches!(lexer.next(), Some(Err(LexerError { kind: LexerErrorKind::InvalidCharacter('$'), .. }))));
+        assert!(lexer.next().is_none());
+        assert_eq!(Lexer::new("a b $").take(2).filter(Result::is_ok).count(), 2);
+    }
+
+    #[test]
+    fn zero_prefixes_need_a_digit_of_their_radix() {
+        let kinds = |source: &str| lex(source).unwrap().into_iter().map(|token| token.kind.to_string()).collect::<Vec<_>>();
+        assert_eq!(kinds("0"), ["0"]);
+        assert_eq!(kinds("0+x"), ["0", "+", "x"]);
+        assert_eq!(kinds("0 x"), ["0", "x"]);
+        assert_eq!(kinds("[0]"), ["[", "0", "]"]);
+        for source in ["0x", "0xg", "0b2", "0o", "0o8"] {
+            assert!(matches!(lex(source).unwrap_err().kind, LexerErrorKind::InvalidFloatLiteral), "{}", source);
+        }
+    }
+
+    #[test]
+    fn kept_number_text_renders_back_as_written() {
+        let options = LexerOptions { keep_number_text: true, ..LexerOptions::default() };
+        let tokens = parse_with_options("x = 1.50 +  0x0F\ny = `a b`", &options).unwrap();
+        let NumLiteral(num) = &tokens[2].kind else { panic!("expected a number") };
+        assert_eq!((num.value, num.raw.as_deref()), (1.5, Some("1.50")));
+        assert_eq!(render_tokens(&tokens), "x = 1.50 +  0x0F\ny = a b");
+        let NumLiteral(num) = &lex("1.50").unwrap()[0].kind else { panic!("expected a number") };
+        assert_eq!(num.raw, None);
+    }
+
+    #[test]
+    fn doubled_quotes_stand_for_one_quote() {
+        assert_eq!(lex(r#""say ""hi""""#).unwrap()[0].kind, StrLiteral(r#""say ""hi""""#.to_string()));
+        assert_eq!(crate::parser::parse_str(r#""say ""hi""""#).unwrap(), crate::parser::Expression::StringValue(r#"say "hi""#.to_string()));
+        assert_eq!(crate::parser::parse_str(r#""""""#).unwrap(), crate::parser::Expression::StringValue("\"".to_string()));
+        let tokens = lex(r#""""" + 1"#).unwrap();
+        assert_eq!((tokens.len(), tokens[1].column), (3, 6));
+        assert!(matches!(lex(r#""a"""#).unwrap_err().kind, LexerErrorKind::UnterminatedStringLiteral));
+    }
+
+    fn number(source: &str) -> NumLiteralData {
+        match lex(source).unwrap().remove(0).kind {
+            NumLiteral(num) => num,
+            kind => panic!("expected a number but found {:?}", kind)
+        }
+    }
+
+    #[test]
+    fn number_literals_record_whether_they_were_integers() {
+        for (source, is_integer) in [("5", true), ("0", true), ("0xFF", true), ("5.0", false), ("1e3", false), ("0.5", false)] {
+            assert_eq!(number(source).is_integer, is_integer, "{}", source);
+        }
+        assert_eq!(number("5").to_source(), "5");
+        assert_eq!(number("5.0").to_source(), "5.0");
+        assert_eq!(number("1e3").to_source(), "1000.0");
+    }
+
+    #[test]
+    fn number_literals_record_their_radix() {
+        for (source, value, radix) in [("0xFF", 255., 16), ("0o17", 15., 8), ("0b101", 5., 2), ("10", 10., 10), ("1.5", 1.5, 10)] {
+            let num = number(source);
+            assert_eq!((num.value, num.radix), (value, radix), "{}", source);
+        }
+        assert_eq!(number("0xff").to_source(), "0xFF");
+        assert_eq!(number("0o17").to_source(), "0o17");
+        assert_eq!(number("0b101").to_source(), "0b101");
+        assert_eq!(render_tokens(&lex("x = 0x1F + 10").unwrap()), "x = 0x1F + 10");
+    }
+
+    #[test]
+    fn radix_prefixes_and_hex_digits_ignore_case() {
+        for (source, value, radix) in [("0X1F", 31., 16), ("0B10", 2., 2), ("0O7", 7., 8), ("0xAbC", 2748., 16), ("0xabc", 2748., 16), ("0xABC", 2748., 16)] {
+            let num = number(source);
+            assert_eq!((num.value, num.radix, num.str_len()), (value, radix, source.len()), "{}", source);
+        }
+        assert_eq!(lex("0X1F+1").unwrap().len(), 3);
+        assert!(matches!(lex("0XG").unwrap_err().kind, LexerErrorKind::InvalidFloatLiteral));
+    }
+
+    #[test]
+    fn trivia_mode_keeps_comments_and_whitespace() {
+        let source = "x  =\t1 // one\ny";
+        let tokens = tokenize_with_trivia(source).unwrap();
+        let kinds: Vec<_> = tokens.iter().map(|token| token.kind.clone()).collect();
+        assert_eq!(
+            kinds,
+            [
+                Identifier("x".to_string()),
+                Whitespace("  ".to_string()),
+                SingleEqual,
+                Whitespace("\t".to_string()),
+                NumLiteral(NumLiteralData { value: 1., raw: None, is_integer: true, radix: 10, str_len: 1 }),
+                Whitespace(" ".to_string()),
+                Comment(" one".to_string()),
+                Newline,
+                Identifier("y".to_string())
+            ]
+        );
+        assert_eq!(tokens.iter().map(|token| token.text(source)).collect::<String>(), source);
+        assert_eq!((tokens[6].column, tokens[6].start, tokens[6].end), (8, 7, 13));
+        let plain = parse(source).unwrap();
+        assert!(plain.iter().all(|token| !matches!(token.kind, Comment(_) | Whitespace(_))));
+        assert_eq!(plain.len(), 5);
+        assert_eq!(plain[4].column, 1);
+    }
+
+    #[test]
+    fn exact_integers_rejects_literals_that_would_round() {
+        let options = LexerOptions { exact_integers: true, ..LexerOptions::default() };
+        let err = parse_with_options("x = 9007199254740993", &options).unwrap_err();
+        assert_eq!(err.kind, LexerErrorKind::NumberLiteralTooLarge("9007199254740993".to_string()));
+        assert_eq!(err.state.column, 5);
+        assert!(parse_with_options("9007199254740992 + 0x20000000000000 + 1.5", &options).is_ok());
+        assert!(matches!(parse_with_options("0x20000000000001", &options).unwrap_err().kind, LexerErrorKind::NumberLiteralTooLarge(_)));
+        assert_eq!(number("9007199254740993").value, 9007199254740992.);
+    }
+
+    #[test]
+    fn size_caps_stop_oversized_input() {
+        let source = "x = [1, 2, 3]";
+        let capped = |max_input_bytes, max_tokens| {
+            parse_with_options(source, &LexerOptions { max_input_bytes, max_tokens, ..LexerOptions::default() })
+        };
+        let err = capped(Some(4), None).unwrap_err();
+        assert_eq!((err.kind, err.state.line, err.state.column), (LexerErrorKind::InputTooLarge, 1, 1));
+        let err = capped(None, Some(3)).unwrap_err();
+        assert_eq!((err.kind, err.state.column), (LexerErrorKind::InputTooLarge, 6));
+        assert_eq!(capped(Some(source.len()), Some(9)).unwrap().len(), 9);
+        assert_eq!(capped(Some(1 << 20), Some(1000)).unwrap(), parse(source).unwrap());
+        let trivia = LexerOptions { keep_trivia: true, max_tokens: Some(2), ..LexerOptions::default() };
+        assert!(parse_with_options("a b", &trivia).is_err());
+    }
+
+    #[test]
+    fn number_widths_count_source_characters() {
+        let tokens = lex("3.14 + 0x1F").unwrap();
+        assert_eq!(number("3.14").str_len(), 4);
+        assert_eq!(tokens[2].kind.get_str_len(), 4);
+        assert_eq!((tokens[2].column, tokens[2].end - tokens[2].start), (8, 4));
+        let options = LexerOptions { on_number: Some(Box::new(|text: &str| text.trim_end_matches("px").parse().map_err(|_| String::new()))), ..LexerOptions::default() };
+        let NumLiteral(num) = &parse_with_options("12px", &options).unwrap()[0].kind else { panic!("expected a number") };
+        assert_eq!(num.str_len(), 4);
+    }
+
+    #[test]
+    fn vec_lexing_matches_the_deque() {
+        let source = "f(x, \"s\") // c\ny = 0x1F";
+        assert_eq!(lex(source).unwrap(), Vec::from(parse(source).unwrap()));
+        let mut tokens = Vec::with_capacity(64);
+        lex_into(source, &mut tokens).unwrap();
+        assert_eq!(tokens, lex(source).unwrap());
+        let capacity = tokens.capacity();
+        lex_into("a b", &mut tokens).unwrap();
+        assert_eq!((tokens.len(), tokens.capacity()), (2, capacity));
+        assert!(lex_into("a b $ c", &mut tokens).is_err());
+        assert_eq!(tokens.len(), 2);
+    }
+
+    #[test]
+    fn token_text_is_the_source_slice() {
+        let source = "new = `\u{e9}t\u{e9}` <= \"a\\\"b\" + 1.50\n  \"x\"\"y\"";
+        let texts: Vec<_> = lex(source).unwrap().iter().map(|token| token.text(source)).collect();
+        assert_eq!(texts, ["new", "=", "`\u{e9}t\u{e9}`", "<=", "\"a\\\"b\"", "+", "1.50", "\n", "\"x\"\"y\""]);
+        let tokens = lex(source).unwrap();
+        assert_eq!((tokens[2].start, tokens[2].end, tokens[3].start), (6, 13, 14));
+        assert_eq!(tokens[4].kind, StrLiteral("\"a\\\"b\"".to_string()));
+    }
+
+    #[test]
+    fn negative_radix_literals_lex_as_minus_and_the_literal() {
+        let tokens = lex("-0xFF").unwrap();
+        assert_eq!(tokens.len(), 2);
+        assert_eq!(tokens[0].kind, Minus);
+        let NumLiteral(num) = &tokens[1].kind else { panic!("expected a number") };
+        assert_eq!((num.value, num.radix), (255., 16));
+    }
+}
@@ -0,0 +1,46 @@
+//! A lexer, parser and tree-walking evaluator for MiniScript.
+//!
+//! ```
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let tokens = miniscript::lex("1 + 2")?;
+//! let expr = miniscript::parse_expression(&tokens)?;
+//! assert_eq!(format!("{:?}", expr), "add(1, 2)");
+//! assert_eq!(expr, "1 + 2".parse()?);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! With the `serde` feature, tokens, expressions and statements implement
+//! `Serialize` and `Deserialize`, with variant names as enum tags.
+
+pub mod diagnostic;
+pub mod eval;
+pub mod fold;
+pub mod format;
+pub mod intrinsics;
+pub mod json;
+pub mod lexer;
+pub mod lint;
+pub mod parser;
+pub mod rpn;
+mod sexpr;
+pub mod shared;
+pub mod visit;
+
+pub use diagnostic::{render_error, render_parse_error};
+pub use eval::{
+    call_function, eval_expression, exec_block, exec_statement, run_program, values_equal, BinaryOperator, Environment, Flow,
+    FunctionValue, InterpretError, MiniKey, OutputOptions, RuntimeError, UnaryOperator, Value,
+};
+pub use fold::{fold_constants, propagate_constants};
+pub use format::{format_expression, FormatOptions, Parentheses};
+pub use intrinsics::Intrinsic;
+pub use lexer::{
+    lex, lex_into, parse_with_options, render_tokens, tokenize_with_trivia, Lexer, LexerError, LexerErrorKind, LexerOptions,
+    Token, TokenKind,
+};
+pub use lint::{common_subexpressions, lint, Info, Warning};
+pub use parser::{parse_expression, parse_program, parse_str, Expression, ParseError, ParseErrorKind, ParseOptions, Parser, Statement};
+pub use rpn::{eval_rpn, to_rpn, RpnOp};
+pub use shared::{share_globals, SharedGlobals, SharedValue};
+pub use visit::{Visitor, VisitorMut};
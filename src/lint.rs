@@ -0,0 +1,260 @@
+//! Static checks that flag code which is valid but probably not what was
+//! meant. Warnings never stop a program from running. `Info` reports point
+//! out what an optimizer could improve instead.
+
+use crate::{
+    parser::{Expression, Statement},
+    visit::{walk_expression, walk_statement, Visitor},
+};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fmt::{Display, Formatter, Result as FormatResult},
+    hash::{Hash, Hasher},
+    mem::discriminant,
+};
+use Expression::*;
+
+#[derive(Debug, PartialEq)]
+pub enum Warning {
+    /// An expression statement with no side effects whose value is thrown
+    /// away, such as `1 + 2`. Holds the expression as source.
+    UnusedPureExpression(String),
+}
+
+impl Display for Warning {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FormatResult {
+        match self {
+            Warning::UnusedPureExpression(expr) => write!(f, "Unused value of '{}', which has no side effects", expr),
+        }
+    }
+}
+
+/// Observations for an optimizer rather than problems in the code.
+#[derive(Debug, PartialEq)]
+pub enum Info {
+    /// A pure expression written more than once in one statement, such as
+    /// `a * b` in `a * b + a * b`, which could be computed once. Holds the
+    /// expression as source.
+    CommonSubexpression(String),
+}
+
+impl Display for Info {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FormatResult {
+        match self {
+            Info::CommonSubexpression(expr) => write!(f, "'{}' is computed more than once in the same statement", expr),
+        }
+    }
+}
+
+/// Finds calls and assignments, without looking inside function literals,
+/// whose bodies do not run where they are written.
+struct SideEffects(bool);
+
+impl Visitor for SideEffects {
+    fn visit_expression(&mut self, expr: &Expression) {
+        match expr {
+            FunctionCall(..) | Assignment(..) | NullCoalescingAssignment(..) | New(_) => self.0 = true,
+            Function { .. } => (),
+            _ => walk_expression(self, expr),
+        }
+    }
+}
+
+fn is_pure(expr: &Expression) -> bool {
+    let mut effects = SideEffects(false);
+    effects.visit_expression(expr);
+    !effects.0
+}
+
+struct Linter(Vec<Warning>);
+
+impl Visitor for Linter {
+    fn visit_statement(&mut self, statement: &Statement) {
+        if let Statement::Expression(expr) = statement {
+            if is_pure(expr) {
+                self.0.push(Warning::UnusedPureExpression(expr.to_string()));
+            }
+        }
+        walk_statement(self, statement);
+    }
+}
+
+/// Checks a whole program. The last top-level statement is exempt from
+/// `UnusedPureExpression`, since its value is the program's result.
+pub fn lint(program: &[Statement]) -> Vec<Warning> {
+    let mut linter = Linter(Vec::new());
+    for (i, statement) in program.iter().enumerate() {
+        match statement {
+            Statement::Expression(expr) if i + 1 == program.len() => linter.visit_expression(expr),
+            statement => linter.visit_statement(statement),
+        }
+    }
+    linter.0
+}
+
+/// Hashes the shape and contents of a tree, so that equal expressions hash
+/// alike. Numbers hash by their bits.
+struct StructuralHasher(DefaultHasher);
+
+impl Visitor for StructuralHasher {
+    fn visit_expression(&mut self, expr: &Expression) {
+        discriminant(expr).hash(&mut self.0);
+        match expr {
+            StringValue(s) | Variable(s) => s.hash(&mut self.0),
+            NumberValue(n) => n.to_bits().hash(&mut self.0),
+            BoolValue(b) => b.hash(&mut self.0),
+            Between { low_inclusive, high_inclusive, .. } => (low_inclusive, high_inclusive).hash(&mut self.0),
+            Function { params, .. } => params.iter().for_each(|(name, _)| name.hash(&mut self.0)),
+            _ => (),
+        }
+        walk_expression(self, expr);
+    }
+
+    fn visit_statement(&mut self, statement: &Statement) {
+        discriminant(statement).hash(&mut self.0);
+        match statement {
+            Statement::For { var: name, .. } | Statement::Labeled(name, _) => name.hash(&mut self.0),
+            Statement::Break(label) | Statement::Continue(label) => label.hash(&mut self.0),
+            _ => (),
+        }
+        walk_statement(self, statement);
+    }
+}
+
+fn structural_hash(expr: &Expression) -> u64 {
+    let mut hasher = StructuralHasher(DefaultHasher::new());
+    hasher.visit_expression(expr);
+    hasher.0.finish()
+}
+
+/// Whether `expr` is worth computing once: pure, and more than a literal,
+/// variable or function literal.
+fn is_reusable(expr: &Expression) -> bool {
+    !matches!(expr, StringValue(_) | NumberValue(_) | BoolValue(_) | NullValue | Variable(_) | Function { .. }) && is_pure(expr)
+}
+
+/// Counts the reusable subexpressions of one statement, bucketed by
+/// structural hash and told apart within a bucket by `==`. Assignment
+/// targets name places rather than values and are skipped, as are function
+/// literals, whose bodies are statements of their own.
+#[derive(Default)]
+struct Occurrences(HashMap<u64, Vec<(Expression, usize)>>);
+
+impl Occurrences {
+    fn count(&self, expr: &Expression) -> usize {
+        let bucket = self.0.get(&structural_hash(expr));
+        bucket.and_then(|bucket| bucket.iter().find(|(seen, _)| seen == expr)).map_or(0, |(_, count)| *count)
+    }
+}
+
+impl Visitor for Occurrences {
+    fn visit_expression(&mut self, expr: &Expression) {
+        if is_reusable(expr) {
+            let bucket = self.0.entry(structural_hash(expr)).or_default();
+            match bucket.iter_mut().find(|(seen, _)| seen == expr) {
+                Some((_, count)) => *count += 1,
+                None => bucket.push((expr.clone(), 1)),
+            }
+        }
+        match expr {
+            Assignment(_, value) | NullCoalescingAssignment(_, value) => self.visit_expression(value),
+            Function { .. } => (),
+            _ => walk_expression(self, expr),
+        }
+    }
+}
+
+/// Reports the outermost repeated subexpressions of a statement, so that
+/// `a * b * c + a * b * c` yields `a * b * c` and not also `a * b`.
+struct Repeats<'a> {
+    occurrences: &'a Occurrences,
+    found: Vec<Expression>,
+}
+
+impl Visitor for Repeats<'_> {
+    fn visit_expression(&mut self, expr: &Expression) {
+        if is_reusable(expr) && self.occurrences.count(expr) > 1 {
+            if !self.found.contains(expr) {
+                self.found.push(expr.clone());
+            }
+            return;
+        }
+        match expr {
+            Assignment(_, value) | NullCoalescingAssignment(_, value) => self.visit_expression(value),
+            Function { .. } => (),
+            _ => walk_expression(self, expr),
+        }
+    }
+}
+
+struct CommonSubexpressions(Vec<Info>);
+
+impl Visitor for CommonSubexpressions {
+    fn visit_statement(&mut self, statement: &Statement) {
+        let exprs: Vec<&Expression> = match statement {
+            Statement::Expression(expr) | Statement::Return(Some(expr)) => vec![expr],
+            Statement::If { branches, .. } => branches.iter().map(|(condition, _)| condition).collect(),
+            Statement::While { condition: expr, .. } | Statement::For { iterable: expr, .. } => vec![expr],
+            _ => Vec::new(),
+        };
+        let mut occurrences = Occurrences::default();
+        exprs.iter().for_each(|expr| occurrences.visit_expression(expr));
+        let mut repeats = Repeats { occurrences: &occurrences, found: Vec::new() };
+        exprs.iter().for_each(|expr| repeats.visit_expression(expr));
+        self.0.extend(repeats.found.iter().map(|expr| Info::CommonSubexpression(expr.to_string())));
+        walk_statement(self, statement);
+    }
+}
+
+/// Finds pure subexpressions that a statement computes more than once,
+/// as candidates for common subexpression elimination. Calls are never
+/// candidates, since each may return something different.
+pub fn common_subexpressions(program: &[Statement]) -> Vec<Info> {
+    let mut finder = CommonSubexpressions(Vec::new());
+    program.iter().for_each(|statement| finder.visit_statement(statement));
+    finder.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_program;
+
+    fn warnings(source: &str) -> Vec<String> {
+        lint(&parse_program(&crate::lexer::lex(source).unwrap()).unwrap()).iter().map(Warning::to_string).collect()
+    }
+
+    #[test]
+    fn pure_expression_statements_are_flagged() {
+        assert_eq!(warnings("1 + 2;\nx = 1"), ["Unused value of '1 + 2', which has no side effects"]);
+        assert_eq!(warnings("while x\n  x == 1\nend while\nx"), ["Unused value of 'x == 1', which has no side effects"]);
+        assert_eq!(warnings("f = function()\n  a.b\nend function\n0"), ["Unused value of 'a.b', which has no side effects"]);
+    }
+
+    #[test]
+    fn calls_assignments_and_the_result_are_not_flagged() {
+        assert!(warnings("f();\nx = 1;\ny ??= 2;\n[g(1)]\nnew m\n1 + 2").is_empty());
+        assert_eq!(warnings("function()\n  f()\nend function\n0").len(), 1);
+    }
+
+    fn infos(source: &str) -> Vec<String> {
+        common_subexpressions(&parse_program(&crate::lexer::lex(source).unwrap()).unwrap()).iter().map(Info::to_string).collect()
+    }
+
+    #[test]
+    fn repeated_pure_subexpressions_are_reported() {
+        assert_eq!(infos("a*b + a*b"), ["'a * b' is computed more than once in the same statement"]);
+        assert_eq!(infos("x = a*b*c - a*b*c"), ["'a * b * c' is computed more than once in the same statement"]);
+        assert_eq!(infos("if m.k > 1 then\n  y = m.k + m.k\nend if").len(), 1);
+        assert_eq!(infos("f = function()\n  return -x / -x\nend function"), ["'-x' is computed more than once in the same statement"]);
+    }
+
+    #[test]
+    fn calls_and_lone_occurrences_are_not_reported() {
+        assert!(infos("f() + f()").is_empty());
+        assert!(infos("a*b + b*a").is_empty());
+        assert!(infos("y = a*b\nz = a*b").is_empty());
+        assert!(infos("x + x").is_empty());
+        assert!(infos("a[i] = a[i] + 1").is_empty());
+    }
+}
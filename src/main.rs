@@ -1,17 +1,39 @@
-mod lexer;
-mod parser;
+use miniscript::{eval, lexer, parser, render_error, render_parse_error, Value};
+use std::process::exit;
 
 fn main() {
-    let input = std::fs::read_to_string("test.msct").expect("Failed to read file");
-    let mut tokens = match lexer::parse(&input) {
+    let path = std::env::args().nth(1).unwrap_or_else(|| "test.msct".to_string());
+    let input = match std::fs::read_to_string(&path) {
+        Ok(input) => input,
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", path, e);
+            eprintln!("Usage: miniscript-on-rust [script.msct]");
+            exit(1);
+        }
+    };
+    let tokens = match lexer::lex(&input) {
         Ok(tokens) => tokens,
         Err(e) => {
-            eprintln!("Failed: {:?}", e);
-            return;
+            eprintln!("{}", render_error(&input, &e));
+            exit(1);
         }
     };
-    match parser::parse_expression(&mut tokens) {
-        Ok(expr) => println!("Parsed: {:?}", expr),
-        Err(e) => eprintln!("Failed: {:?}", e),
+    let program = match parser::parse_program(&tokens) {
+        Ok(program) => program,
+        Err(e) => {
+            eprintln!("{}", render_parse_error(&input, &e));
+            exit(1);
+        }
+    };
+    for statement in &program {
+        println!("Parsed: {}", statement.to_sexpr());
+    }
+    match eval::exec_program(&program, &mut eval::Environment::new()) {
+        Ok(Value::Null) => {}
+        Ok(value) => println!("Value: {}", value.repr()),
+        Err(e) => {
+            eprintln!("Failed: {}", e);
+            exit(1);
+        }
     }
 }
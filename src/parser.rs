@@ -1,275 +1,1729 @@
-use crate::lexer::{Token, TokenKind};
-use std::{collections::VecDeque, fmt::{Debug, Formatter, Result as FormatResult}};
-use Expression::*;
-
-type ExprPtr = Box<Expression>;
-
-pub enum Expression {
-    StringValue(String),
-    NumberValue(f64),
-    Variable(String),
-    MemberAccess(ExprPtr, ExprPtr),
-    FunctionCall(ExprPtr, Vec<Expression>),
-    LogicalNot(ExprPtr),
-    UnaryNegation(ExprPtr),
-    Typeof(ExprPtr),
-    Multiplication(ExprPtr, ExprPtr),
-    Division(ExprPtr, ExprPtr),
-    Remainder(ExprPtr, ExprPtr),
-    Addition(ExprPtr, ExprPtr),
-    Subtraction(ExprPtr, ExprPtr),
-    LessThan(ExprPtr, ExprPtr),
-    LessThanEq(ExprPtr, ExprPtr),
-    GreaterThan(ExprPtr, ExprPtr),
-    GreaterThanEq(ExprPtr, ExprPtr),
-    Equality(ExprPtr, ExprPtr),
-    Inequality(ExprPtr, ExprPtr),
-    LogicalAnd(ExprPtr, ExprPtr),
-    LogicalOr(ExprPtr, ExprPtr),
-    Assignment(ExprPtr, ExprPtr),
-}
-
-impl Expression {
-    #[inline(always)]
-    fn boxing(self) -> ExprPtr {
-        Box::new(self)
-    }
-}
-
-impl Debug for Expression {
-    fn fmt(&self, f: &mut Formatter<'_>) -> FormatResult {
-        match self {
-            StringValue(s) => write!(f, "{}", s),
-            NumberValue(n) => write!(f, "{}", n),
-            Variable(s) => write!(f, "var({})", s),
-            MemberAccess(left, right) => write!(f, "access({:?}, {:?})", left, right),
-            FunctionCall(left, right) => write!(f, "call({:?}, {:?})", left, right),
-            LogicalNot(left) => write!(f, "not({:?})", left),
-            UnaryNegation(left) => write!(f, "minus({:?})", left),
-            Typeof(left) => write!(f, "type({:?})", left),
-            Multiplication(left, right) => write!(f, "mul({:?}, {:?})", left, right),
-            Division(left, right) => write!(f, "div({:?}, {:?})", left, right),
-            Remainder(left, right) => write!(f, "rem({:?}, {:?})", left, right),
-            Addition(left, right) => write!(f, "add({:?}, {:?})", left, right),
-            Subtraction(left, right) => write!(f, "sub({:?}, {:?})", left, right),
-            LessThan(left, right) => write!(f, "lt({:?}, {:?})", left, right),
-            LessThanEq(left, right) => write!(f, "le({:?}, {:?})", left, right),
-            GreaterThan(left, right) => write!(f, "gt({:?}, {:?})", left, right),
-            GreaterThanEq(left, right) => write!(f, "ge({:?}, {:?})", left, right),
-            Equality(left, right) => write!(f, "eq({:?}, {:?})", left, right),
-            Inequality(left, right) => write!(f, "nq({:?}, {:?})", left, right),
-            LogicalAnd(left, right) => write!(f, "and({:?}, {:?})", left, right),
-            LogicalOr(left, right) => write!(f, "or({:?}, {:?})", left, right),
-            Assignment(left, right) => write!(f, "asin({:?}, {:?})", left, right),
-        }
-    }
-}
-
-fn parse_value_expr(tokens: &mut VecDeque<Token>) -> Result<Expression, String> {
-    if let Some(token) = tokens.pop_front() {
-        match token.kind {
-            TokenKind::StrLiteral(value) => Ok(StringValue(value)),
-            TokenKind::NumLiteral(value) => Ok(NumberValue(value.value)),
-            TokenKind::Identifier(value) => Ok(Variable(value)),
-            TokenKind::LeftParen => {
-                let expr = parse_expression(tokens);
-                let token = tokens.pop_front().unwrap();
-                if token.kind != TokenKind::RightParen {
-                    Err(format!("Expected ')' but found '{:?}'", token.kind))
-                } else {
-                    expr
-                }
-            }
-            _ => Err(format!("Expected primary but found '{:?}'", token.kind)),
-        }
-    } else {
-        Err("Unexpected end of input".to_string())
-    }
-}
-
-fn parse_primary(tokens: &mut VecDeque<Token>) -> Result<Expression, String> {
-    let mut expr = parse_value_expr(tokens)?;
-    while let Some(token) = tokens.front() {
-        match token.kind {
-            TokenKind::Dot => {
-                tokens.pop_front();
-                expr = MemberAccess(expr.boxing(), parse_value_expr(tokens)?.boxing());
-            }
-            TokenKind::LeftParen => {
-                tokens.pop_front();
-                let mut args = Vec::new();
-                while let Some(token) = tokens.front() {
-                    if token.kind == TokenKind::RightParen {
-                        tokens.pop_front();
-                        break;
-                    }
-                    args.push(parse_expression(tokens)?);
-                    if let Some(token) = tokens.front() {
-                        if token.kind == TokenKind::Comma {
-                            tokens.pop_front();
-                        } else if token.kind != TokenKind::RightParen {
-                            return Err(format!(
-                                "Expected ',' or ')' but found '{:?}'",
-                                token.kind
-                            ));
-                        }
-                    } else {
-                        return Err("Unexpected end of input".to_string());
-                    }
-                }
-                expr = FunctionCall(expr.boxing(), args);
-            }
-            _ => break,
-        }
-    }
-    Ok(expr)
-}
-
-fn parse_unary(tokens: &mut VecDeque<Token>) -> Result<Expression, String> {
-    if let Some(token) = tokens.front() {
-        match token.kind {
-            TokenKind::Exclamation => {
-                tokens.pop_front();
-                return Ok(LogicalNot(parse_unary(tokens)?.boxing()));
-            }
-            TokenKind::TypeofKeyword => {
-                tokens.pop_front();
-                return Ok(Typeof(parse_unary(tokens)?.boxing()));
-            }
-            TokenKind::Minus => {
-                tokens.pop_front();
-                return Ok(UnaryNegation(parse_unary(tokens)?.boxing()));
-            }
-            _ => (),
-        }
-    }
-    parse_primary(tokens)
-}
-
-fn parse_muldiv(tokens: &mut VecDeque<Token>) -> Result<Expression, String> {
-    let mut left = parse_unary(tokens)?;
-    while let Some(token) = tokens.front() {
-        match token.kind {
-            TokenKind::Asterisk => {
-                tokens.pop_front();
-                left = Multiplication(left.boxing(), parse_unary(tokens)?.boxing());
-            }
-            TokenKind::Slash => {
-                tokens.pop_front();
-                left = Division(left.boxing(), parse_unary(tokens)?.boxing());
-            }
-            TokenKind::Percent => {
-                tokens.pop_front();
-                left = Remainder(left.boxing(), parse_unary(tokens)?.boxing());
-            }
-            _ => break,
-        }
-    }
-    Ok(left)
-}
-
-fn parse_addsub(tokens: &mut VecDeque<Token>) -> Result<Expression, String> {
-    let mut left = parse_muldiv(tokens)?;
-    while let Some(token) = tokens.front() {
-        match token.kind {
-            TokenKind::Plus => {
-                tokens.pop_front();
-                left = Addition(left.boxing(), parse_muldiv(tokens)?.boxing());
-            }
-            TokenKind::Minus => {
-                tokens.pop_front();
-                left = Subtraction(left.boxing(), parse_muldiv(tokens)?.boxing());
-            }
-            _ => break,
-        }
-    }
-    Ok(left)
-}
-
-fn parse_relational(tokens: &mut VecDeque<Token>) -> Result<Expression, String> {
-    let mut left = parse_addsub(tokens)?;
-    while let Some(token) = tokens.front() {
-        match token.kind {
-            TokenKind::LessThan => {
-                tokens.pop_front();
-                left = LessThan(left.boxing(), parse_addsub(tokens)?.boxing());
-            }
-            TokenKind::LessThanEq => {
-                tokens.pop_front();
-                left = LessThanEq(left.boxing(), parse_addsub(tokens)?.boxing());
-            }
-            TokenKind::GreaterThan => {
-                tokens.pop_front();
-                left = GreaterThan(left.boxing(), parse_addsub(tokens)?.boxing());
-            }
-            TokenKind::GreaterThanEq => {
-                tokens.pop_front();
-                left = GreaterThanEq(left.boxing(), parse_addsub(tokens)?.boxing());
-            }
-            _ => break,
-        }
-    }
-    Ok(left)
-}
-
-fn parse_equality(tokens: &mut VecDeque<Token>) -> Result<Expression, String> {
-    let mut left = parse_relational(tokens)?;
-    while let Some(token) = tokens.front() {
-        match token.kind {
-            TokenKind::DoubleEqual => {
-                tokens.pop_front();
-                left = Equality(left.boxing(), parse_relational(tokens)?.boxing());
-            }
-            TokenKind::ExclEqual => {
-                tokens.pop_front();
-                left = Inequality(left.boxing(), parse_relational(tokens)?.boxing());
-            }
-            _ => break,
-        }
-    }
-    Ok(left)
-}
-
-fn parse_logical_and(tokens: &mut VecDeque<Token>) -> Result<Expression, String> {
-    let mut left = parse_equality(tokens)?;
-    while tokens
-        .front()
-        .map(|token| token.kind == TokenKind::DoubleAnd)
-        .unwrap_or(false)
-    {
-        tokens.pop_front();
-        left = LogicalAnd(left.boxing(), parse_equality(tokens)?.boxing());
-    }
-    Ok(left)
-}
-
-fn parse_logical_or(tokens: &mut VecDeque<Token>) -> Result<Expression, String> {
-    let mut left = parse_logical_and(tokens)?;
-    while tokens
-        .front()
-        .map(|token| token.kind == TokenKind::DoublePipe)
-        .unwrap_or(false)
-    {
-        tokens.pop_front();
-        left = LogicalOr(left.boxing(), parse_logical_and(tokens)?.boxing());
-    }
-    Ok(left)
-}
-
-fn parse_assignment(tokens: &mut VecDeque<Token>) -> Result<Expression, String> {
-    let left = parse_logical_or(tokens)?;
-    if let Some(token) = tokens.front() {
-        if token.kind == TokenKind::SingleEqual {
-            tokens.pop_front();
-            return Ok(Assignment(
-                left.boxing(),
-                parse_assignment(tokens)?.boxing(),
-            ));
-        }
-    }
-    Ok(left)
-}
-
-pub fn parse_expression(tokens: &mut VecDeque<Token>) -> Result<Expression, String> {
-    parse_assignment(tokens)
-}
+use crate::format::{format_expression, FormatOptions};
+use crate::lexer::{self, unescape_str_literal, LexerError, LexerErrorKind, Token, TokenKind};
+use std::fmt::{Debug, Display, Formatter, Result as FormatResult, Write};
+use std::str::FromStr;
+use Expression::*;
+
+type ExprPtr = Box<Expression>;
+
+#[derive(Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Expression {
+    StringValue(String),
+    NumberValue(f64),
+    BoolValue(bool),
+    NullValue,
+    Variable(String),
+    MemberAccess(ExprPtr, ExprPtr),
+    /// `target[index]`.
+    Index(ExprPtr, ExprPtr),
+    FunctionCall(ExprPtr, Vec<Expression>),
+    /// `[a, b, c]`.
+    ListLiteral(Vec<Expression>),
+    /// `{key: value, ...}`.
+    MapLiteral(Vec<(Expression, Expression)>),
+    LogicalNot(ExprPtr),
+    UnaryNegation(ExprPtr),
+    Typeof(ExprPtr),
+    New(ExprPtr),
+    /// `@f`: the value of `f` itself. MiniScript calls a function named
+    /// without parentheses, and `@` refers to it instead; here naming a
+    /// function never calls it, so `@f` evaluates like `f`.
+    AddressOf(ExprPtr),
+    Multiplication(ExprPtr, ExprPtr),
+    Division(ExprPtr, ExprPtr),
+    Remainder(ExprPtr, ExprPtr),
+    /// `^` with `ParseOptions::caret_is_power`.
+    Power(ExprPtr, ExprPtr),
+    /// `^` by default.
+    BitwiseXor(ExprPtr, ExprPtr),
+    Addition(ExprPtr, ExprPtr),
+    Subtraction(ExprPtr, ExprPtr),
+    LessThan(ExprPtr, ExprPtr),
+    LessThanEq(ExprPtr, ExprPtr),
+    GreaterThan(ExprPtr, ExprPtr),
+    GreaterThanEq(ExprPtr, ExprPtr),
+    IsA(ExprPtr, ExprPtr),
+    Equality(ExprPtr, ExprPtr),
+    Inequality(ExprPtr, ExprPtr),
+    LogicalAnd(ExprPtr, ExprPtr),
+    LogicalOr(ExprPtr, ExprPtr),
+    Assignment(ExprPtr, ExprPtr),
+    NullCoalescingAssignment(ExprPtr, ExprPtr),
+    Conditional(ExprPtr, ExprPtr, ExprPtr),
+    /// `low < value < high`, produced only with `ParseOptions::chained_comparisons`.
+    /// The flags tell whether each side was written with `<=`.
+    Between {
+        low: ExprPtr,
+        value: ExprPtr,
+        high: ExprPtr,
+        low_inclusive: bool,
+        high_inclusive: bool,
+    },
+    /// `start to end [by step]`, evaluating to a list of numbers.
+    Range {
+        start: ExprPtr,
+        end: ExprPtr,
+        step: Option<ExprPtr>,
+    },
+    Function {
+        params: Vec<(String, Option<Expression>)>,
+        body: Vec<Statement>,
+    },
+}
+
+#[derive(Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Statement {
+    Expression(Expression),
+    If {
+        branches: Vec<(Expression, Vec<Statement>)>,
+        else_branch: Option<Vec<Statement>>,
+    },
+    While {
+        condition: Expression,
+        body: Vec<Statement>,
+    },
+    For {
+        var: String,
+        iterable: Expression,
+        body: Vec<Statement>,
+    },
+    Return(Option<Expression>),
+    /// `break`, or `break label` to leave the enclosing loop with that label.
+    Break(Option<String>),
+    Continue(Option<String>),
+    /// `label: while ...` or `label: for ...`, naming a loop for `break` and
+    /// `continue`.
+    Labeled(String, Box<Statement>),
+    /// Runs its statement when the enclosing block exits.
+    Defer(Box<Statement>),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ParseErrorKind {
+    /// Input that does not fit the grammar; the message says what was expected.
+    Syntax,
+    DuplicateParameter(String),
+    /// `break` or `continue` naming a label that no enclosing loop has.
+    UndefinedLabel(String),
+    /// The left side of `=` or `??=` is not a variable, member or element.
+    InvalidAssignmentTarget,
+    /// A block closed by the terminator of another kind of block, such as
+    /// `while ... end for`.
+    MismatchedEnd { expected: String, found: String },
+    /// Input nested deeper than `ParseOptions::max_depth`.
+    NestingTooDeep,
+    /// The source did not lex; only from `parse_str`, which lexes too.
+    Lexer(LexerErrorKind),
+}
+
+#[derive(Debug)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl ParseError {
+    fn at(token: &Token, message: String) -> Self {
+        Self { kind: ParseErrorKind::Syntax, message, line: token.line, column: token.column }
+    }
+    fn duplicate_parameter(token: &Token, name: String) -> Self {
+        Self {
+            message: format!("Duplicate parameter '{}'", name),
+            kind: ParseErrorKind::DuplicateParameter(name),
+            line: token.line,
+            column: token.column,
+        }
+    }
+    fn invalid_assignment_target(token: &Token) -> Self {
+        Self {
+            kind: ParseErrorKind::InvalidAssignmentTarget,
+            message: "Cannot assign to this expression".to_string(),
+            line: token.line,
+            column: token.column,
+        }
+    }
+    fn mismatched_end(token: &Token, expected: String, found: String) -> Self {
+        Self {
+            message: format!("Expected '{}' but found '{}'", expected, found),
+            kind: ParseErrorKind::MismatchedEnd { expected, found },
+            line: token.line,
+            column: token.column,
+        }
+    }
+    fn undefined_label(token: &Token, name: String) -> Self {
+        Self {
+            message: format!("Undefined label '{}'", name),
+            kind: ParseErrorKind::UndefinedLabel(name),
+            line: token.line,
+            column: token.column,
+        }
+    }
+}
+
+impl From<LexerError> for ParseError {
+    fn from(e: LexerError) -> Self {
+        Self { message: e.kind.to_string(), kind: ParseErrorKind::Lexer(e.kind), line: e.state.line, column: e.state.column }
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FormatResult {
+        write!(f, "{} at {}:{}", self.message, self.line, self.column)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Binding strengths, from assignment (loosest) up to literals and
+/// parenthesized forms. The parser's binary operator table and `Display`
+/// both use these. Each level is one more than the level below it.
+pub(crate) mod precedence {
+    pub const ASSIGNMENT: u8 = 1;
+    pub const CONDITIONAL: u8 = 2;
+    pub const OR: u8 = 3;
+    pub const AND: u8 = 4;
+    pub const XOR: u8 = 5;
+    pub const EQUALITY: u8 = 6;
+    pub const RELATIONAL: u8 = 7;
+    pub const RANGE: u8 = 8;
+    pub const ADDITIVE: u8 = 9;
+    pub const MULTIPLICATIVE: u8 = 10;
+    pub const UNARY: u8 = 11;
+    pub const POWER: u8 = 12;
+    pub const POSTFIX: u8 = 13;
+    pub const ATOM: u8 = 14;
+}
+
+impl Expression {
+    #[inline(always)]
+    fn boxing(self) -> ExprPtr {
+        Box::new(self)
+    }
+    /// How tightly the expression binds; see `precedence`.
+    pub(crate) fn precedence(&self) -> u8 {
+        match self {
+            Assignment(..) | NullCoalescingAssignment(..) => precedence::ASSIGNMENT,
+            Conditional(..) => precedence::CONDITIONAL,
+            LogicalOr(..) => precedence::OR,
+            LogicalAnd(..) => precedence::AND,
+            Equality(..) | Inequality(..) => precedence::EQUALITY,
+            LessThan(..) | LessThanEq(..) | GreaterThan(..) | GreaterThanEq(..) | IsA(..) | Between { .. } => precedence::RELATIONAL,
+            Range { .. } => precedence::RANGE,
+            Addition(..) | Subtraction(..) => precedence::ADDITIVE,
+            Multiplication(..) | Division(..) | Remainder(..) => precedence::MULTIPLICATIVE,
+            Power(..) => precedence::POWER,
+            BitwiseXor(..) => precedence::XOR,
+            LogicalNot(_) | UnaryNegation(_) | Typeof(_) | New(_) | AddressOf(_) => precedence::UNARY,
+            MemberAccess(..) | Index(..) | FunctionCall(..) => precedence::POSTFIX,
+            // Folding can produce negative numbers, which are written with a
+            // leading `-` and so bind like negation: `(-2) ^ x`, `(-1).foo`.
+            NumberValue(n) if n.is_sign_negative() => precedence::UNARY,
+            StringValue(_) | NumberValue(_) | BoolValue(_) | NullValue | Variable(_) | ListLiteral(_) | MapLiteral(_)
+            | Function { .. } => precedence::ATOM,
+        }
+    }
+}
+
+/// Writes an identifier, backtick-escaping it when it is a keyword or
+/// would not lex as a single identifier.
+pub(crate) fn write_name(f: &mut impl Write, name: &str) -> FormatResult {
+    let plain = match crate::lexer::parse(name) {
+        Ok(tokens) => tokens.len() == 1 && tokens[0].kind == TokenKind::Identifier(name.to_string()) && !name.starts_with('`'),
+        Err(_) => false,
+    };
+    if plain {
+        write!(f, "{}", name)
+    } else {
+        write!(f, "`{}`", name)
+    }
+}
+
+pub(crate) fn is_keyword_name(name: &str) -> bool {
+    match crate::lexer::parse(name) {
+        Ok(tokens) => tokens.len() == 1 && tokens[0].kind.is_keyword() && tokens[0].kind.to_string() == name,
+        Err(_) => false,
+    }
+}
+
+fn write_label(f: &mut Formatter<'_>, label: &Option<String>) -> FormatResult {
+    match label {
+        Some(label) => {
+            write!(f, " ")?;
+            write_name(f, label)
+        }
+        None => Ok(()),
+    }
+}
+
+/// Writes `statements` one per line, indented by two spaces.
+pub(crate) fn write_block(f: &mut impl Write, statements: &[Statement]) -> FormatResult {
+    for statement in statements {
+        writeln!(f, "  {}", statement.to_string().replace('\n', "\n  "))?;
+    }
+    Ok(())
+}
+
+/// Renders MiniScript source that parses back to the same expression, as
+/// `format_expression` does with the default options.
+impl Display for Expression {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FormatResult {
+        f.write_str(&format_expression(self, &FormatOptions::default()))
+    }
+}
+
+impl Display for Statement {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FormatResult {
+        match self {
+            // A bare conditional would read back as an `if` statement.
+            Statement::Expression(expr @ Conditional(..)) => write!(f, "({})", expr),
+            Statement::Expression(expr) => write!(f, "{}", expr),
+            Statement::If { branches, else_branch } => {
+                for (i, (cond, body)) in branches.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "else ")?;
+                    }
+                    writeln!(f, "if {} then", cond)?;
+                    write_block(f, body)?;
+                }
+                if let Some(body) = else_branch {
+                    writeln!(f, "else")?;
+                    write_block(f, body)?;
+                }
+                write!(f, "end if")
+            }
+            Statement::While { condition, body } => {
+                writeln!(f, "while {}", condition)?;
+                write_block(f, body)?;
+                write!(f, "end while")
+            }
+            Statement::For { var, iterable, body } => {
+                write!(f, "for ")?;
+                write_name(f, var)?;
+                writeln!(f, " in {}", iterable)?;
+                write_block(f, body)?;
+                write!(f, "end for")
+            }
+            Statement::Return(Some(value)) => write!(f, "return {}", value),
+            Statement::Return(None) => write!(f, "return"),
+            Statement::Break(label) => {
+                write!(f, "break")?;
+                write_label(f, label)
+            }
+            Statement::Continue(label) => {
+                write!(f, "continue")?;
+                write_label(f, label)
+            }
+            Statement::Labeled(label, statement) => {
+                write_name(f, label)?;
+                write!(f, ": {}", statement)
+            }
+            Statement::Defer(statement) => write!(f, "defer {}", statement),
+        }
+    }
+}
+
+impl Debug for Expression {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FormatResult {
+        match self {
+            StringValue(s) => write!(f, "{:?}", s),
+            NumberValue(n) => write!(f, "{}", n),
+            BoolValue(b) => write!(f, "{}", b),
+            NullValue => write!(f, "null"),
+            Variable(s) => write!(f, "var({})", s),
+            MemberAccess(left, right) => write!(f, "access({:?}, {:?})", left, right),
+            Index(left, right) => write!(f, "index({:?}, {:?})", left, right),
+            FunctionCall(left, right) => write!(f, "call({:?}, {:?})", left, right),
+            ListLiteral(items) => write!(f, "list({:?})", items),
+            MapLiteral(entries) => write!(f, "map({:?})", entries),
+            LogicalNot(left) => write!(f, "not({:?})", left),
+            UnaryNegation(left) => write!(f, "minus({:?})", left),
+            Typeof(left) => write!(f, "type({:?})", left),
+            New(left) => write!(f, "new({:?})", left),
+            AddressOf(left) => write!(f, "ref({:?})", left),
+            Multiplication(left, right) => write!(f, "mul({:?}, {:?})", left, right),
+            Division(left, right) => write!(f, "div({:?}, {:?})", left, right),
+            Remainder(left, right) => write!(f, "rem({:?}, {:?})", left, right),
+            Power(left, right) => write!(f, "pow({:?}, {:?})", left, right),
+            BitwiseXor(left, right) => write!(f, "xor({:?}, {:?})", left, right),
+            Addition(left, right) => write!(f, "add({:?}, {:?})", left, right),
+            Subtraction(left, right) => write!(f, "sub({:?}, {:?})", left, right),
+            LessThan(left, right) => write!(f, "lt({:?}, {:?})", left, right),
+            LessThanEq(left, right) => write!(f, "le({:?}, {:?})", left, right),
+            GreaterThan(left, right) => write!(f, "gt({:?}, {:?})", left, right),
+            GreaterThanEq(left, right) => write!(f, "ge({:?}, {:?})", left, right),
+            IsA(left, right) => write!(f, "isa({:?}, {:?})", left, right),
+            Equality(left, right) => write!(f, "eq({:?}, {:?})", left, right),
+            Inequality(left, right) => write!(f, "nq({:?}, {:?})", left, right),
+            LogicalAnd(left, right) => write!(f, "and({:?}, {:?})", left, right),
+            LogicalOr(left, right) => write!(f, "or({:?}, {:?})", left, right),
+            Assignment(left, right) => write!(f, "asin({:?}, {:?})", left, right),
+            NullCoalescingAssignment(left, right) => write!(f, "asin_null({:?}, {:?})", left, right),
+            Conditional(cond, then, otherwise) => write!(f, "cond({:?}, {:?}, {:?})", cond, then, otherwise),
+            Range { start, end, step: Some(step) } => write!(f, "range({:?}, {:?}, {:?})", start, end, step),
+            Range { start, end, step: None } => write!(f, "range({:?}, {:?})", start, end),
+            Between { low, value, high, low_inclusive, high_inclusive } => write!(
+                f,
+                "between({:?}, {}, {:?}, {}, {:?})",
+                low,
+                if *low_inclusive { "<=" } else { "<" },
+                value,
+                if *high_inclusive { "<=" } else { "<" },
+                high
+            ),
+            Function { params, body } => {
+                write!(f, "func([")?;
+                for (i, (name, default)) in params.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    match default {
+                        Some(default) => write!(f, "{}={:?}", name, default)?,
+                        None => write!(f, "{}", name)?,
+                    }
+                }
+                write!(f, "], {:?})", body)
+            }
+        }
+    }
+}
+
+impl Debug for Statement {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FormatResult {
+        match self {
+            Statement::Expression(expr) => write!(f, "{:?}", expr),
+            Statement::If { branches, else_branch } => {
+                write!(f, "if(")?;
+                for (i, (cond, body)) in branches.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{:?}, {:?}", cond, body)?;
+                }
+                if let Some(body) = else_branch {
+                    write!(f, ", else, {:?}", body)?;
+                }
+                write!(f, ")")
+            }
+            Statement::While { condition, body } => write!(f, "while({:?}, {:?})", condition, body),
+            Statement::For { var, iterable, body } => write!(f, "for({}, {:?}, {:?})", var, iterable, body),
+            Statement::Return(Some(value)) => write!(f, "return({:?})", value),
+            Statement::Return(None) => write!(f, "return"),
+            Statement::Break(None) => write!(f, "break"),
+            Statement::Break(Some(label)) => write!(f, "break({})", label),
+            Statement::Continue(None) => write!(f, "continue"),
+            Statement::Continue(Some(label)) => write!(f, "continue({})", label),
+            Statement::Labeled(label, statement) => write!(f, "label({}, {:?})", label, statement),
+            Statement::Defer(statement) => write!(f, "defer({:?})", statement),
+        }
+    }
+}
+
+/// Renders the tokens within `radius` of index `around` by their spellings,
+/// marking the focus token as `>>tok<<`.
+pub fn context_window(tokens: &[Token], around: usize, radius: usize) -> String {
+    let start = around.saturating_sub(radius);
+    let end = around.saturating_add(radius + 1).min(tokens.len());
+    tokens
+        .get(start..end)
+        .unwrap_or_default()
+        .iter()
+        .enumerate()
+        .map(|(i, token)| {
+            if start + i == around {
+                format!(">>{}<<", token.kind)
+            } else {
+                token.kind.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Associativity {
+    Left,
+    Right,
+    /// The operator cannot follow itself without parentheses.
+    NonAssociative,
+}
+
+/// A row of `Parser::binary_operator`: how tightly the operator binds (a
+/// `precedence` level), how it groups, and the node it builds.
+struct BinaryOperator {
+    level: u8,
+    associativity: Associativity,
+    build: fn(ExprPtr, ExprPtr) -> Expression,
+}
+
+fn is_statement_end(token: &Token) -> bool {
+    matches!(token.kind, TokenKind::SemiColon | TokenKind::Newline)
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ParseOptions {
+    /// Parses `a < b < c` (with any mix of `<` and `<=`) as a single
+    /// `Between` node that evaluates `b` once. Without it the chain parses
+    /// as `(a < b) < c`, which fails at runtime since it compares a boolean
+    /// with a number.
+    pub chained_comparisons: bool,
+    /// Parses `^` as right-associative exponentiation, binding tighter than
+    /// unary minus as in math (`-2 ^ 2` is -4). By default `^` is bitwise
+    /// XOR of the operands truncated to integers, binding looser than `==`
+    /// as in C. The two readings give different results for the same source,
+    /// so scripts must be run with the option they were written for.
+    pub caret_is_power: bool,
+    /// How deeply expressions, prefix operators and statements may nest
+    /// before parsing fails with `NestingTooDeep`; `None` means
+    /// `DEFAULT_MAX_DEPTH`. Each `.`, `[]` or `()` suffix in a chain and
+    /// each `^` as power counts as one more level too, since evaluating them
+    /// recurses just like parentheses do. Left-associative operators do not:
+    /// `1 + 1 + 1` is one level deep, and such chains are only limited by
+    /// `MAX_TREE_DEPTH`, which caps all nesting, this kind included. Each level takes stack space when parsing and
+    /// evaluating, so a much higher limit can overflow the stack on deeply
+    /// nested input instead.
+    pub max_depth: Option<usize>,
+}
+
+/// The default `ParseOptions::max_depth`, far deeper than hand-written code
+/// nests, and shallow enough for a 2 MiB thread stack in debug builds.
+pub const DEFAULT_MAX_DEPTH: usize = 100;
+
+/// How deep the tree itself may get, counting every operator and suffix
+/// along a path. Flat chains such as long sums or string concatenations
+/// build trees this deep; the evaluator loops over them, but cloning,
+/// formatting and dropping still recurse, which at this depth fits the
+/// 8 MiB main thread stack of a debug build.
+pub const MAX_TREE_DEPTH: usize = 1000;
+
+/// A cursor over a token slice. Parsing never consumes the slice, so the
+/// same tokens can be parsed again or inspected afterwards.
+pub struct Parser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+    options: ParseOptions,
+    /// Labels of the loops enclosing the cursor, innermost last.
+    labels: Vec<String>,
+    /// How many brackets enclose the cursor. Inside any, newlines do not end
+    /// statements and are skipped, so lists, maps, arguments and
+    /// parenthesized expressions can span lines.
+    bracket_depth: usize,
+    /// How many levels of nesting enclose the cursor; see `nested` and
+    /// `deepen`.
+    depth: usize,
+    /// How deep the tree being built is at the cursor; see `lengthen`.
+    tree_depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    pub fn new(tokens: &'a [Token]) -> Self {
+        Self::with_options(tokens, ParseOptions::default())
+    }
+
+    pub fn with_options(tokens: &'a [Token], options: ParseOptions) -> Self {
+        Self { tokens, pos: 0, options, labels: Vec::new(), bracket_depth: 0, depth: 0, tree_depth: 0 }
+    }
+
+    /// Runs `parse` one nesting level deeper. Past `ParseOptions::max_depth`
+    /// it fails with `NestingTooDeep` rather than overflow the stack.
+    fn nested<T>(&mut self, parse: impl FnOnce(&mut Self) -> Result<T, ParseError>) -> Result<T, ParseError> {
+        self.restoring_depth(|parser| {
+            parser.deepen()?;
+            parse(parser)
+        })
+    }
+
+    /// Runs `parse`, then drops any levels it added with `deepen` or
+    /// `lengthen`.
+    fn restoring_depth<T>(&mut self, parse: impl FnOnce(&mut Self) -> Result<T, ParseError>) -> Result<T, ParseError> {
+        let (depth, tree_depth) = (self.depth, self.tree_depth);
+        let result = parse(self);
+        (self.depth, self.tree_depth) = (depth, tree_depth);
+        result
+    }
+
+    /// Counts one more level of nesting, failing with `NestingTooDeep` past
+    /// `ParseOptions::max_depth`.
+    fn deepen(&mut self) -> Result<(), ParseError> {
+        let max_depth = self.options.max_depth.unwrap_or(DEFAULT_MAX_DEPTH);
+        if self.depth >= max_depth {
+            return Err(self.nesting_too_deep(max_depth));
+        }
+        self.depth += 1;
+        self.lengthen()
+    }
+
+    /// Counts one more level of the tree that the parser builds in a loop
+    /// rather than by recursing, failing with `NestingTooDeep` past
+    /// `MAX_TREE_DEPTH`.
+    fn lengthen(&mut self) -> Result<(), ParseError> {
+        if self.tree_depth >= MAX_TREE_DEPTH {
+            return Err(self.nesting_too_deep(MAX_TREE_DEPTH));
+        }
+        self.tree_depth += 1;
+        Ok(())
+    }
+
+    fn nesting_too_deep(&self, limit: usize) -> ParseError {
+        let message = format!("Nesting exceeds the limit of {}", limit);
+        let mut error = match self.peek() {
+            Some(token) => ParseError::at(token, message),
+            None => self.error_at_end(message),
+        };
+        error.kind = ParseErrorKind::NestingTooDeep;
+        error
+    }
+
+    /// Index of the next significant token, skipping newlines in brackets.
+    fn peek_index(&self) -> usize {
+        let mut index = self.pos;
+        if self.bracket_depth > 0 {
+            while self.tokens.get(index).is_some_and(|token| token.kind == TokenKind::Newline) {
+                index += 1;
+            }
+        }
+        index
+    }
+
+    fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.peek_index())
+    }
+
+    fn advance(&mut self) -> Option<&'a Token> {
+        let index = self.peek_index();
+        let token = self.tokens.get(index);
+        if token.is_some() {
+            self.pos = index + 1;
+        }
+        token
+    }
+
+    /// Runs `parse` with the cursor inside one more level of brackets.
+    fn bracketed<T>(&mut self, parse: impl FnOnce(&mut Self) -> Result<T, ParseError>) -> Result<T, ParseError> {
+        self.bracket_depth += 1;
+        let result = parse(self);
+        self.bracket_depth -= 1;
+        result
+    }
+
+    fn peek_kind(&self) -> Option<&'a TokenKind> {
+        self.peek().map(|token| &token.kind)
+    }
+
+    fn check(&self, kind: &TokenKind) -> bool {
+        self.peek_kind() == Some(kind)
+    }
+
+    /// Error positioned just past the last token.
+    fn error_at_end(&self, message: String) -> ParseError {
+        let (line, column) = self
+            .tokens
+            .last()
+            .map(|token| (token.line, token.column + token.kind.get_str_len()))
+            .unwrap_or((1, 1));
+        ParseError { kind: ParseErrorKind::Syntax, message, line, column }
+    }
+
+    /// Error for the token under the cursor, showing the surrounding tokens.
+    /// All "Expected ... but found ..." messages come from here.
+    fn unexpected(&self, expected: &str) -> ParseError {
+        match self.peek() {
+            Some(token) => ParseError::at(
+                token,
+                format!(
+                    "Expected {} but found '{}' (near: {})",
+                    expected,
+                    token.kind,
+                    context_window(self.tokens, self.peek_index(), 3)
+                ),
+            ),
+            None => self.error_at_end(format!("Expected {} but found end of input", expected)),
+        }
+    }
+
+    fn expect(&mut self, kind: TokenKind, spelling: &str) -> Result<&'a Token, ParseError> {
+        match self.peek() {
+            Some(token) if token.kind == kind => {
+                self.advance();
+                Ok(token)
+            }
+            _ => Err(self.unexpected(&format!("'{}'", spelling))),
+        }
+    }
+
+    /// Consumes `end` followed by `keyword`, which closes a block. A
+    /// terminator for a different kind of block, as in `while ... end for`,
+    /// is reported as `MismatchedEnd`.
+    fn expect_end(&mut self, keyword: TokenKind) -> Result<(), ParseError> {
+        let expected = format!("end {}", keyword);
+        let end = self.expect(TokenKind::EndKeyword, &expected)?;
+        match self.peek_kind() {
+            Some(kind) if *kind == keyword => {
+                self.advance();
+                Ok(())
+            }
+            Some(
+                kind @ (TokenKind::IfKeyword
+                | TokenKind::WhileKeyword
+                | TokenKind::ForKeyword
+                | TokenKind::FunctionKeyword),
+            ) => Err(ParseError::mismatched_end(end, expected, format!("end {}", kind))),
+            _ => Err(self.unexpected(&format!("'{}'", expected))),
+        }
+    }
+
+    fn expect_identifier(&mut self, expected: &str) -> Result<String, ParseError> {
+        match self.peek() {
+            Some(Token { kind: TokenKind::Identifier(name), .. }) => {
+                self.advance();
+                Ok(name.clone())
+            }
+            _ => Err(self.unexpected(expected)),
+        }
+    }
+
+    /// Consumes a binary or prefix operator and parses its operand, reporting
+    /// the operator's position when the operand is missing altogether.
+    fn parse_operand(&mut self, parse: impl FnOnce(&mut Self) -> Result<Expression, ParseError>) -> Result<ExprPtr, ParseError> {
+        let operator = self.advance().expect("operator token was peeked");
+        if self.peek().map(is_statement_end).unwrap_or(true) {
+            let found = match self.peek() {
+                Some(token) => format!("'{}'", token.kind),
+                None => "end of input".to_string(),
+            };
+            return Err(ParseError::at(
+                operator,
+                format!("Expected expression after '{}' but found {}", operator.kind, found),
+            ));
+        }
+        Ok(parse(self)?.boxing())
+    }
+
+    fn parse_value_expr(&mut self) -> Result<Expression, ParseError> {
+        let Some(token) = self.peek() else {
+            return Err(self.unexpected("primary"));
+        };
+        let expr = match &token.kind {
+            TokenKind::StrLiteral(value) => StringValue(unescape_str_literal(value)),
+            TokenKind::NumLiteral(value) => NumberValue(value.value),
+            TokenKind::TrueKeyword => BoolValue(true),
+            TokenKind::FalseKeyword => BoolValue(false),
+            TokenKind::NullKeyword => NullValue,
+            TokenKind::Identifier(value) => Variable(value.clone()),
+            TokenKind::FunctionKeyword => {
+                self.advance();
+                return self.parse_function();
+            }
+            TokenKind::LeftBracket => {
+                self.advance();
+                return Ok(ListLiteral(self.parse_items(TokenKind::RightBracket, "]", Self::parse_assignment)?));
+            }
+            TokenKind::LeftCurly => {
+                self.advance();
+                return Ok(MapLiteral(self.parse_items(TokenKind::RightCurly, "}", Self::parse_map_entry)?));
+            }
+            TokenKind::LeftParen => {
+                self.advance();
+                return self.bracketed(|parser| {
+                    let expr = parser.parse_assignment()?;
+                    parser.expect(TokenKind::RightParen, ")")?;
+                    Ok(expr)
+                });
+            }
+            _ => return Err(self.unexpected("primary")),
+        };
+        self.advance();
+        Ok(expr)
+    }
+
+    /// Parses the rest of a `function(params) ... end function` literal after
+    /// the keyword. Parameters may carry default values: `function(a, b=2)`.
+    fn parse_function(&mut self) -> Result<Expression, ParseError> {
+        self.expect(TokenKind::LeftParen, "(")?;
+        let mut params = Vec::new();
+        if self.check(&TokenKind::RightParen) {
+            self.advance();
+        } else {
+            loop {
+                let token = self.peek();
+                let name = self.expect_identifier("parameter name")?;
+                if params.iter().any(|(param, _)| *param == name) {
+                    return Err(ParseError::duplicate_parameter(token.expect("parameter name was read"), name));
+                }
+                let mut default = None;
+                if self.check(&TokenKind::SingleEqual) {
+                    self.advance();
+                    default = Some(self.nested(Self::parse_conditional)?);
+                }
+                params.push((name, default));
+                match self.peek_kind() {
+                    Some(TokenKind::Comma) => self.advance(),
+                    Some(TokenKind::RightParen) => {
+                        self.advance();
+                        break;
+                    }
+                    _ => return Err(self.unexpected("',' or ')'")),
+                };
+            }
+        }
+        // The body is made of statements again, even inside brackets.
+        let outer_labels = std::mem::take(&mut self.labels);
+        let outer_depth = std::mem::replace(&mut self.bracket_depth, 0);
+        let body = self.parse_block("end function");
+        self.labels = outer_labels;
+        self.bracket_depth = outer_depth;
+        let body = body?;
+        self.expect_end(TokenKind::FunctionKeyword)?;
+        Ok(Function { params, body })
+    }
+
+    /// Parses the right side of `.`, where keywords are contextual: `a.typeof`
+    /// and `a.if` name members rather than starting an operator or statement.
+    fn parse_member_name(&mut self) -> Result<Expression, ParseError> {
+        match self.peek() {
+            Some(token) if token.kind.is_keyword() => {
+                self.advance();
+                Ok(Variable(token.kind.to_string()))
+            }
+            _ => self.parse_value_expr(),
+        }
+    }
+
+    /// Parses comma-separated items up to and including `closing`. One
+    /// trailing comma is allowed, but no empty slots.
+    fn parse_items<T>(
+        &mut self,
+        closing: TokenKind,
+        spelling: &str,
+        parse_item: fn(&mut Self) -> Result<T, ParseError>,
+    ) -> Result<Vec<T>, ParseError> {
+        self.bracketed(|parser| {
+            let mut items = Vec::new();
+            while !parser.check(&closing) {
+                items.push(parse_item(parser)?);
+                match parser.peek() {
+                    Some(token) if token.kind == TokenKind::Comma => {
+                        parser.advance();
+                    }
+                    Some(token) if token.kind == closing => (),
+                    _ => return Err(parser.unexpected(&format!("',' or '{}'", spelling))),
+                }
+            }
+            parser.advance();
+            Ok(items)
+        })
+    }
+
+    fn parse_argument(&mut self) -> Result<Expression, ParseError> {
+        if self.check(&TokenKind::Comma) {
+            return Err(self.unexpected("argument"));
+        }
+        self.parse_assignment()
+    }
+
+    fn parse_map_entry(&mut self) -> Result<(Expression, Expression), ParseError> {
+        self.nested(|parser| {
+            let key = parser.parse_conditional()?;
+            parser.expect(TokenKind::Colon, ":")?;
+            Ok((key, parser.parse_conditional()?))
+        })
+    }
+
+    /// Parses a value and the `.`, `[]` and `()` suffixes after it. Each
+    /// suffix nests the tree one level deeper, so each counts against
+    /// `ParseOptions::max_depth` until the chain ends.
+    fn parse_primary(&mut self) -> Result<Expression, ParseError> {
+        self.restoring_depth(Self::parse_postfix_chain)
+    }
+
+    fn parse_postfix_chain(&mut self) -> Result<Expression, ParseError> {
+        let mut expr = self.parse_value_expr()?;
+        while let Some(token) = self.peek() {
+            if matches!(token.kind, TokenKind::Dot | TokenKind::LeftBracket | TokenKind::LeftParen) {
+                self.deepen()?;
+            }
+            match token.kind {
+                TokenKind::Dot => {
+                    self.advance();
+                    expr = MemberAccess(expr.boxing(), self.parse_member_name()?.boxing());
+                }
+                TokenKind::LeftBracket => {
+                    self.advance();
+                    let index = self.bracketed(|parser| {
+                        let index = parser.parse_assignment()?;
+                        parser.expect(TokenKind::RightBracket, "]")?;
+                        Ok(index)
+                    })?;
+                    expr = Index(expr.boxing(), index.boxing());
+                }
+                TokenKind::LeftParen => {
+                    self.advance();
+                    let args = self.parse_items(TokenKind::RightParen, ")", Self::parse_argument)?;
+                    expr = FunctionCall(expr.boxing(), args);
+                }
+                _ => break,
+            }
+        }
+        Ok(expr)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expression, ParseError> {
+        if let Some(token) = self.peek() {
+            match token.kind {
+                TokenKind::Exclamation | TokenKind::NotKeyword => {
+                    return Ok(LogicalNot(self.parse_operand(Self::parse_unary_operand)?));
+                }
+                TokenKind::TypeofKeyword => {
+                    return Ok(Typeof(self.parse_operand(Self::parse_unary_operand)?));
+                }
+                TokenKind::Minus => {
+                    return Ok(UnaryNegation(self.parse_operand(Self::parse_unary_operand)?));
+                }
+                TokenKind::NewKeyword => {
+                    return Ok(New(self.parse_operand(Self::parse_primary)?));
+                }
+                TokenKind::At => {
+                    return Ok(AddressOf(self.parse_operand(Self::parse_primary)?));
+                }
+                _ => (),
+            }
+        }
+        self.parse_primary()
+    }
+
+    /// The operand of a prefix operator, which takes in only the operators
+    /// binding tighter than it (`^` as power).
+    fn parse_unary_operand(&mut self) -> Result<Expression, ParseError> {
+        self.nested(|parser| parser.parse_binary(precedence::UNARY))
+    }
+
+    /// The binary operator table. Adding an operator means adding its row
+    /// here; `to` and chained `<`/`<=` get extra handling in `parse_binary`.
+    fn binary_operator(&self, kind: &TokenKind) -> Option<BinaryOperator> {
+        use Associativity::*;
+        let (level, associativity, build): (u8, Associativity, fn(ExprPtr, ExprPtr) -> Expression) = match kind {
+            TokenKind::DoublePipe | TokenKind::OrKeyword => (precedence::OR, Left, LogicalOr),
+            TokenKind::DoubleAnd | TokenKind::AndKeyword => (precedence::AND, Left, LogicalAnd),
+            TokenKind::Caret if self.options.caret_is_power => (precedence::POWER, Right, Power),
+            TokenKind::Caret => (precedence::XOR, Left, BitwiseXor),
+            TokenKind::DoubleEqual => (precedence::EQUALITY, Left, Equality),
+            TokenKind::ExclEqual => (precedence::EQUALITY, Left, Inequality),
+            TokenKind::LessThan => (precedence::RELATIONAL, Left, LessThan),
+            TokenKind::LessThanEq => (precedence::RELATIONAL, Left, LessThanEq),
+            TokenKind::GreaterThan => (precedence::RELATIONAL, Left, GreaterThan),
+            TokenKind::GreaterThanEq => (precedence::RELATIONAL, Left, GreaterThanEq),
+            TokenKind::IsaKeyword => (precedence::RELATIONAL, Left, IsA),
+            TokenKind::ToKeyword => (precedence::RANGE, NonAssociative, |start, end| Range { start, end, step: None }),
+            TokenKind::Plus => (precedence::ADDITIVE, Left, Addition),
+            TokenKind::Minus => (precedence::ADDITIVE, Left, Subtraction),
+            TokenKind::Asterisk => (precedence::MULTIPLICATIVE, Left, Multiplication),
+            TokenKind::Slash => (precedence::MULTIPLICATIVE, Left, Division),
+            TokenKind::Percent => (precedence::MULTIPLICATIVE, Left, Remainder),
+            _ => return None,
+        };
+        Some(BinaryOperator { level, associativity, build })
+    }
+
+    /// Precedence climbing over `binary_operator`: parses a unary operand,
+    /// then takes in every following operator whose level is at least
+    /// `min_level`. The right operand of a left-associative operator only
+    /// takes in tighter operators, so `a - b - c` is `(a - b) - c`.
+    ///
+    /// Comparisons are left-associative too: `1 < 2 < 3` is `(1 < 2) < 3`,
+    /// not Python's `1 < 2 and 2 < 3`. Only `<`/`<=` chains become
+    /// `Between`, and only with `ParseOptions::chained_comparisons`.
+    ///
+    /// Each operator taken in nests the tree one level deeper. For `^` as
+    /// power that is on the right, where parsing and evaluating recurse, so
+    /// it counts against `ParseOptions::max_depth` like a pair of
+    /// parentheses would. Left-associative operators nest on the left, which
+    /// is built here and evaluated in loops, so they only count towards
+    /// `MAX_TREE_DEPTH`.
+    fn parse_binary(&mut self, min_level: u8) -> Result<Expression, ParseError> {
+        self.restoring_depth(|parser| parser.parse_binary_chain(min_level))
+    }
+
+    fn parse_binary_chain(&mut self, min_level: u8) -> Result<Expression, ParseError> {
+        let mut left = self.parse_unary()?;
+        // Set after a non-associative operator, which nothing of its own
+        // level may follow: `1 to 2 to 3` stops after `1 to 2`.
+        let mut max_level = u8::MAX;
+        // Whether `left` is a `<`/`<=` built by this loop, which a following
+        // `<`/`<=` may extend into a `Between`.
+        let mut chainable = false;
+        while let Some(token) = self.peek() {
+            let Some(operator) = self.binary_operator(&token.kind) else {
+                break;
+            };
+            if operator.level < min_level || operator.level >= max_level {
+                break;
+            }
+            if operator.associativity == Associativity::Right {
+                self.deepen()?;
+            } else {
+                self.lengthen()?;
+            }
+            let right_level = match operator.associativity {
+                Associativity::Right => operator.level,
+                Associativity::Left | Associativity::NonAssociative => operator.level + 1,
+            };
+            let right = self.parse_operand(|parser| parser.parse_binary(right_level))?;
+            let less_than = matches!(token.kind, TokenKind::LessThan | TokenKind::LessThanEq);
+            let high_inclusive = token.kind == TokenKind::LessThanEq;
+            left = match left {
+                LessThan(low, value) if chainable && less_than => {
+                    Between { low, value, high: right, low_inclusive: false, high_inclusive }
+                }
+                LessThanEq(low, value) if chainable && less_than => {
+                    Between { low, value, high: right, low_inclusive: true, high_inclusive }
+                }
+                left => (operator.build)(left.boxing(), right),
+            };
+            if let Range { step, .. } = &mut left {
+                if self.check(&TokenKind::ByKeyword) {
+                    *step = Some(self.parse_operand(|parser| parser.parse_binary(right_level))?);
+                }
+            }
+            if operator.associativity == Associativity::NonAssociative {
+                max_level = operator.level;
+            }
+            chainable = self.options.chained_comparisons && less_than && matches!(left, LessThan(..) | LessThanEq(..));
+        }
+        Ok(left)
+    }
+
+    fn parse_conditional(&mut self) -> Result<Expression, ParseError> {
+        if self.check(&TokenKind::IfKeyword) {
+            self.advance();
+            let cond = self.parse_assignment()?;
+            self.expect(TokenKind::ThenKeyword, "then")?;
+            let then = self.parse_assignment()?;
+            self.expect(TokenKind::ElseKeyword, "else")?;
+            let otherwise = self.parse_assignment()?;
+            return Ok(Conditional(cond.boxing(), then.boxing(), otherwise.boxing()));
+        }
+        self.parse_binary(precedence::OR)
+    }
+
+    fn parse_assignment(&mut self) -> Result<Expression, ParseError> {
+        self.nested(|parser| {
+            let start = parser.peek_index();
+            let left = parser.parse_conditional()?;
+            if let Some(token) = parser.peek() {
+                if matches!(token.kind, TokenKind::SingleEqual | TokenKind::DoubleQuestionEqual)
+                    && !matches!(left, Variable(_) | MemberAccess(..) | Index(..))
+                {
+                    return Err(ParseError::invalid_assignment_target(&parser.tokens[start]));
+                }
+                match token.kind {
+                    TokenKind::SingleEqual => {
+                        return Ok(Assignment(left.boxing(), parser.parse_operand(Self::parse_assignment)?));
+                    }
+                    TokenKind::DoubleQuestionEqual => {
+                        return Ok(NullCoalescingAssignment(left.boxing(), parser.parse_operand(Self::parse_assignment)?));
+                    }
+                    _ => (),
+                }
+            }
+            Ok(left)
+        })
+    }
+
+    /// Parses one expression and stops after it, leaving whatever follows
+    /// for the caller. The free `parse_expression` requires the expression
+    /// to be the whole input.
+    pub fn parse_expression(&mut self) -> Result<Expression, ParseError> {
+        self.parse_assignment()
+    }
+
+    /// Fails unless only statement separators are left.
+    fn expect_end_of_input(&mut self) -> Result<(), ParseError> {
+        self.skip_statement_ends();
+        match self.peek() {
+            Some(_) => Err(self.unexpected("end of input")),
+            None => Ok(()),
+        }
+    }
+
+    fn skip_statement_ends(&mut self) {
+        while self.peek().map(is_statement_end).unwrap_or(false) {
+            self.advance();
+        }
+    }
+
+    /// A statement ends at a separator, at the end of input, or right before
+    /// the `else`/`end` closing the enclosing block.
+    fn at_statement_end(&self) -> bool {
+        match self.peek() {
+            Some(token) => {
+                is_statement_end(token) || matches!(token.kind, TokenKind::ElseKeyword | TokenKind::EndKeyword)
+            }
+            None => true,
+        }
+    }
+
+    fn expect_statement_end(&self) -> Result<(), ParseError> {
+        if self.at_statement_end() {
+            Ok(())
+        } else {
+            Err(self.unexpected("end of statement"))
+        }
+    }
+
+    /// Parses statements up to, but not including, the `else` or `end` that
+    /// closes the block.
+    fn parse_block(&mut self, closing: &str) -> Result<Vec<Statement>, ParseError> {
+        let mut statements = Vec::new();
+        loop {
+            self.skip_statement_ends();
+            match self.peek() {
+                None => return Err(self.unexpected(&format!("'{}'", closing))),
+                Some(token) if matches!(token.kind, TokenKind::ElseKeyword | TokenKind::EndKeyword) => {
+                    return Ok(statements)
+                }
+                Some(_) => statements.push(self.parse_statement()?),
+            }
+        }
+    }
+
+    /// Parses the rest of an `if` statement after the keyword: either the
+    /// single-line `if c then stmt [else stmt]` or the block form closed by
+    /// `end if`.
+    fn parse_if(&mut self) -> Result<Statement, ParseError> {
+        let mut branches = Vec::new();
+        let condition = self.parse_expression()?;
+        self.expect(TokenKind::ThenKeyword, "then")?;
+        if !self.peek().map(is_statement_end).unwrap_or(true) {
+            branches.push((condition, vec![self.parse_statement()?]));
+            let mut else_branch = None;
+            if self.check(&TokenKind::ElseKeyword) {
+                self.advance();
+                else_branch = Some(vec![self.parse_statement()?]);
+            }
+            return Ok(Statement::If { branches, else_branch });
+        }
+        branches.push((condition, self.parse_block("end if")?));
+        let mut else_branch = None;
+        while self.check(&TokenKind::ElseKeyword) {
+            self.advance();
+            if self.check(&TokenKind::IfKeyword) {
+                self.advance();
+                let condition = self.parse_expression()?;
+                self.expect(TokenKind::ThenKeyword, "then")?;
+                branches.push((condition, self.parse_block("end if")?));
+            } else {
+                else_branch = Some(self.parse_block("end if")?);
+                break;
+            }
+        }
+        self.expect_end(TokenKind::IfKeyword)?;
+        Ok(Statement::If { branches, else_branch })
+    }
+
+    /// Parses the rest of a `while cond ... end while` loop after the keyword.
+    fn parse_while(&mut self) -> Result<Statement, ParseError> {
+        let condition = self.parse_expression()?;
+        let body = self.parse_block("end while")?;
+        self.expect_end(TokenKind::WhileKeyword)?;
+        Ok(Statement::While { condition, body })
+    }
+
+    /// Parses the rest of a `for var in seq ... end for` loop after the keyword.
+    fn parse_for(&mut self) -> Result<Statement, ParseError> {
+        let var = self.expect_identifier("loop variable")?;
+        self.expect(TokenKind::InKeyword, "in")?;
+        let iterable = self.parse_expression()?;
+        let body = self.parse_block("end for")?;
+        self.expect_end(TokenKind::ForKeyword)?;
+        Ok(Statement::For { var, iterable, body })
+    }
+
+    /// Parses the loop after `label:`, with the label in scope for its body.
+    fn parse_labeled(&mut self, label: String) -> Result<Statement, ParseError> {
+        let parse_loop: fn(&mut Self) -> Result<Statement, ParseError> = match self.peek_kind() {
+            Some(TokenKind::WhileKeyword) => Self::parse_while,
+            Some(TokenKind::ForKeyword) => Self::parse_for,
+            _ => return Err(self.unexpected("'while' or 'for' after label")),
+        };
+        self.advance();
+        self.labels.push(label.clone());
+        let statement = parse_loop(self);
+        self.labels.pop();
+        Ok(Statement::Labeled(label, Box::new(statement?)))
+    }
+
+    /// Parses the optional label after `break` or `continue`, which must name
+    /// an enclosing loop.
+    fn parse_jump_label(&mut self) -> Result<Option<String>, ParseError> {
+        match self.peek() {
+            Some(token @ Token { kind: TokenKind::Identifier(name), .. }) => {
+                if !self.labels.contains(name) {
+                    return Err(ParseError::undefined_label(token, name.clone()));
+                }
+                self.advance();
+                Ok(Some(name.clone()))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn parse_statement(&mut self) -> Result<Statement, ParseError> {
+        self.nested(|parser| {
+            let statement = match parser.peek_kind() {
+                Some(TokenKind::IfKeyword) => {
+                    parser.advance();
+                    parser.parse_if()?
+                }
+                Some(TokenKind::WhileKeyword) => {
+                    parser.advance();
+                    parser.parse_while()?
+                }
+                Some(TokenKind::ForKeyword) => {
+                    parser.advance();
+                    parser.parse_for()?
+                }
+                Some(TokenKind::ReturnKeyword) => {
+                    parser.advance();
+                    if parser.at_statement_end() {
+                        Statement::Return(None)
+                    } else {
+                        Statement::Return(Some(parser.parse_expression()?))
+                    }
+                }
+                Some(TokenKind::Identifier(label))
+                    if parser.tokens.get(parser.pos + 1).map(|token| token.kind == TokenKind::Colon).unwrap_or(false) =>
+                {
+                    parser.pos += 2;
+                    parser.parse_labeled(label.clone())?
+                }
+                Some(TokenKind::BreakKeyword) => {
+                    parser.advance();
+                    Statement::Break(parser.parse_jump_label()?)
+                }
+                Some(TokenKind::ContinueKeyword) => {
+                    parser.advance();
+                    Statement::Continue(parser.parse_jump_label()?)
+                }
+                Some(TokenKind::DeferKeyword) => {
+                    parser.advance();
+                    Statement::Defer(Box::new(parser.parse_statement()?))
+                }
+                _ => Statement::Expression(parser.parse_expression()?),
+            };
+            parser.expect_statement_end()?;
+            Ok(statement)
+        })
+    }
+
+    /// Parses a sequence of statements separated by `;` or newlines, skipping
+    /// empty statements.
+    pub fn parse_program(&mut self) -> Result<Vec<Statement>, ParseError> {
+        let mut statements = Vec::new();
+        loop {
+            self.skip_statement_ends();
+            if self.peek().is_none() {
+                return Ok(statements);
+            }
+            statements.push(self.parse_statement()?);
+        }
+    }
+}
+
+/// Parses `tokens` as a single expression. Tokens left after it, other than
+/// statement separators, are an error at the first of them, so `1 2` fails.
+pub fn parse_expression(tokens: &[Token]) -> Result<Expression, ParseError> {
+    let mut parser = Parser::new(tokens);
+    let expr = parser.parse_expression()?;
+    parser.expect_end_of_input()?;
+    Ok(expr)
+}
+
+pub fn parse_program(tokens: &[Token]) -> Result<Vec<Statement>, ParseError> {
+    Parser::new(tokens).parse_program()
+}
+
+/// Lexes and parses `source` as a single expression, which must make up the
+/// whole input.
+pub fn parse_str(source: &str) -> Result<Expression, ParseError> {
+    parse_expression(&lexer::lex(source)?)
+}
+
+impl FromStr for Expression {
+    type Err = ParseError;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        parse_str(source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program(source: &str) -> Vec<Statement> {
+        parse_program(&lexer::lex(source).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn defer_displays_as_source_and_debugs_as_tree() {
+        let statements = program("defer x = 1");
+        assert_eq!(statements[0].to_string(), "defer x = 1");
+        assert_eq!(format!("{:?}", statements[0]), "defer(asin(var(x), 1))");
+        assert_eq!(program(&statements[0].to_string()), statements);
+    }
+
+    fn nesting_error(source: &str, options: ParseOptions) -> Option<ParseErrorKind> {
+        let tokens = lexer::lex(source).unwrap();
+        Parser::with_options(&tokens, options).parse_program().err().map(|e| e.kind)
+    }
+
+    #[test]
+    fn every_recursive_form_counts_against_the_nesting_limit() {
+        let power = ParseOptions { caret_is_power: true, ..ParseOptions::default() };
+        let cases = [
+            "{1:".repeat(100_000),
+            "function(a=".repeat(100_000),
+            "[".repeat(100_000),
+            "2^".repeat(100_000) + "2",
+            "1+".repeat(100_000) + "1",
+            "a or ".repeat(100_000) + "b",
+            "x".to_string() + &"[0]".repeat(100_000),
+            "f".to_string() + &"()".repeat(100_000),
+            "a".to_string() + &".b".repeat(100_000),
+            "(".repeat(100_000),
+            "-".repeat(100_000) + "1",
+            "not ".repeat(100_000) + "x",
+            "defer ".repeat(100_000) + "x",
+            "(1+".repeat(100_000),
+        ];
+        for source in cases {
+            assert_eq!(nesting_error(&source, power.clone()), Some(ParseErrorKind::NestingTooDeep), "{}", &source[..20]);
+        }
+    }
+
+    #[test]
+    fn chains_within_the_limit_parse_and_evaluate() {
+        let sum = |terms: usize| vec!["1"; terms].join(" + ");
+        let run = |source: &str| crate::eval::run_program(source, &mut crate::eval::Environment::new()).map(|(value, _)| value);
+        // Flat chains do not count against `max_depth`, only the tree depth.
+        let shallow = ParseOptions { max_depth: Some(3), ..ParseOptions::default() };
+        assert_eq!(nesting_error(&sum(150), shallow.clone()), None);
+        assert_eq!(nesting_error("((1))", shallow), Some(ParseErrorKind::NestingTooDeep));
+        assert_eq!(run(&sum(150)).unwrap(), crate::eval::Value::Number(150.));
+        let concatenation = vec!["\"ab\""; 150].join(" + ");
+        assert_eq!(run(&concatenation).unwrap(), crate::eval::Value::Str("ab".repeat(150)));
+        assert_eq!(run(&vec!["false"; 150].join(" or ")).unwrap(), crate::eval::Value::Bool(false));
+        assert_eq!(nesting_error(&sum(MAX_TREE_DEPTH - 1), ParseOptions::default()), None);
+        assert_eq!(nesting_error(&sum(MAX_TREE_DEPTH + 2), ParseOptions::default()), Some(ParseErrorKind::NestingTooDeep));
+        let longest = parse_str(&sum(MAX_TREE_DEPTH - 1)).unwrap();
+        assert_eq!(parse_str(&longest.to_string()).unwrap(), longest);
+        assert_eq!(run(&sum(MAX_TREE_DEPTH - 1)).unwrap(), crate::eval::Value::Number((MAX_TREE_DEPTH - 1) as f64));
+        let nested = "(".repeat(DEFAULT_MAX_DEPTH - 2) + "1" + &")".repeat(DEFAULT_MAX_DEPTH - 2);
+        assert!(run(&nested).is_ok());
+    }
+
+    fn debug(source: &str) -> String {
+        format!("{:?}", parse_str(source).unwrap())
+    }
+
+    #[test]
+    fn isa_parses_at_relational_precedence() {
+        assert_eq!(debug("5 isa number"), "isa(5, var(number))");
+        assert_eq!(debug("x isa y"), "isa(var(x), var(y))");
+        assert_eq!(debug("a isa b == c"), "eq(isa(var(a), var(b)), var(c))");
+        assert_eq!(debug("a + 1 isa list"), "isa(add(var(a), 1), var(list))");
+    }
+
+    #[test]
+    fn at_takes_a_reference_to_a_primary() {
+        assert_eq!(debug("@f isa funcRef"), "isa(ref(var(f)), var(funcRef))");
+        assert_eq!(debug("@a.b(1)"), "ref(call(access(var(a), var(b)), [1]))");
+        assert_eq!(debug("g = @f"), "asin(var(g), ref(var(f)))");
+        for source in ["@f isa funcRef", "@a.b", "-@x", "[@f, @g]"] {
+            let expr = parse_str(source).unwrap();
+            assert_eq!(expr.to_string(), source);
+            assert_eq!(parse_str(&expr.to_string()).unwrap(), expr);
+        }
+        assert_eq!(parse_str("@").unwrap_err().message, "Expected expression after '@' but found end of input");
+    }
+
+    #[test]
+    fn if_then_else_parses_as_a_conditional_expression() {
+        assert_eq!(debug("if a then b else c"), "cond(var(a), var(b), var(c))");
+        assert_eq!(debug("x = if a then b else if c then d else e"), "asin(var(x), cond(var(a), var(b), cond(var(c), var(d), var(e))))");
+        assert_eq!(parse_str("if a then b else c").unwrap().to_string(), "if a then b else c");
+        assert!(parse_str("if a then b").is_err());
+    }
+
+    #[test]
+    fn context_window_marks_the_focus_token() {
+        let tokens: Vec<_> = lexer::lex("a = (1 + ) * 2 + 3").unwrap();
+        assert_eq!(context_window(&tokens, 5, 2), "1 + >>)<< * 2");
+        assert_eq!(context_window(&tokens, 0, 2), ">>a<< = (");
+        assert_eq!(context_window(&tokens, 9, 3), "* 2 + >>3<<");
+        assert_eq!(context_window(&tokens, 50, 3), "");
+        assert_eq!(parse_str("a = (1 + ) * 2 + 3").unwrap_err().message, "Expected primary but found ')' (near: ( 1 + >>)<< * 2 +)");
+    }
+
+    #[test]
+    fn new_applies_to_a_postfix_expression() {
+        assert_eq!(debug("new Foo"), "new(var(Foo))");
+        assert_eq!(debug("x = new a.b"), "asin(var(x), new(access(var(a), var(b))))");
+        assert_eq!(debug("-new a"), "minus(new(var(a)))");
+        assert_eq!(parse_str("new a.b").unwrap().to_string(), "new a.b");
+        assert_eq!(parse_str("new").unwrap_err().message, "Expected expression after 'new' but found end of input");
+    }
+
+    #[test]
+    fn parse_errors_carry_the_line_and_column() {
+        let err = parse_program(&lexer::lex("x = 1\ny = (2 +\n").unwrap()).unwrap_err();
+        assert_eq!((&err.kind, err.line, err.column), (&ParseErrorKind::Syntax, 2, 8));
+        assert_eq!(err.to_string(), "Expected expression after '+' but found end of input at 2:8");
+        let err = parse_str("1 + $").unwrap_err();
+        assert_eq!((err.kind, err.line, err.column), (ParseErrorKind::Lexer(LexerErrorKind::InvalidCharacter('$')), 1, 5));
+    }
+
+    #[test]
+    fn unclosed_parentheses_are_errors_not_panics() {
+        let err = parse_str("(1 + 2").unwrap_err();
+        assert_eq!((err.message.as_str(), err.line, err.column), ("Expected ')' but found end of input", 1, 7));
+        assert_eq!(parse_str("(1 2)").unwrap_err().message, "Expected ')' but found '2' (near: ( 1 >>2<< ))");
+        assert_eq!(parse_str("((").unwrap_err().message, "Expected primary but found end of input");
+    }
+
+    #[test]
+    fn programs_are_statements_separated_by_newlines_or_semicolons() {
+        let statements = program("x = 1\n\ny = x + 2; f(y)\n  // note\nreturn\n");
+        let debugs: Vec<_> = statements.iter().map(|statement| format!("{:?}", statement)).collect();
+        assert_eq!(debugs, ["asin(var(x), 1)", "asin(var(y), add(var(x), 2))", "call(var(f), [var(y)])", "return"]);
+        assert!(program("").is_empty());
+        let err = parse_program(&lexer::lex("x = 1 y = 2").unwrap()).unwrap_err();
+        assert_eq!((err.line, err.column), (1, 7));
+    }
+
+    #[test]
+    fn if_blocks_chain_else_if_branches() {
+        let source = "if a then\n  x = 1\nelse if b then\n  x = 2\nelse\n  x = 3\nend if";
+        let statements = program(source);
+        assert_eq!(format!("{:?}", statements[0]), "if(var(a), [asin(var(x), 1)], var(b), [asin(var(x), 2)], else, [asin(var(x), 3)])");
+        assert_eq!(statements[0].to_string(), source);
+        assert_eq!(format!("{:?}", program("if a then x = 1 else x = 2")[0]), "if(var(a), [asin(var(x), 1)], else, [asin(var(x), 2)])");
+        assert_eq!(parse_program(&lexer::lex("if a then\n x=1").unwrap()).unwrap_err().message, "Expected 'end if' but found end of input");
+    }
+
+    #[test]
+    fn while_loops_parse_up_to_end_while() {
+        let source = "while i < 3\n  i = i + 1\nend while";
+        assert_eq!(format!("{:?}", program(source)[0]), "while(lt(var(i), 3), [asin(var(i), add(var(i), 1))])");
+        assert_eq!(program(source)[0].to_string(), source);
+        let err = parse_program(&lexer::lex("while x\nend for").unwrap()).unwrap_err();
+        assert_eq!((err.message.as_str(), err.line), ("Expected 'end while' but found 'end for'", 2));
+    }
+
+    #[test]
+    fn for_loops_name_a_variable_and_an_iterable() {
+        let source = "for i in range(3)\n  s = s + i\nend for";
+        assert_eq!(format!("{:?}", program(source)[0]), "for(i, call(var(range), [3]), [asin(var(s), add(var(s), var(i)))])");
+        assert_eq!(program(source)[0].to_string(), source);
+        assert_eq!(parse_program(&lexer::lex("for 1 in x\nend for").unwrap()).unwrap_err().message, "Expected loop variable but found '1' (near: for >>1<< in x \\n)");
+    }
+
+    #[test]
+    fn function_literals_take_parameters_with_defaults() {
+        let source = "f = function(a, b=2)\n  return a\nend function";
+        assert_eq!(debug(source), "asin(var(f), func([a, b=2], [return(var(a))]))");
+        assert_eq!(parse_str(source).unwrap().to_string(), source);
+        assert_eq!(debug("function()\nend function"), "func([], [])");
+        assert!(parse_str("function(a\nend function").is_err());
+        assert!(parse_str("function(a)\n  x = 1").is_err());
+    }
+
+    #[test]
+    fn return_break_and_continue_parse_as_statements() {
+        let debugs: Vec<_> = program("return 1\nreturn\nwhile 1\n  break\n  continue\nend while").iter().map(|s| format!("{:?}", s)).collect();
+        assert_eq!(debugs, ["return(1)", "return", "while(1, [break, continue])"]);
+        assert!(parse_program(&lexer::lex("break 1").unwrap()).is_err());
+    }
+
+    #[test]
+    fn missing_operands_point_at_their_operator() {
+        for (source, operator, column) in [("1 +", "+", 3), ("x = 2 * ", "*", 7), ("a and", "and", 3), ("not", "not", 1)] {
+            let err = parse_str(source).unwrap_err();
+            assert_eq!(err.message, format!("Expected expression after '{}' but found end of input", operator));
+            assert_eq!((err.line, err.column), (1, column), "{}", source);
+        }
+    }
+
+    #[test]
+    fn the_parser_reads_tokens_without_consuming_them() {
+        let tokens = lexer::lex("1 + 2 3").unwrap();
+        let mut parser = Parser::new(&tokens);
+        assert_eq!(format!("{:?}", parser.parse_expression().unwrap()), "add(1, 2)");
+        assert_eq!(format!("{:?}", parser.parse_expression().unwrap()), "3");
+        assert!(parser.parse_expression().is_err());
+        assert_eq!(tokens.len(), 4);
+        assert_eq!(parse_program(&tokens).unwrap_err().column, 7);
+        assert_eq!(format!("{:?}", Parser::new(&tokens).parse_expression().unwrap()), "add(1, 2)");
+    }
+
+    #[test]
+    fn displayed_source_parses_back_to_the_same_tree() {
+        let sources = [
+            "(a + b) * c",
+            "a - (b - c)",
+            "-(-x)",
+            "not (a and b) or c",
+            "\"tab\\t \\\"q\\\"\"",
+            "`my var` + m.end",
+            "x = y = [1, {\"k\": f(2)}]",
+            "(a < b) < c",
+            "a < b <= c",
+        ];
+        for source in sources {
+            let expr = parse_str(source).unwrap();
+            assert_eq!(parse_str(&expr.to_string()).unwrap(), expr, "{}", source);
+        }
+        assert_eq!(parse_str("(a + b) * c").unwrap().to_string(), "(a + b) * c");
+        assert_eq!(parse_str("((a)) + (b * c)").unwrap().to_string(), "a + b * c");
+        let statements = program("for i in l\n  if i then\n    break\n  end if\nend for\nwhile 1\n  x = function(a=1)\n    return a\n  end function\nend while");
+        let text: Vec<_> = statements.iter().map(Statement::to_string).collect();
+        assert_eq!(program(&text.join("\n")), statements);
+    }
+
+    #[test]
+    fn chained_comparisons_parse_as_between_when_enabled() {
+        let chained = |source: &str| {
+            let tokens = lexer::lex(source).unwrap();
+            Parser::with_options(&tokens, ParseOptions { chained_comparisons: true, ..ParseOptions::default() }).parse_expression().unwrap()
+        };
+        assert_eq!(debug("a < b <= c"), "le(lt(var(a), var(b)), var(c))");
+        let between = chained("a < b <= c");
+        assert_eq!(format!("{:?}", between), "between(var(a), <, var(b), <=, var(c))");
+        assert_eq!(between.to_string(), "a < b <= c");
+        assert_eq!(format!("{:?}", chained("a < b < c < d")), "lt(between(var(a), <, var(b), <, var(c)), var(d))");
+        let mut env = crate::eval::Environment::new();
+        crate::eval::run_program("n = 0\nf = function()\n  globals.n = n + 1\n  return 5\nend function", &mut env).unwrap();
+        assert_eq!(crate::eval::eval_expression(&chained("1 < f() <= 5"), &mut env).unwrap(), crate::eval::Value::Bool(true));
+        assert_eq!(crate::eval::eval_expression(&chained("9 < f() < 10"), &mut env).unwrap(), crate::eval::Value::Bool(false));
+        assert_eq!(env.get("n"), Some(crate::eval::Value::Number(2.)));
+    }
+
+    #[test]
+    fn duplicate_parameters_are_rejected_at_the_second_name() {
+        let err = parse_str("function(a, b, a=1)\nend function").unwrap_err();
+        assert_eq!((err.kind, err.line, err.column), (ParseErrorKind::DuplicateParameter("a".to_string()), 1, 16));
+        assert!(parse_str("function(a, b)\n  f = function(a)\n  end function\nend function").is_ok());
+    }
+
+    #[test]
+    fn ranges_bind_looser_than_arithmetic_and_do_not_chain() {
+        assert_eq!(debug("a + 1 to b * 2"), "range(add(var(a), 1), mul(var(b), 2))");
+        assert_eq!(debug("0 to 1 by 0.25"), "range(0, 1, 0.25)");
+        assert_eq!(parse_str("0 to 1 by 0.25").unwrap().to_string(), "0 to 1 by 0.25");
+        assert!(parse_str("1 to 2 to 3").is_err());
+    }
+
+    #[test]
+    fn caret_is_xor_unless_configured_as_power() {
+        let power = |source: &str| {
+            let tokens = lexer::lex(source).unwrap();
+            let expr = Parser::with_options(&tokens, ParseOptions { caret_is_power: true, ..ParseOptions::default() }).parse_expression().unwrap();
+            (format!("{:?}", expr), crate::eval::eval_expression(&expr, &mut crate::eval::Environment::new()).unwrap())
+        };
+        assert_eq!(debug("1 + 2 ^ 3"), "xor(add(1, 2), 3)");
+        assert_eq!(debug("2 ^ 3 ^ 2"), "xor(xor(2, 3), 2)");
+        assert_eq!(power("2 ^ 3 ^ 2"), ("pow(2, pow(3, 2))".to_string(), crate::eval::Value::Number(512.)));
+        assert_eq!(power("-2 ^ 2"), ("minus(pow(2, 2))".to_string(), crate::eval::Value::Number(-4.)));
+        assert_eq!(power("2 ^ -1").1, crate::eval::Value::Number(0.5));
+    }
+
+    #[test]
+    fn call_arguments_allow_one_trailing_comma() {
+        assert_eq!(debug("f()"), "call(var(f), [])");
+        assert_eq!(debug("f(1)"), "call(var(f), [1])");
+        assert_eq!(debug("f(1,2)"), "call(var(f), [1, 2])");
+        assert_eq!(debug("f(1,2,)"), "call(var(f), [1, 2])");
+        for (source, column) in [("f(,)", 3), ("f(1,,2)", 5), ("f(1,2,,)", 7)] {
+            let err = parse_str(source).unwrap_err();
+            assert!(err.message.starts_with("Expected argument but found ','"), "{}", err.message);
+            assert_eq!((err.line, err.column), (1, column), "{}", source);
+        }
+    }
+
+    #[test]
+    fn keywords_after_a_dot_are_member_names() {
+        assert_eq!(debug("a.typeof"), "access(var(a), var(typeof))");
+        assert_eq!(debug("a.if"), "access(var(a), var(if))");
+        assert_eq!(debug("a.end.b"), "access(access(var(a), var(end)), var(b))");
+        assert_eq!(debug("typeof a"), "type(var(a))");
+        assert_eq!(parse_str("a.if").unwrap().to_string(), "a.if");
+        assert!(parse_str("if").is_err());
+    }
+
+    #[test]
+    fn labels_name_an_enclosing_loop() {
+        let statements = program("outer: for i in [1]\n  while true\n    continue outer\n  end while\nend for");
+        assert_eq!(format!("{:?}", statements[0]), "label(outer, for(i, list([1]), [while(true, [continue(outer)])]))");
+        let err = parse_program(&lexer::lex("while true\n  break outer\nend while").unwrap()).unwrap_err();
+        assert_eq!((err.message.as_str(), err.line, err.column), ("Undefined label 'outer'", 2, 9));
+        let err = parse_program(&lexer::lex("outer: while true\nend while\nwhile true\n  break outer\nend while").unwrap()).unwrap_err();
+        assert_eq!(err.message, "Undefined label 'outer'");
+        assert!(parse_program(&lexer::lex("x: y = 1").unwrap()).unwrap_err().message.starts_with("Expected 'while' or 'for' after label"));
+    }
+
+    #[test]
+    fn comparison_and_equality_chains_are_left_associative() {
+        assert_eq!(debug("1 < 2 < 3"), "lt(lt(1, 2), 3)");
+        assert_eq!(debug("3 > 2 >= 1"), "ge(gt(3, 2), 1)");
+        assert_eq!(debug("a == b == c"), "eq(eq(var(a), var(b)), var(c))");
+        assert_eq!(debug("a != b == c"), "eq(nq(var(a), var(b)), var(c))");
+        assert_eq!(parse_str("1 < 2 < 3").unwrap().to_string(), "(1 < 2) < 3");
+        let run = |source: &str| crate::eval::eval_expression(&parse_str(source).unwrap(), &mut crate::eval::Environment::new());
+        assert!(run("1 < 2 < 3").is_err());
+        assert_eq!(run("1 == 1 == 1").unwrap(), crate::eval::Value::Bool(false));
+        assert_eq!(run("1 == 1 == true").unwrap(), crate::eval::Value::Bool(true));
+    }
+
+    #[test]
+    fn only_variables_members_and_elements_are_assignable() {
+        assert_eq!(debug("x = 1"), "asin(var(x), 1)");
+        assert_eq!(debug("a.b = 1"), "asin(access(var(a), var(b)), 1)");
+        assert_eq!(debug("a[0] ??= 1"), "asin_null(index(var(a), 0), 1)");
+        for source in ["1 = 2", "a + b = 1", "f(1) = 2", "1 ??= 2"] {
+            let err = parse_str(source).unwrap_err();
+            assert_eq!((err.kind, err.line, err.column), (ParseErrorKind::InvalidAssignmentTarget, 1, 1), "{}", source);
+        }
+        let err = parse_program(&lexer::lex("x = 1\nif x then\n  y = -x = 2\nend if").unwrap()).unwrap_err();
+        assert_eq!((err.kind, err.line, err.column), (ParseErrorKind::InvalidAssignmentTarget, 3, 7));
+    }
+
+    #[test]
+    fn mismatches_share_one_message_template() {
+        let cases = [
+            ("(1 + 2", "Expected ')' but found end of input", 7),
+            ("f(1 2)", "Expected ',' or ')' but found '2' (near: f ( 1 >>2<< ))", 5),
+            ("[1 2]", "Expected ',' or ']' but found '2' (near: [ 1 >>2<< ])", 4),
+            ("{1 2}", "Expected ':' but found '2' (near: { 1 >>2<< })", 4),
+            ("a[1", "Expected ']' but found end of input", 4),
+            ("if x 1", "Expected 'then' but found '1' (near: if x >>1<<)", 6),
+        ];
+        for (source, message, column) in cases {
+            let err = parse_str(source).unwrap_err();
+            assert_eq!((err.kind, err.message.as_str(), err.line, err.column), (ParseErrorKind::Syntax, message, 1, column), "{}", source);
+        }
+    }
+
+    #[test]
+    fn missing_operands_name_the_operator() {
+        for (source, operator) in [("1 +", "+"), ("x = ", "="), ("a and", "and"), ("2 *", "*")] {
+            let message = parse_str(source).unwrap_err().message;
+            assert_eq!(message, format!("Expected expression after '{}' but found end of input", operator));
+        }
+    }
+
+    fn program_error(source: &str) -> ParseError {
+        parse_program(&lexer::lex(source).unwrap()).unwrap_err()
+    }
+
+    #[test]
+    fn blocks_close_with_end_and_their_keyword() {
+        let statements = program("if x then\n  while y\n    for i in l\n    end for\n  end while\nend if\nf = function()\nend function");
+        assert_eq!(statements.len(), 2);
+        let err = program_error("x = 1\nwhile x\nend for");
+        let mismatched = ParseErrorKind::MismatchedEnd { expected: "end while".to_string(), found: "end for".to_string() };
+        assert_eq!((err.kind, err.message.as_str(), err.line, err.column), (mismatched, "Expected 'end while' but found 'end for'", 3, 1));
+        assert!(matches!(program_error("function()\nend if").kind, ParseErrorKind::MismatchedEnd { .. }));
+        let err = program_error("for i in l\nend");
+        assert_eq!((err.kind, err.message.as_str()), (ParseErrorKind::Syntax, "Expected 'end for' but found end of input"));
+        assert_eq!(program_error("if x then\nend").message, "Expected 'end if' but found end of input");
+    }
+
+    #[test]
+    fn newlines_inside_brackets_do_not_end_the_statement() {
+        assert_eq!(debug("[1,\n2,\n3\n]"), "list([1, 2, 3])");
+        assert_eq!(debug("foo(\na,\nb)"), "call(var(foo), [var(a), var(b)])");
+        assert_eq!(debug("(1 +\n  2\n) * 3"), "mul(add(1, 2), 3)");
+        assert_eq!(debug("{\"a\":\n1,\n\"b\": [\n2]}"), "map([(\"a\", 1), (\"b\", list([2]))])");
+        let statements = program("x = [1,\n  2]\nf(x,\n  3)");
+        assert_eq!(statements.len(), 2);
+        assert_eq!(format!("{:?}", statements[1]), "call(var(f), [var(x), 3])");
+        let err = parse_str("x = 1 +\n2").unwrap_err();
+        assert_eq!((err.message.as_str(), err.line, err.column), ("Expected expression after '+' but found '\\n'", 1, 7));
+    }
+
+    #[test]
+    fn the_precedence_table_builds_the_expected_trees() {
+        let battery = [
+            ("a or b and c", "or(var(a), and(var(b), var(c)))"),
+            ("a && b || c", "or(and(var(a), var(b)), var(c))"),
+            ("a ^ b + c", "xor(var(a), add(var(b), var(c)))"),
+            ("a == b < c", "eq(var(a), lt(var(b), var(c)))"),
+            ("a isa b == c", "eq(isa(var(a), var(b)), var(c))"),
+            ("a < b + c", "lt(var(a), add(var(b), var(c)))"),
+            ("1 to 2 + 3", "range(1, add(2, 3))"),
+            ("a + b * c - d", "sub(add(var(a), mul(var(b), var(c))), var(d))"),
+            ("a / b % c * d", "mul(rem(div(var(a), var(b)), var(c)), var(d))"),
+            ("a - b - c", "sub(sub(var(a), var(b)), var(c))"),
+            ("-a * b", "mul(minus(var(a)), var(b))"),
+            ("typeof a + b", "add(type(var(a)), var(b))"),
+            ("a.b[c](d) * 2", "mul(call(index(access(var(a), var(b)), var(c)), [var(d)]), 2)"),
+            ("x = y = 1 + 2", "asin(var(x), asin(var(y), add(1, 2)))"),
+            ("if a then b else c or d", "cond(var(a), var(b), or(var(c), var(d)))"),
+        ];
+        for (source, tree) in battery {
+            let expr = parse_str(source).unwrap();
+            assert_eq!(format!("{:?}", expr), tree, "{}", source);
+            assert_eq!(parse_str(&expr.to_string()).unwrap(), expr, "{}", source);
+        }
+        assert!(parse_str("1 to 2 to 3").is_err());
+    }
+
+    #[test]
+    fn parse_str_reports_lexer_and_parser_errors_alike() {
+        let expr: Expression = "1 + 2 * x".parse().unwrap();
+        assert_eq!(expr, parse_expression(&lexer::lex("1 + 2 * x").unwrap()).unwrap());
+        assert_eq!(format!("{:?}", expr), "add(1, mul(2, var(x)))");
+        let err = "1 + 2 )".parse::<Expression>().unwrap_err();
+        assert_eq!((err.kind, err.line, err.column), (ParseErrorKind::Syntax, 1, 7));
+        let err = parse_str("1 $").unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::Lexer(lexer::LexerErrorKind::InvalidCharacter('$')));
+        assert_eq!((err.message.as_str(), err.line, err.column), ("Invalid character '$'", 1, 3));
+    }
+
+    #[test]
+    fn top_level_expressions_reject_trailing_tokens() {
+        let parse = |source: &str| parse_expression(&lexer::lex(source).unwrap());
+        assert_eq!(format!("{:?}", parse("1 + 2").unwrap()), "add(1, 2)");
+        assert_eq!(format!("{:?}", parse("1 + 2\n;").unwrap()), "add(1, 2)");
+        for (source, message, column) in [
+            ("1 2", "Expected end of input but found '2' (near: 1 >>2<<)", 3),
+            ("(1) 2", "Expected end of input but found '2' (near: ( 1 ) >>2<<)", 5),
+            ("1 + 2 foo", "Expected end of input but found 'foo' (near: 1 + 2 >>foo<<)", 7),
+        ] {
+            let err = parse(source).unwrap_err();
+            assert_eq!((err.message.as_str(), err.line, err.column), (message, 1, column), "{}", source);
+        }
+        let tokens = lexer::lex("1 2").unwrap();
+        let mut parser = Parser::new(&tokens);
+        assert_eq!(parser.parse_expression().unwrap(), Expression::NumberValue(1.));
+        assert_eq!(parser.parse_expression().unwrap(), Expression::NumberValue(2.));
+    }
+
+    #[test]
+    fn trees_compare_and_clone_structurally() {
+        let source = "f(a.b[1], [x, {\"k\": -2}], function(p=1)\n  return p\nend function)";
+        let first = parse_str(source).unwrap();
+        assert_eq!(first, parse_str(source).unwrap());
+        assert_ne!(first, parse_str("f(a.b[2])").unwrap());
+        let mut copy = first.clone();
+        assert_eq!(copy, first);
+        if let FunctionCall(_, args) = &mut copy {
+            args.pop();
+        }
+        assert_ne!(copy, first);
+        assert_eq!(program("x = 1\nwhile x\n  x = 0\nend while"), program("x = 1\nwhile x\n  x = 0\nend while"));
+    }
+}
@@ -0,0 +1,546 @@
+//! Flattening of expressions into reverse-Polish operations for a stack
+//! machine. Both directions work with explicit stacks, so arbitrarily deep
+//! trees neither overflow the native stack while converting nor while
+//! running. Every expression converts; the bodies of called functions run
+//! through `exec_block` as usual.
+
+use crate::{
+    eval::{
+        assign_element, call_function, closure, index_value, is_truthy, make_range, member_value, names_scope, store_in_scope,
+        BinaryOperator, Environment, MiniKey, RuntimeError, UnaryOperator, Value, COMPUTED_MEMBER_ACCESS,
+    },
+    parser::{Expression, Statement},
+};
+use std::collections::BTreeMap;
+use Expression::*;
+
+/// One stack machine operation. Jump targets are indices into the
+/// operation list.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RpnOp {
+    Push(Value),
+    Load(String),
+    /// Sets the variable to the value on top of the stack, leaving it there.
+    Store(String),
+    /// Pops `key` and stores the value on top into the entry `key` of the
+    /// named scope, such as `globals`, or of the variable when one shadows
+    /// the scope name.
+    StoreInScope(String),
+    /// Pops the collection and `key`, and stores the value on top into the
+    /// element `key`.
+    StoreElement,
+    /// Pops the target and pushes its member.
+    Member(String),
+    /// Pops the target and fails, since only named members are supported.
+    /// Holds the member as source, for the error when the target is null.
+    ComputedMember(String),
+    /// Pops the index and target and pushes the element.
+    Index,
+    /// Like `Member`, but pushes null for a missing map entry.
+    MemberOrNull(String),
+    /// Like `Index`, but pushes null for a missing map entry.
+    IndexOrNull,
+    /// Pops that many items and pushes a list of them.
+    List(usize),
+    /// Fails unless the value on top can be a map key.
+    CheckKey,
+    /// Pops that many key-value pairs and pushes a map of them.
+    Map(usize),
+    /// Pops that many arguments and the callee, and pushes the result.
+    Call(usize),
+    /// Pushes a function closing over the current scopes.
+    Function { params: Vec<(String, Option<Expression>)>, body: Vec<Statement> },
+    Unary(UnaryOperator),
+    Binary(BinaryOperator),
+    /// Pops the value on top and jumps when it is falsey.
+    JumpUnless(usize),
+    /// Jumps, keeping the value on top, when it is falsey; pops it otherwise.
+    JumpIfFalsey(usize),
+    /// Jumps, keeping the value on top, when it is truthy; pops it otherwise.
+    JumpIfTruthy(usize),
+    /// Pushes the variable's value and jumps when it is set and not null.
+    JumpIfSet(String, usize),
+    /// Jumps, keeping the value on top, when it is not null; pops it otherwise.
+    JumpIfNotNull(usize),
+    Jump(usize),
+    /// Pops `value` and `low`. When the comparison fails, pushes `false` and
+    /// jumps; otherwise pushes `value` back for the high side.
+    BetweenLow { inclusive: bool, target: usize },
+    /// Pops `high` and `value` and pushes the comparison.
+    BetweenHigh { inclusive: bool },
+    /// Pops the step (if any), end and start, and pushes the range's list.
+    Range { has_step: bool },
+    InvalidAssignmentTarget,
+    Unsupported(&'static str),
+}
+
+enum Task<'a> {
+    Visit(&'a Expression),
+    Emit(RpnOp),
+    /// Records the current position as the target of a label.
+    Mark(usize),
+}
+
+/// Flattens `expr` into operations that `eval_rpn` runs with the same
+/// results, side effects and errors as `eval_expression`.
+pub fn to_rpn(expr: &Expression) -> Vec<RpnOp> {
+    let mut ops = Vec::new();
+    // Jumps refer to labels while converting and are resolved at the end.
+    let mut labels = Vec::new();
+    let mut tasks = vec![Task::Visit(expr)];
+    while let Some(task) = tasks.pop() {
+        let expr = match task {
+            Task::Visit(expr) => expr,
+            Task::Emit(op) => {
+                ops.push(op);
+                continue;
+            }
+            Task::Mark(label) => {
+                labels[label] = ops.len();
+                continue;
+            }
+        };
+        // Scheduled in execution order, then reversed onto the stack.
+        let scheduled = match expr {
+            StringValue(s) => vec![Task::Emit(RpnOp::Push(Value::Str(s.clone())))],
+            NumberValue(n) => vec![Task::Emit(RpnOp::Push(Value::Number(*n)))],
+            BoolValue(b) => vec![Task::Emit(RpnOp::Push(Value::Bool(*b)))],
+            NullValue => vec![Task::Emit(RpnOp::Push(Value::Null))],
+            Variable(name) => vec![Task::Emit(RpnOp::Load(name.clone()))],
+            AddressOf(operand) => vec![Task::Visit(operand)],
+            LogicalNot(operand) => vec![Task::Visit(operand), Task::Emit(RpnOp::Unary(UnaryOperator::Not))],
+            UnaryNegation(operand) => vec![Task::Visit(operand), Task::Emit(RpnOp::Unary(UnaryOperator::Negate))],
+            Typeof(operand) => vec![Task::Visit(operand), Task::Emit(RpnOp::Unary(UnaryOperator::Typeof))],
+            Multiplication(left, right) => binary(left, BinaryOperator::Multiply, right),
+            Division(left, right) => binary(left, BinaryOperator::Divide, right),
+            Remainder(left, right) => binary(left, BinaryOperator::Remainder, right),
+            Power(left, right) => binary(left, BinaryOperator::Power, right),
+            BitwiseXor(left, right) => binary(left, BinaryOperator::BitwiseXor, right),
+            Addition(left, right) => binary(left, BinaryOperator::Add, right),
+            Subtraction(left, right) => binary(left, BinaryOperator::Subtract, right),
+            LessThan(left, right) => binary(left, BinaryOperator::Less, right),
+            LessThanEq(left, right) => binary(left, BinaryOperator::LessEq, right),
+            GreaterThan(left, right) => binary(left, BinaryOperator::Greater, right),
+            GreaterThanEq(left, right) => binary(left, BinaryOperator::GreaterEq, right),
+            Equality(left, right) => binary(left, BinaryOperator::Equal, right),
+            Inequality(left, right) => binary(left, BinaryOperator::NotEqual, right),
+            LogicalAnd(left, right) => {
+                let end = new_label(&mut labels);
+                vec![Task::Visit(left), Task::Emit(RpnOp::JumpIfFalsey(end)), Task::Visit(right), Task::Mark(end)]
+            }
+            LogicalOr(left, right) => {
+                let end = new_label(&mut labels);
+                vec![Task::Visit(left), Task::Emit(RpnOp::JumpIfTruthy(end)), Task::Visit(right), Task::Mark(end)]
+            }
+            Conditional(cond, then, otherwise) => {
+                let (other, end) = (new_label(&mut labels), new_label(&mut labels));
+                vec![
+                    Task::Visit(cond),
+                    Task::Emit(RpnOp::JumpUnless(other)),
+                    Task::Visit(then),
+                    Task::Emit(RpnOp::Jump(end)),
+                    Task::Mark(other),
+                    Task::Visit(otherwise),
+                    Task::Mark(end),
+                ]
+            }
+            Between { low, value, high, low_inclusive, high_inclusive } => {
+                let end = new_label(&mut labels);
+                vec![
+                    Task::Visit(low),
+                    Task::Visit(value),
+                    Task::Emit(RpnOp::BetweenLow { inclusive: *low_inclusive, target: end }),
+                    Task::Visit(high),
+                    Task::Emit(RpnOp::BetweenHigh { inclusive: *high_inclusive }),
+                    Task::Mark(end),
+                ]
+            }
+            Range { start, end, step } => {
+                let mut tasks = vec![Task::Visit(start), Task::Visit(end)];
+                tasks.extend(step.as_deref().map(Task::Visit));
+                tasks.push(Task::Emit(RpnOp::Range { has_step: step.is_some() }));
+                tasks
+            }
+            Assignment(target, value) => assignment(target, value),
+            // As in `eval_expression`, the target is read once and, when it
+            // is unset or null, evaluated again to store.
+            NullCoalescingAssignment(target, value) => {
+                let end = new_label(&mut labels);
+                let mut tasks = match target.as_ref() {
+                    Variable(name) => vec![Task::Emit(RpnOp::JumpIfSet(name.clone(), end))],
+                    MemberAccess(container, member) => match member.as_ref() {
+                        Variable(name) => vec![Task::Visit(container), Task::Emit(RpnOp::MemberOrNull(name.clone()))],
+                        _ => vec![Task::Visit(target)],
+                    },
+                    Index(container, index) => vec![Task::Visit(container), Task::Visit(index), Task::Emit(RpnOp::IndexOrNull)],
+                    _ => vec![Task::Emit(RpnOp::InvalidAssignmentTarget)],
+                };
+                if !matches!(target.as_ref(), Variable(_)) {
+                    tasks.push(Task::Emit(RpnOp::JumpIfNotNull(end)));
+                }
+                tasks.extend(assignment(target, value));
+                tasks.push(Task::Mark(end));
+                tasks
+            }
+            MemberAccess(container, member) => match member.as_ref() {
+                Variable(name) => vec![Task::Visit(container), Task::Emit(RpnOp::Member(name.clone()))],
+                member => vec![Task::Visit(container), Task::Emit(RpnOp::ComputedMember(member.to_string()))],
+            },
+            Index(target, index) => vec![Task::Visit(target), Task::Visit(index), Task::Emit(RpnOp::Index)],
+            ListLiteral(items) => items.iter().map(Task::Visit).chain([Task::Emit(RpnOp::List(items.len()))]).collect(),
+            MapLiteral(entries) => entries
+                .iter()
+                .flat_map(|(key, value)| [Task::Visit(key), Task::Emit(RpnOp::CheckKey), Task::Visit(value)])
+                .chain([Task::Emit(RpnOp::Map(entries.len()))])
+                .collect(),
+            FunctionCall(callee, args) => std::iter::once(Task::Visit(callee))
+                .chain(args.iter().map(Task::Visit))
+                .chain([Task::Emit(RpnOp::Call(args.len()))])
+                .collect(),
+            New(_) => vec![Task::Emit(RpnOp::Unsupported("'new'"))],
+            IsA(..) => vec![Task::Emit(RpnOp::Unsupported("'isa'"))],
+            Function { params, body } => vec![Task::Emit(RpnOp::Function { params: params.clone(), body: body.clone() })],
+        };
+        tasks.extend(scheduled.into_iter().rev());
+    }
+    for op in &mut ops {
+        match op {
+            RpnOp::JumpUnless(target)
+            | RpnOp::JumpIfFalsey(target)
+            | RpnOp::JumpIfTruthy(target)
+            | RpnOp::JumpIfSet(_, target)
+            | RpnOp::JumpIfNotNull(target)
+            | RpnOp::Jump(target)
+            | RpnOp::BetweenLow { target, .. } => *target = labels[*target],
+            _ => (),
+        }
+    }
+    ops
+}
+
+fn new_label(labels: &mut Vec<usize>) -> usize {
+    labels.push(0);
+    labels.len() - 1
+}
+
+/// Evaluates the value, then the target's index and container, as `assign`
+/// does, leaving the value on the stack.
+fn assignment<'a>(target: &'a Expression, value: &'a Expression) -> Vec<Task<'a>> {
+    let (container, key) = match target {
+        Variable(name) => return vec![Task::Visit(value), Task::Emit(RpnOp::Store(name.clone()))],
+        MemberAccess(container, member) => match member.as_ref() {
+            Variable(name) => (container, Task::Emit(RpnOp::Push(Value::Str(name.clone())))),
+            _ => return vec![Task::Visit(value), Task::Emit(RpnOp::InvalidAssignmentTarget)],
+        },
+        Index(container, index) => (container, Task::Visit(index)),
+        _ => return vec![Task::Visit(value), Task::Emit(RpnOp::InvalidAssignmentTarget)],
+    };
+    match container.as_ref() {
+        Variable(scope) if matches!(scope.as_str(), "globals" | "outer" | "locals") => {
+            vec![Task::Visit(value), key, Task::Emit(RpnOp::StoreInScope(scope.clone()))]
+        }
+        container => vec![Task::Visit(value), key, Task::Visit(container), Task::Emit(RpnOp::StoreElement)],
+    }
+}
+
+fn binary<'a>(left: &'a Expression, operator: BinaryOperator, right: &'a Expression) -> Vec<Task<'a>> {
+    vec![Task::Visit(left), Task::Visit(right), Task::Emit(RpnOp::Binary(operator))]
+}
+
+fn between_operator(inclusive: bool) -> BinaryOperator {
+    if inclusive { BinaryOperator::LessEq } else { BinaryOperator::Less }
+}
+
+/// Runs operations produced by `to_rpn`, returning the value left on the stack.
+pub fn eval_rpn(ops: &[RpnOp], env: &mut Environment) -> Result<Value, RuntimeError> {
+    let mut stack = Vec::new();
+    let mut pc = 0;
+    while let Some(op) = ops.get(pc) {
+        pc += 1;
+        match op {
+            RpnOp::Push(value) => stack.push(value.clone()),
+            RpnOp::Load(name) => stack.push(env.load(name)?),
+            RpnOp::Store(name) => env.set(name.clone(), top(&stack).clone()),
+            RpnOp::StoreInScope(scope) => {
+                let key = pop(&mut stack);
+                let value = top(&stack).clone();
+                if names_scope(scope, env) {
+                    store_in_scope(scope, key, value, env)?;
+                } else {
+                    assign_element(env.load(scope)?, key, value)?;
+                }
+            }
+            RpnOp::StoreElement => {
+                let container = pop(&mut stack);
+                let key = pop(&mut stack);
+                assign_element(container, key, top(&stack).clone())?;
+            }
+            RpnOp::Member(name) => {
+                let target = pop(&mut stack);
+                stack.push(member(target, name)?);
+            }
+            RpnOp::ComputedMember(member) => {
+                return Err(match pop(&mut stack) {
+                    Value::Null => RuntimeError::NullMemberAccess(member.clone()),
+                    _ => RuntimeError::Unsupported(COMPUTED_MEMBER_ACCESS),
+                });
+            }
+            RpnOp::Index => {
+                let index = pop(&mut stack);
+                let target = pop(&mut stack);
+                stack.push(index_value(target, index)?);
+            }
+            RpnOp::MemberOrNull(name) => {
+                let target = pop(&mut stack);
+                stack.push(missing_as_null(member(target, name))?);
+            }
+            RpnOp::IndexOrNull => {
+                let index = pop(&mut stack);
+                let target = pop(&mut stack);
+                stack.push(missing_as_null(index_value(target, index))?);
+            }
+            RpnOp::List(len) => {
+                let items = pop_many(&mut stack, *len);
+                stack.push(Value::list(items));
+            }
+            RpnOp::CheckKey => {
+                MiniKey::from_value(top(&stack).clone())?;
+            }
+            RpnOp::Map(len) => {
+                let mut entries = BTreeMap::new();
+                let mut items = pop_many(&mut stack, 2 * len).into_iter();
+                while let (Some(key), Some(value)) = (items.next(), items.next()) {
+                    entries.insert(MiniKey::from_value(key)?, value);
+                }
+                stack.push(Value::map(entries));
+            }
+            RpnOp::Call(argc) => {
+                let args = pop_many(&mut stack, *argc);
+                let callee = pop(&mut stack);
+                stack.push(call_function(&callee, args, env)?);
+            }
+            RpnOp::Function { params, body } => stack.push(closure(params, body, env)),
+            RpnOp::Unary(operator) => {
+                let operand = pop(&mut stack);
+                stack.push(operator.apply(operand)?);
+            }
+            RpnOp::Binary(operator) => {
+                let right = pop(&mut stack);
+                let left = pop(&mut stack);
+                stack.push(operator.apply(left, right)?);
+            }
+            RpnOp::JumpUnless(target) => {
+                if !is_truthy(&pop(&mut stack)) {
+                    pc = *target;
+                }
+            }
+            RpnOp::JumpIfFalsey(target) => {
+                if is_truthy(top(&stack)) {
+                    stack.pop();
+                } else {
+                    pc = *target;
+                }
+            }
+            RpnOp::JumpIfTruthy(target) => {
+                if is_truthy(top(&stack)) {
+                    pc = *target;
+                } else {
+                    stack.pop();
+                }
+            }
+            RpnOp::JumpIfSet(name, target) => match env.get(name) {
+                Some(current) if current != Value::Null => {
+                    stack.push(current);
+                    pc = *target;
+                }
+                _ => (),
+            },
+            RpnOp::JumpIfNotNull(target) => {
+                if *top(&stack) != Value::Null {
+                    pc = *target;
+                } else {
+                    stack.pop();
+                }
+            }
+            RpnOp::Jump(target) => pc = *target,
+            RpnOp::BetweenLow { inclusive, target } => {
+                let value = pop(&mut stack);
+                let low = pop(&mut stack);
+                if is_truthy(&between_operator(*inclusive).apply(low, value.clone())?) {
+                    stack.push(value);
+                } else {
+                    stack.push(Value::Bool(false));
+                    pc = *target;
+                }
+            }
+            RpnOp::BetweenHigh { inclusive } => {
+                let high = pop(&mut stack);
+                let value = pop(&mut stack);
+                stack.push(between_operator(*inclusive).apply(value, high)?);
+            }
+            RpnOp::Range { has_step } => {
+                let step = has_step.then(|| pop(&mut stack));
+                let end = pop(&mut stack);
+                let start = pop(&mut stack);
+                stack.push(make_range(start, end, step)?);
+            }
+            RpnOp::InvalidAssignmentTarget => return Err(RuntimeError::InvalidAssignmentTarget),
+            RpnOp::Unsupported(what) => return Err(RuntimeError::Unsupported(what)),
+        }
+    }
+    Ok(pop(&mut stack))
+}
+
+/// `target.name`, failing as `eval_expression` does when `target` is null.
+fn member(target: Value, name: &str) -> Result<Value, RuntimeError> {
+    match target {
+        Value::Null => Err(RuntimeError::NullMemberAccess(Variable(name.to_string()).to_string())),
+        target => member_value(target, name),
+    }
+}
+
+/// Reads a missing map entry as null, as `??=` does.
+fn missing_as_null(result: Result<Value, RuntimeError>) -> Result<Value, RuntimeError> {
+    match result {
+        Err(RuntimeError::KeyNotFound(_)) => Ok(Value::Null),
+        result => result,
+    }
+}
+
+/// Pops the top `count` values, in the order they were pushed.
+fn pop_many(stack: &mut Vec<Value>, count: usize) -> Vec<Value> {
+    let start = stack.len().checked_sub(count).expect("operations left the stack unbalanced");
+    stack.split_off(start)
+}
+
+fn pop(stack: &mut Vec<Value>) -> Value {
+    stack.pop().expect("operations left the stack unbalanced")
+}
+
+fn top(stack: &[Value]) -> &Value {
+    stack.last().expect("operations left the stack unbalanced")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval::{eval_expression, run_program};
+
+    /// Evaluates `expr` after `setup` both ways, with a fresh environment
+    /// each time, and returns the result with the environment's `repr`.
+    fn both_ways(setup: &str, expr: &str) -> [(Result<String, String>, String); 2] {
+        trees_both_ways(setup, &expr.parse().unwrap())
+    }
+
+    fn trees_both_ways(setup: &str, expr: &Expression) -> [(Result<String, String>, String); 2] {
+        let run = |rpn: bool| {
+            let mut env = Environment::new();
+            run_program(setup, &mut env).unwrap();
+            let result = if rpn { eval_rpn(&to_rpn(expr), &mut env) } else { eval_expression(expr, &mut env) };
+            let globals = env.load("globals").unwrap().repr();
+            (result.map(|value| value.repr()).map_err(|e| e.to_string()), globals)
+        };
+        [run(false), run(true)]
+    }
+
+    #[test]
+    fn every_expression_runs_as_the_tree_walker_runs_it() {
+        let setup = "m = {\"k\": 1}\nl = [1, 2, 3]\nf = function(a, b=10)\n  return a + b\nend function\nn = null";
+        for expr in [
+            "l[1] + m.k",
+            "m[\"k\"]",
+            "[l[-1], {1: \"one\", \"two\": [2]}]",
+            "f(1) + f(1, 2)",
+            "m.missing",
+            "n.x",
+            "l[9]",
+            "{[1]: 2}",
+            "l.len",
+            "f(l[0])(1)",
+            "(function(x)\n  return x * 2\nend function)(21)",
+            "l[0] = 7",
+            "m.k = l",
+            "m.j ??= 5",
+            "m.k ??= 5",
+            "l[1] ??= 5",
+            "n ??= 5",
+            "globals.g = 4",
+            "globals[\"h\"] = 5",
+            "locals.x = 1",
+            "outer[1] = 1",
+            "n.x = 1",
+            "@f",
+            "new m",
+        ] {
+            let [tree, rpn] = both_ways(setup, expr);
+            assert_eq!(tree, rpn, "{}", expr);
+        }
+    }
+
+    #[test]
+    fn targets_the_parser_rejects_fail_after_the_value_is_evaluated() {
+        let call = FunctionCall(Box::new(Variable("f".to_string())), vec![]);
+        let set_x = Assignment(Box::new(Variable("x".to_string())), Box::new(NumberValue(1.)));
+        let computed = MemberAccess(Box::new(Variable("m".to_string())), Box::new(NumberValue(1.)));
+        for expr in [
+            Assignment(Box::new(call.clone()), Box::new(set_x.clone())),
+            Assignment(Box::new(computed.clone()), Box::new(set_x.clone())),
+            NullCoalescingAssignment(Box::new(call), Box::new(set_x)),
+            computed.clone(),
+            NullCoalescingAssignment(Box::new(computed), Box::new(NumberValue(1.))),
+        ] {
+            let [tree, rpn] = trees_both_ways("f = function()\nend function\nm = {}", &expr);
+            assert!(rpn.0.is_err());
+            assert_eq!(tree, rpn, "{:?}", expr);
+        }
+    }
+
+    #[test]
+    fn stores_into_a_variable_that_shadows_a_scope_name() {
+        let [tree, rpn] = both_ways("globals = [0]", "globals[0] = 1");
+        assert_eq!(tree, rpn);
+        assert_eq!(rpn.1, "[1]");
+    }
+
+    #[test]
+    fn closures_capture_the_scopes_where_they_are_made() {
+        let [tree, rpn] = both_ways("y = 2", "(function(x)\n  return x + y\nend function)(1)");
+        assert_eq!(rpn.0, Ok("3".to_string()));
+        assert_eq!(tree, rpn);
+    }
+
+    #[test]
+    fn operands_come_before_their_operator() {
+        let ops = to_rpn(&"1 + 2 * x".parse().unwrap());
+        assert_eq!(
+            ops,
+            [
+                RpnOp::Push(Value::Number(1.)),
+                RpnOp::Push(Value::Number(2.)),
+                RpnOp::Load("x".to_string()),
+                RpnOp::Binary(BinaryOperator::Multiply),
+                RpnOp::Binary(BinaryOperator::Add),
+            ]
+        );
+        let ops = to_rpn(&"a and b".parse().unwrap());
+        assert_eq!(ops, [RpnOp::Load("a".to_string()), RpnOp::JumpIfFalsey(3), RpnOp::Load("b".to_string())]);
+    }
+
+    #[test]
+    fn deep_trees_convert_and_run_without_recursion() {
+        let result = std::thread::Builder::new()
+            .stack_size(16 << 20)
+            .spawn(|| {
+                let mut expr = NumberValue(0.);
+                for _ in 0..100_000 {
+                    expr = Addition(Box::new(expr), Box::new(NumberValue(1.)));
+                }
+                let ops = to_rpn(&expr);
+                eval_rpn(&ops, &mut Environment::new()).unwrap().to_string()
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(result, "100000");
+    }
+}
@@ -0,0 +1,172 @@
+//! A stable s-expression dump of the syntax tree, for snapshot tests and
+//! tools. Unlike `Debug`, whose format may change, this output only changes
+//! when the tree does.
+
+use crate::parser::{Expression, Statement};
+use Expression::*;
+
+enum SExpr {
+    Atom(String),
+    List(Vec<SExpr>),
+}
+
+fn atom(text: impl ToString) -> SExpr {
+    SExpr::Atom(text.to_string())
+}
+
+/// `(head children...)`.
+fn node(head: &str, children: impl IntoIterator<Item = SExpr>) -> SExpr {
+    SExpr::List(std::iter::once(atom(head)).chain(children).collect())
+}
+
+fn body(statements: &[Statement]) -> SExpr {
+    node("body", statements.iter().map(Statement::sexpr))
+}
+
+impl SExpr {
+    /// Writes a list on one line when it holds only atoms, and otherwise
+    /// puts each item after the head on its own line, indented two spaces
+    /// deeper.
+    fn write(&self, out: &mut String, indent: usize) {
+        let items = match self {
+            SExpr::Atom(text) => return out.push_str(text),
+            SExpr::List(items) => items,
+        };
+        out.push('(');
+        let flat = items.iter().all(|item| matches!(item, SExpr::Atom(_)));
+        for (i, item) in items.iter().enumerate() {
+            if i > 0 && flat {
+                out.push(' ');
+            } else if i > 0 {
+                out.push('\n');
+                out.push_str(&" ".repeat(indent + 2));
+            }
+            item.write(out, indent + 2);
+        }
+        out.push(')');
+    }
+}
+
+impl Expression {
+    /// Dumps the tree as an indented s-expression, such as
+    /// `(add\n  1\n  (mul 2 3))` for `1 + 2 * 3`. Literals and names are
+    /// written as source.
+    pub fn to_sexpr(&self) -> String {
+        let mut out = String::new();
+        self.sexpr().write(&mut out, 0);
+        out
+    }
+
+    fn sexpr(&self) -> SExpr {
+        let binary = |head: &str, left: &Expression, right: &Expression| node(head, [left.sexpr(), right.sexpr()]);
+        match self {
+            StringValue(_) | NumberValue(_) | BoolValue(_) | NullValue | Variable(_) => atom(self),
+            MemberAccess(left, right) => binary("access", left, right),
+            Index(left, right) => binary("index", left, right),
+            FunctionCall(callee, args) => node("call", std::iter::once(callee.sexpr()).chain(args.iter().map(Expression::sexpr))),
+            ListLiteral(items) => node("list", items.iter().map(Expression::sexpr)),
+            MapLiteral(entries) => node("map", entries.iter().map(|(key, value)| binary("entry", key, value))),
+            LogicalNot(operand) => node("not", [operand.sexpr()]),
+            UnaryNegation(operand) => node("neg", [operand.sexpr()]),
+            Typeof(operand) => node("typeof", [operand.sexpr()]),
+            New(operand) => node("new", [operand.sexpr()]),
+            AddressOf(operand) => node("address-of", [operand.sexpr()]),
+            Multiplication(left, right) => binary("mul", left, right),
+            Division(left, right) => binary("div", left, right),
+            Remainder(left, right) => binary("rem", left, right),
+            Power(left, right) => binary("pow", left, right),
+            BitwiseXor(left, right) => binary("xor", left, right),
+            Addition(left, right) => binary("add", left, right),
+            Subtraction(left, right) => binary("sub", left, right),
+            LessThan(left, right) => binary("lt", left, right),
+            LessThanEq(left, right) => binary("le", left, right),
+            GreaterThan(left, right) => binary("gt", left, right),
+            GreaterThanEq(left, right) => binary("ge", left, right),
+            IsA(left, right) => binary("isa", left, right),
+            Equality(left, right) => binary("eq", left, right),
+            Inequality(left, right) => binary("ne", left, right),
+            LogicalAnd(left, right) => binary("and", left, right),
+            LogicalOr(left, right) => binary("or", left, right),
+            Assignment(left, right) => binary("assign", left, right),
+            NullCoalescingAssignment(left, right) => binary("assign-if-null", left, right),
+            Conditional(cond, then, otherwise) => node("if", [cond.sexpr(), then.sexpr(), otherwise.sexpr()]),
+            Range { start, end, step } => node("range", [start, end].into_iter().chain(step).map(|expr| expr.sexpr())),
+            Between { low, value, high, low_inclusive, high_inclusive } => node(
+                "between",
+                [
+                    low.sexpr(),
+                    atom(if *low_inclusive { "<=" } else { "<" }),
+                    value.sexpr(),
+                    atom(if *high_inclusive { "<=" } else { "<" }),
+                    high.sexpr(),
+                ],
+            ),
+            Function { params, body: statements } => {
+                let params = params.iter().map(|(name, default)| match default {
+                    Some(default) => node("default", [atom(Variable(name.clone())), default.sexpr()]),
+                    None => atom(Variable(name.clone())),
+                });
+                node("function", [node("params", params), body(statements)])
+            }
+        }
+    }
+}
+
+impl Statement {
+    /// Dumps the statement like `Expression::to_sexpr`; an expression
+    /// statement dumps as its expression.
+    pub fn to_sexpr(&self) -> String {
+        let mut out = String::new();
+        self.sexpr().write(&mut out, 0);
+        out
+    }
+
+    fn sexpr(&self) -> SExpr {
+        let label = |label: &Option<String>| label.iter().map(|label| atom(Variable(label.clone()))).collect::<Vec<_>>();
+        match self {
+            Statement::Expression(expr) => expr.sexpr(),
+            Statement::If { branches, else_branch } => node(
+                "if",
+                branches
+                    .iter()
+                    .map(|(cond, statements)| node("branch", [cond.sexpr(), body(statements)]))
+                    .chain(else_branch.iter().map(|statements| node("else", [body(statements)]))),
+            ),
+            Statement::While { condition, body: statements } => node("while", [condition.sexpr(), body(statements)]),
+            Statement::For { var, iterable, body: statements } => {
+                node("for", [atom(Variable(var.clone())), iterable.sexpr(), body(statements)])
+            }
+            Statement::Return(value) => node("return", value.iter().map(Expression::sexpr)),
+            Statement::Break(target) => node("break", label(target)),
+            Statement::Continue(target) => node("continue", label(target)),
+            Statement::Labeled(name, statement) => node("labeled", [atom(Variable(name.clone())), statement.sexpr()]),
+            Statement::Defer(statement) => node("defer", [statement.sexpr()]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::{parse_program, parse_str};
+
+    #[test]
+    fn nested_operations_indent_one_level_each() {
+        assert_eq!(parse_str("1 + 2 * 3").unwrap().to_sexpr(), "(add\n  1\n  (mul 2 3))");
+        assert_eq!(parse_str("[1, {\"a\": f(x)}]").unwrap().to_sexpr(), "(list\n  1\n  (map\n    (entry\n      \"a\"\n      (call f x))))");
+        assert_eq!(parse_str("-x").unwrap().to_sexpr(), "(neg x)");
+        assert_eq!(parse_str("null").unwrap().to_sexpr(), "null");
+    }
+
+    #[test]
+    fn statements_dump_their_bodies() {
+        let source = "outer: for i in 1 to 3\n  if i then\n    break outer\n  else\n    continue\n  end if\nend for\ng = function(a, b=2)\n  return\nend function";
+        let dumps: Vec<_> = parse_program(&crate::lexer::lex(source).unwrap()).unwrap().iter().map(|statement| statement.to_sexpr()).collect();
+        assert_eq!(
+            dumps,
+            [
+                "(labeled\n  outer\n  (for\n    i\n    (range 1 3)\n    (body\n      (if\n        (branch\n          i\n          (body\n            (break outer)))\n        (else\n          (body\n            (continue)))))))",
+                "(assign\n  g\n  (function\n    (params\n      a\n      (default b 2))\n    (body\n      (return))))",
+            ]
+        );
+    }
+}
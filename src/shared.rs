@@ -0,0 +1,167 @@
+//! Globals that several threads can run scripts against at once. `Value`
+//! shares lists, maps and functions through `Rc`, so it cannot cross
+//! threads; a `SharedValue` holds the same data behind `Arc` instead. An
+//! environment made with `Environment::with_shared_globals` reads through
+//! to the shared map without locking it and writes only to its own scope.
+//!
+//! ```
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use miniscript::{run_program, share_globals, Environment, Value};
+//!
+//! let mut setup = Environment::new();
+//! run_program("rates = {\"eur\": 0.5}", &mut setup)?;
+//! let globals = share_globals(&setup)?;
+//! let worker = std::thread::spawn(move || {
+//!     let mut env = Environment::with_shared_globals(globals);
+//!     run_program("rates.eur * 10", &mut env).map(|(value, _)| value.to_string()).ok()
+//! });
+//! assert_eq!(worker.join().unwrap().as_deref(), Some("5"));
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::eval::{container_id, Environment, MiniKey, RuntimeError, Value};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+
+/// A value that can cross threads: anything but a function.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SharedValue {
+    Number(f64),
+    Str(Arc<str>),
+    Bool(bool),
+    Null,
+    List(Arc<[SharedValue]>),
+    Map(Arc<BTreeMap<MiniKey, SharedValue>>),
+}
+
+/// Global variables for `Environment::with_shared_globals`.
+pub type SharedGlobals = Arc<HashMap<String, SharedValue>>;
+
+impl Value {
+    /// A copy of the value that can cross threads. Functions, and lists or
+    /// maps inside themselves, cannot be shared.
+    pub fn to_shared(&self) -> Result<SharedValue, RuntimeError> {
+        to_shared(self, &mut Vec::new())
+    }
+}
+
+/// `enclosing` holds the lists and maps being copied around `value`.
+fn to_shared(value: &Value, enclosing: &mut Vec<usize>) -> Result<SharedValue, RuntimeError> {
+    let id = container_id(value);
+    if let Some(id) = id {
+        if enclosing.contains(&id) {
+            return Err(RuntimeError::NotShareable("a list or map inside itself"));
+        }
+        enclosing.push(id);
+    }
+    let shared = match value {
+        Value::Number(n) => SharedValue::Number(*n),
+        Value::Str(s) => SharedValue::Str(s.as_str().into()),
+        Value::Bool(b) => SharedValue::Bool(*b),
+        Value::Null => SharedValue::Null,
+        Value::List(items) => {
+            SharedValue::List(items.borrow().iter().map(|item| to_shared(item, enclosing)).collect::<Result<_, _>>()?)
+        }
+        Value::Map(entries) => SharedValue::Map(Arc::new(
+            entries
+                .borrow()
+                .iter()
+                .map(|(key, value)| Ok((key.clone(), to_shared(value, enclosing)?)))
+                .collect::<Result<_, RuntimeError>>()?,
+        )),
+        Value::Function(_) => return Err(RuntimeError::NotShareable("a function")),
+    };
+    if id.is_some() {
+        enclosing.pop();
+    }
+    Ok(shared)
+}
+
+/// A fresh copy, whose lists and maps belong to the caller's thread.
+impl From<&SharedValue> for Value {
+    fn from(value: &SharedValue) -> Self {
+        match value {
+            SharedValue::Number(n) => Value::Number(*n),
+            SharedValue::Str(s) => Value::Str(s.to_string()),
+            SharedValue::Bool(b) => Value::Bool(*b),
+            SharedValue::Null => Value::Null,
+            SharedValue::List(items) => Value::list(items.iter().map(Value::from).collect()),
+            SharedValue::Map(entries) => {
+                Value::map(entries.iter().map(|(key, value)| (key.clone(), Value::from(value))).collect())
+            }
+        }
+    }
+}
+
+/// Shares the global variables of `env`, as a setup script left them.
+pub fn share_globals(env: &Environment) -> Result<SharedGlobals, RuntimeError> {
+    let Value::Map(globals) = env.globals() else {
+        unreachable!("globals are a map");
+    };
+    let globals = globals.borrow();
+    let shared = globals.iter().map(|(name, value)| Ok((name.to_string(), value.to_shared()?))).collect::<Result<_, RuntimeError>>()?;
+    Ok(Arc::new(shared))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval::run_program;
+
+    fn run(source: &str, env: &mut Environment) -> Value {
+        run_program(source, env).unwrap().0
+    }
+
+    #[test]
+    fn threads_evaluate_against_the_same_globals() {
+        let mut setup = Environment::new();
+        run("prices = {\"tea\": 3, \"cake\": 5}\norder = [\"tea\", \"cake\", \"tea\"]", &mut setup);
+        let globals = share_globals(&setup).unwrap();
+        let script = "total = 0\nfor item in order\n  total = total + prices[item]\nend for\norder[0] = \"cake\"\nprices.tea = 100\ntotal * factor";
+        let workers: Vec<_> = [1., 2.]
+            .into_iter()
+            .map(|factor| {
+                let globals = Arc::clone(&globals);
+                std::thread::spawn(move || {
+                    (0..50)
+                        .map(|_| {
+                            let mut env = Environment::with_shared_globals(Arc::clone(&globals));
+                            env.set("factor".to_string(), Value::Number(factor));
+                            run(script, &mut env).to_string()
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let results: Vec<_> = workers.into_iter().map(|worker| worker.join().unwrap()).collect();
+        assert!(results[0].iter().all(|result| result == "11"));
+        assert!(results[1].iter().all(|result| result == "22"));
+        assert_eq!(globals["prices"], run("{\"tea\": 3, \"cake\": 5}", &mut Environment::new()).to_shared().unwrap());
+    }
+
+    #[test]
+    fn writes_stay_in_the_environment() {
+        let globals = Arc::new(HashMap::from([("xs".to_string(), SharedValue::List(Arc::new([SharedValue::Number(1.)])))]));
+        let mut env = Environment::with_shared_globals(Arc::clone(&globals));
+        assert_eq!(run("xs[0] = 2\nxs[0]", &mut env), Value::Number(2.));
+        assert_eq!(run("globals.xs", &mut env).repr(), "[2]");
+        assert_eq!(run("xs[0]", &mut Environment::with_shared_globals(globals)), Value::Number(1.));
+    }
+
+    #[test]
+    fn functions_and_cycles_are_not_shared() {
+        let mut env = Environment::new();
+        assert!(matches!(run("function()\nend function", &mut env).to_shared(), Err(RuntimeError::NotShareable(_))));
+        assert!(matches!(run("a = [1]\na[0] = a\na", &mut env).to_shared(), Err(RuntimeError::NotShareable(_))));
+        assert!(share_globals(&env).is_err());
+    }
+
+    #[test]
+    fn shared_values_are_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<SharedGlobals>();
+    }
+}
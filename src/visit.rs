@@ -0,0 +1,271 @@
+//! Traversal of the syntax tree. Implement `Visitor` (or `VisitorMut` to
+//! rewrite in place), override the hooks for the nodes of interest and call
+//! the matching `walk_*` function from them to keep descending.
+
+use crate::parser::{Expression, Statement};
+use Expression::*;
+
+pub trait Visitor {
+    fn visit_expression(&mut self, expr: &Expression) {
+        walk_expression(self, expr);
+    }
+    fn visit_statement(&mut self, statement: &Statement) {
+        walk_statement(self, statement);
+    }
+}
+
+pub trait VisitorMut {
+    fn visit_expression_mut(&mut self, expr: &mut Expression) {
+        walk_expression_mut(self, expr);
+    }
+    fn visit_statement_mut(&mut self, statement: &mut Statement) {
+        walk_statement_mut(self, statement);
+    }
+}
+
+/// Visits the direct children of `expr`, left to right.
+pub fn walk_expression<V: Visitor + ?Sized>(visitor: &mut V, expr: &Expression) {
+    match expr {
+        StringValue(_) | NumberValue(_) | BoolValue(_) | NullValue | Variable(_) => (),
+        LogicalNot(operand) | UnaryNegation(operand) | Typeof(operand) | New(operand) | AddressOf(operand) => {
+            visitor.visit_expression(operand);
+        }
+        MemberAccess(left, right)
+        | Index(left, right)
+        | Multiplication(left, right)
+        | Division(left, right)
+        | Remainder(left, right)
+        | Power(left, right)
+        | BitwiseXor(left, right)
+        | Addition(left, right)
+        | Subtraction(left, right)
+        | LessThan(left, right)
+        | LessThanEq(left, right)
+        | GreaterThan(left, right)
+        | GreaterThanEq(left, right)
+        | IsA(left, right)
+        | Equality(left, right)
+        | Inequality(left, right)
+        | LogicalAnd(left, right)
+        | LogicalOr(left, right)
+        | Assignment(left, right)
+        | NullCoalescingAssignment(left, right) => {
+            visitor.visit_expression(left);
+            visitor.visit_expression(right);
+        }
+        ListLiteral(items) => {
+            for item in items {
+                visitor.visit_expression(item);
+            }
+        }
+        MapLiteral(entries) => {
+            for (key, value) in entries {
+                visitor.visit_expression(key);
+                visitor.visit_expression(value);
+            }
+        }
+        FunctionCall(callee, args) => {
+            visitor.visit_expression(callee);
+            for arg in args {
+                visitor.visit_expression(arg);
+            }
+        }
+        Conditional(cond, then, otherwise) => {
+            visitor.visit_expression(cond);
+            visitor.visit_expression(then);
+            visitor.visit_expression(otherwise);
+        }
+        Between { low, value, high, .. } => {
+            visitor.visit_expression(low);
+            visitor.visit_expression(value);
+            visitor.visit_expression(high);
+        }
+        Range { start, end, step } => {
+            visitor.visit_expression(start);
+            visitor.visit_expression(end);
+            if let Some(step) = step {
+                visitor.visit_expression(step);
+            }
+        }
+        Function { params, body } => {
+            for default in params.iter().filter_map(|(_, default)| default.as_ref()) {
+                visitor.visit_expression(default);
+            }
+            for statement in body {
+                visitor.visit_statement(statement);
+            }
+        }
+    }
+}
+
+/// Visits the expressions and nested statements of `statement`.
+pub fn walk_statement<V: Visitor + ?Sized>(visitor: &mut V, statement: &Statement) {
+    match statement {
+        Statement::Expression(expr) | Statement::Return(Some(expr)) => visitor.visit_expression(expr),
+        Statement::If { branches, else_branch } => {
+            for (condition, body) in branches {
+                visitor.visit_expression(condition);
+                body.iter().for_each(|statement| visitor.visit_statement(statement));
+            }
+            for statement in else_branch.iter().flatten() {
+                visitor.visit_statement(statement);
+            }
+        }
+        Statement::While { condition: expr, body } | Statement::For { iterable: expr, body, .. } => {
+            visitor.visit_expression(expr);
+            body.iter().for_each(|statement| visitor.visit_statement(statement));
+        }
+        Statement::Defer(statement) | Statement::Labeled(_, statement) => visitor.visit_statement(statement),
+        Statement::Return(None) | Statement::Break(_) | Statement::Continue(_) => (),
+    }
+}
+
+/// Visits the direct children of `expr` mutably, left to right.
+pub fn walk_expression_mut<V: VisitorMut + ?Sized>(visitor: &mut V, expr: &mut Expression) {
+    match expr {
+        StringValue(_) | NumberValue(_) | BoolValue(_) | NullValue | Variable(_) => (),
+        LogicalNot(operand) | UnaryNegation(operand) | Typeof(operand) | New(operand) | AddressOf(operand) => {
+            visitor.visit_expression_mut(operand);
+        }
+        MemberAccess(left, right)
+        | Index(left, right)
+        | Multiplication(left, right)
+        | Division(left, right)
+        | Remainder(left, right)
+        | Power(left, right)
+        | BitwiseXor(left, right)
+        | Addition(left, right)
+        | Subtraction(left, right)
+        | LessThan(left, right)
+        | LessThanEq(left, right)
+        | GreaterThan(left, right)
+        | GreaterThanEq(left, right)
+        | IsA(left, right)
+        | Equality(left, right)
+        | Inequality(left, right)
+        | LogicalAnd(left, right)
+        | LogicalOr(left, right)
+        | Assignment(left, right)
+        | NullCoalescingAssignment(left, right) => {
+            visitor.visit_expression_mut(left);
+            visitor.visit_expression_mut(right);
+        }
+        ListLiteral(items) => {
+            for item in items {
+                visitor.visit_expression_mut(item);
+            }
+        }
+        MapLiteral(entries) => {
+            for (key, value) in entries {
+                visitor.visit_expression_mut(key);
+                visitor.visit_expression_mut(value);
+            }
+        }
+        FunctionCall(callee, args) => {
+            visitor.visit_expression_mut(callee);
+            for arg in args {
+                visitor.visit_expression_mut(arg);
+            }
+        }
+        Conditional(cond, then, otherwise) => {
+            visitor.visit_expression_mut(cond);
+            visitor.visit_expression_mut(then);
+            visitor.visit_expression_mut(otherwise);
+        }
+        Between { low, value, high, .. } => {
+            visitor.visit_expression_mut(low);
+            visitor.visit_expression_mut(value);
+            visitor.visit_expression_mut(high);
+        }
+        Range { start, end, step } => {
+            visitor.visit_expression_mut(start);
+            visitor.visit_expression_mut(end);
+            if let Some(step) = step {
+                visitor.visit_expression_mut(step);
+            }
+        }
+        Function { params, body } => {
+            for default in params.iter_mut().filter_map(|(_, default)| default.as_mut()) {
+                visitor.visit_expression_mut(default);
+            }
+            for statement in body {
+                visitor.visit_statement_mut(statement);
+            }
+        }
+    }
+}
+
+/// Visits the expressions and nested statements of `statement` mutably.
+pub fn walk_statement_mut<V: VisitorMut + ?Sized>(visitor: &mut V, statement: &mut Statement) {
+    match statement {
+        Statement::Expression(expr) | Statement::Return(Some(expr)) => visitor.visit_expression_mut(expr),
+        Statement::If { branches, else_branch } => {
+            for (condition, body) in branches {
+                visitor.visit_expression_mut(condition);
+                body.iter_mut().for_each(|statement| visitor.visit_statement_mut(statement));
+            }
+            for statement in else_branch.iter_mut().flatten() {
+                visitor.visit_statement_mut(statement);
+            }
+        }
+        Statement::While { condition: expr, body } | Statement::For { iterable: expr, body, .. } => {
+            visitor.visit_expression_mut(expr);
+            body.iter_mut().for_each(|statement| visitor.visit_statement_mut(statement));
+        }
+        Statement::Defer(statement) | Statement::Labeled(_, statement) => visitor.visit_statement_mut(statement),
+        Statement::Return(None) | Statement::Break(_) | Statement::Continue(_) => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lexer::lex, parser::parse_program};
+
+    /// Collects variable names in visiting order.
+    struct Names(Vec<String>);
+
+    impl Visitor for Names {
+        fn visit_expression(&mut self, expr: &Expression) {
+            if let Variable(name) = expr {
+                self.0.push(name.clone());
+            }
+            walk_expression(self, expr);
+        }
+    }
+
+    /// Renames every variable `from` to `to`.
+    struct Rename(&'static str, &'static str);
+
+    impl VisitorMut for Rename {
+        fn visit_expression_mut(&mut self, expr: &mut Expression) {
+            match expr {
+                Variable(name) if name == self.0 => *name = self.1.to_string(),
+                expr => walk_expression_mut(self, expr),
+            }
+        }
+    }
+
+    fn program(source: &str) -> Vec<Statement> {
+        parse_program(&lex(source).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn visitors_reach_every_expression_left_to_right() {
+        let mut names = Names(Vec::new());
+        let source = "if a then\n  b = c[d](e, f.g)\nelse\n  for h in i\n    defer j\n  end for\nend if\nreturn {k: @l}";
+        for statement in &program(source) {
+            names.visit_statement(statement);
+        }
+        assert_eq!(names.0, ["a", "b", "c", "d", "e", "f", "g", "i", "j", "k", "l"]);
+    }
+
+    #[test]
+    fn mutable_visitors_rewrite_in_place() {
+        let mut statements = program("while x < 3\n  x = x + f(function(y=x)\n    return x\n  end function)\nend while");
+        for statement in &mut statements {
+            Rename("x", "n").visit_statement_mut(statement);
+        }
+        assert_eq!(statements, program("while n < 3\n  n = n + f(function(y=n)\n    return n\n  end function)\nend while"));
+    }
+}
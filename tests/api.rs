@@ -0,0 +1,23 @@
+use miniscript::{lex, parse_expression, parse_program, run_program, Environment, InterpretError, RuntimeError, Value};
+
+#[test]
+fn hosts_lex_parse_and_run_through_the_crate_root() {
+    let tokens = lex("x = 6 * 7\nx").unwrap();
+    let statements = parse_program(&tokens).unwrap();
+    assert_eq!(statements.len(), 2);
+    let expr = parse_expression(&lex("1 + 2").unwrap()).unwrap();
+    assert_eq!(expr.to_string(), "1 + 2");
+
+    let mut env = Environment::new();
+    let (value, _) = run_program("x = 6 * 7\nx", &mut env).unwrap();
+    assert_eq!(value, Value::Number(42.));
+    assert_eq!(env.get("x"), Some(Value::Number(42.)));
+}
+
+#[test]
+fn errors_say_which_stage_failed() {
+    let mut env = Environment::new();
+    assert!(matches!(run_program("$", &mut env), Err(InterpretError::Lexer(_))));
+    assert!(matches!(run_program("(", &mut env), Err(InterpretError::Parse(_))));
+    assert!(matches!(run_program("y", &mut env), Err(InterpretError::Runtime(RuntimeError::UndefinedVariable(_)))));
+}
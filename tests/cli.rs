@@ -0,0 +1,51 @@
+use std::{
+    path::Path,
+    process::{Command, Output},
+};
+
+/// Runs the CLI on a script written to a fresh temporary file.
+fn run_script(name: &str, source: &str) -> Output {
+    let path = std::env::temp_dir().join(format!("miniscript-cli-{}-{}.msct", std::process::id(), name));
+    std::fs::write(&path, source).unwrap();
+    let output = run(&path);
+    std::fs::remove_file(&path).unwrap();
+    output
+}
+
+fn run(path: &Path) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_miniscript-on-rust")).arg(path).output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+#[test]
+fn runs_the_script_named_on_the_command_line() {
+    let output = run_script("parsed", "1 + 2 * 3\n");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "Parsed: (add\n  1\n  (mul 2 3))\nValue: 7\n");
+}
+
+#[test]
+fn runs_statements_as_well_as_expressions() {
+    let output = run_script("loop", "total = 0\nfor i in [1, 2, 3]\n  total = total + i\nend for\ntotal\n");
+    assert!(output.status.success());
+    assert!(stdout(&output).ends_with("Value: 6\n"), "{}", stdout(&output));
+}
+
+#[test]
+fn exits_with_failure_on_errors() {
+    let missing = run(&std::env::temp_dir().join("miniscript-cli-missing.msct"));
+    assert_eq!(missing.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&missing.stderr).contains("Usage:"));
+    let failing = [
+        ("lex", "x = $"),
+        ("parse", "x = (1"),
+        ("runtime", "undefinedName"),
+        ("recursion", "g = function()\n  return g()\nend function\ng()"),
+    ];
+    for (name, source) in failing {
+        assert_eq!(run_script(name, source).status.code(), Some(1), "{}", name);
+    }
+}
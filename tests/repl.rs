@@ -0,0 +1,38 @@
+use std::{
+    io::Write,
+    process::{Command, Output, Stdio},
+};
+
+/// Runs the REPL with `input` on its standard input.
+fn session(input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_repl"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn evaluates_each_line_and_keeps_variables_between_lines() {
+    let output = session("x = 2\nx * 3\n\"s\"\nprint(\"hi\")\n");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "> 2\n> 6\n> \"s\"\n> hi\n> ");
+}
+
+#[test]
+fn skips_blank_lines_and_stops_at_quit() {
+    let output = session("\n   \n1\n.quit\n2\n");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "> > > 1\n> ");
+}
+
+#[test]
+fn underscore_holds_the_last_successful_result() {
+    let output = session("6 * 7\nnope\n_ + 1\n");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "> 42\n> > 43\n> ");
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "Failed: Undefined variable 'nope'\n");
+}
+
@@ -0,0 +1,23 @@
+#![cfg(feature = "serde")]
+
+use miniscript::{lex, parse_program, parse_str, Expression, Statement, Token};
+
+#[test]
+fn syntax_trees_round_trip_through_json() {
+    let expr = parse_str("f(a.b, [1, \"x\"]) + -2").unwrap();
+    let json = serde_json::to_string(&expr).unwrap();
+    assert_eq!(serde_json::from_str::<Expression>(&json).unwrap(), expr);
+
+    let statements = parse_program(&lex("for i in l\n  defer print(i)\nend for").unwrap()).unwrap();
+    let json = serde_json::to_string(&statements).unwrap();
+    assert_eq!(serde_json::from_str::<Vec<Statement>>(&json).unwrap(), statements);
+}
+
+#[test]
+fn enum_variants_are_tagged_by_name() {
+    assert_eq!(serde_json::to_string(&parse_str("-x").unwrap()).unwrap(), r#"{"UnaryNegation":{"Variable":"x"}}"#);
+    let tokens = lex("x").unwrap();
+    let json = serde_json::to_value(&tokens[0]).unwrap();
+    assert_eq!(json["kind"], serde_json::json!({"Identifier": "x"}));
+    assert_eq!(serde_json::from_value::<Token>(json).unwrap().kind, tokens[0].kind);
+}